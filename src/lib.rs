@@ -1,20 +1,26 @@
 use anyhow::{anyhow, bail, Context as _};
-use camino::Utf8Path;
+use camino::{Utf8Path, Utf8PathBuf};
 use cargo_metadata as cm;
 use indoc::formatdoc;
 use itertools::Itertools as _;
-use proc_macro2::{TokenStream, TokenTree};
+use once_cell::sync::OnceCell;
+use proc_macro2::{Delimiter, Spacing, TokenStream, TokenTree};
 use std::{
+    convert::TryFrom,
     env,
-    ffi::OsStr,
+    ffi::{OsStr, OsString},
     fmt,
     io::{self, Write},
     path::{Path, PathBuf},
+    process::Stdio,
+    str::FromStr,
+    sync::atomic::{self, AtomicUsize},
+    time::{Duration, Instant, SystemTime},
 };
 use structopt::{clap::AppSettings, StructOpt};
-use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor as _};
+use termcolor::{Color, ColorChoice, ColorSpec, StandardStream, WriteColor};
 
-#[derive(StructOpt)]
+#[derive(Clone, StructOpt)]
 #[structopt(
     about,
     author,
@@ -28,26 +34,311 @@ pub enum Opt {
         usage(
             r#"cargo executable-payload [OPTIONS]
     cargo executable-payload [OPTIONS] --src <PATH>
-    cargo executable-payload [OPTIONS] --bin <NAME>"#,
+    cargo executable-payload [OPTIONS] --bin <NAME>
+    cargo executable-payload [OPTIONS] --from-binary <PATH>"#,
         )
     )]
     ExecutablePayload {
-        /// Use `cross` instead of `$CARGO`
+        /// Use `cross` instead of `$CARGO`. `strip` and `objcopy` run in the image of `cross` as
+        /// well, and `upx` runs on the host
         #[structopt(long)]
         use_cross: bool,
 
-        /// Path to `strip(1)`
+        /// Path to `strip(1)` (in the image of `cross` if `--use-cross` is specified)
         #[structopt(long, value_name("PATH"))]
         strip_exe: Option<PathBuf>,
 
+        /// Strip with `objcopy --strip-all` instead of `strip -s`
+        #[structopt(long)]
+        use_objcopy: bool,
+
+        /// Path to `objcopy(1)` (used in the image of `cross` for `--use-objcopy --use-cross`)
+        #[structopt(long, value_name("PATH"))]
+        objcopy_exe: Option<PathBuf>,
+
+        /// Do not apply `strip` for targets matching the pattern (`*` matches any string)
+        #[structopt(long, value_name("PATTERN"), number_of_values(1))]
+        no_strip_for: Vec<String>,
+
         /// Do not apply `upx`
         #[structopt(long)]
         no_upx: bool,
 
-        /// Write output to the file instead of stdout
+        /// Do not apply `upx` for targets matching the pattern (`*` matches any string)
+        #[structopt(long, value_name("PATTERN"), number_of_values(1))]
+        no_upx_for: Vec<String>,
+
+        /// Run the executable compressed by `upx` with `--help` to check that it launches
+        #[structopt(long)]
+        verify_upx: bool,
+
+        /// Remove `.comment` and `.note.gnu.build-id` sections with `objcopy` before `upx`
+        #[structopt(long)]
+        slim_elf: bool,
+
+        /// Disable `PT_INTERP` of the executable unless it needs shared libraries
+        #[structopt(long)]
+        remove_interp: bool,
+
+        /// Fail instead of skipping `strip` or `upx` if it is not found (unless disabled for the
+        /// target)
+        #[structopt(long)]
+        require_tools: bool,
+
+        /// Report options that have no effect with the others
+        #[structopt(long)]
+        report_unused_flags: bool,
+
+        /// Fail on the options reported by `--report-unused-flags`
+        #[structopt(long, requires("report-unused-flags"))]
+        strict: bool,
+
+        /// Build and process the executable twice, and fail if the results differ
+        #[structopt(long)]
+        assert_stable: bool,
+
+        /// Show a note if building and processing the executable takes longer than SECONDS
+        #[structopt(long, value_name("SECONDS"))]
+        warn_slow: Option<u64>,
+
+        /// Do not show output of `cargo`, `strip`, and `upx` unless they fail
+        #[structopt(long)]
+        quiet_tools: bool,
+
+        /// Do not show any status or output of tools unless the run fails
+        #[structopt(long)]
+        quiet_on_success: bool,
+
+        /// Compress the executable before encoding it (can be repeated) [possible values: gzip,
+        /// lzma]
+        #[structopt(long, value_name("FORMAT"), number_of_values(1))]
+        compression: Vec<Compression>,
+
+        /// Compression level for `upx`, `--compression gzip` (1 to 9), and `--compression lzma` (0
+        /// to 9) [default: `--best` for `upx`, 9 for `gzip` and `lzma`]
+        #[structopt(long, value_name("N"))]
+        compression_level: Option<u32>,
+
+        /// Path to `xz(1)` for `--compression lzma`
+        #[structopt(long, value_name("PATH"))]
+        xz_exe: Option<PathBuf>,
+
+        /// Base name of the decoded executable in the temporary directory, suffixed with the PID
+        /// and the time at runtime [default: name of the bin target]
+        #[structopt(long, value_name("NAME"))]
+        extract_name: Option<String>,
+
+        /// Path of the decoded executable in the environment of the generated code, used as is. A
+        /// file at the path is replaced
+        #[structopt(long, value_name("PATH"), conflicts_with("extract-name"))]
+        extract_path: Option<String>,
+
+        /// Kind of output. `split` writes the base64 payload to `<DIR>/payload.b64` and the
+        /// generated code that reads it with `include_str!` to `<DIR>/loader.rs`, with
+        /// `--output-dir`. `rust-fragment` omits `main` and the header, to be pasted into an
+        /// existing program that calls `run_payload()`
+        #[structopt(
+            long,
+            value_name("KIND"),
+            default_value("rust"),
+            possible_values(&["rust", "binary", "split", "rust-fragment"])
+        )]
+        emit: Emit,
+
+        /// Encoding of the payload in the generated code
+        #[structopt(
+            long,
+            value_name("ENCODING"),
+            default_value("base64"),
+            possible_values(&["base64", "z85"])
+        )]
+        encoding: Encoding,
+
+        /// Decode the base64 payload into a `static` array with a `const fn` when the generated
+        /// code is compiled. This makes compiling it considerably slower for large payloads
+        #[structopt(long, conflicts_with("multiarch"))]
+        const_decode: bool,
+
+        /// Integrity check of the decoded executable in the generated code (`crc32` adds about 200
+        /// bytes, and `sha256` about 2 KiB)
+        #[structopt(
+            long,
+            value_name("ALGO"),
+            default_value("none"),
+            possible_values(&["none", "crc32", "sha256"])
+        )]
+        checksum_algo: ChecksumAlgo,
+
+        /// Decoder for `--encoding base64` and `--compression gzip` in the generated code. `fast`
+        /// is faster for large payloads, `minimal` is smaller in the source code
+        #[structopt(
+            long,
+            value_name("KIND"),
+            default_value("minimal"),
+            possible_values(&["minimal", "fast"])
+        )]
+        runtime_decoder: RuntimeDecoder,
+
+        /// Write output to the file instead of stdout (`-` for stdout)
         #[structopt(short, long, value_name("PATH"))]
         output: Option<PathBuf>,
 
+        /// Write output to `<DIR>/<package>-<bin>-<target>.rs` (without `.rs` for `--emit binary`,
+        /// and `<DIR>/loader.rs` for `--emit split`) and record it in `<DIR>/payloads.json`
+        #[structopt(long, value_name("DIR"), conflicts_with("output"))]
+        output_dir: Option<PathBuf>,
+
+        /// Write output to a new temporary file (not removed afterwards) and print its path
+        #[structopt(long, conflicts_with_all(&["output", "output-dir"]))]
+        output_tempfile: bool,
+
+        /// Create the parent directories of `--output` if they do not exist
+        #[structopt(long, requires("output"))]
+        mkdir: bool,
+
+        /// Write the SHA-256 of the output file to `<PATH>.sha256`, in the format of `sha256sum`
+        #[structopt(long, requires("output"))]
+        sidecar_hash: bool,
+
+        /// Print a `.gitignore` entry for the output file and exit
+        #[structopt(long)]
+        print_gitignore: bool,
+
+        /// Remove comments and whitespace from the original source code in the output
+        #[structopt(long)]
+        minify_source_comment: bool,
+
+        /// Compress the original source code in the output with `--compression` and encode it
+        #[structopt(long, requires("compression"))]
+        compress_source_comment: bool,
+
+        /// Do not call `fsync(2)` on the decoded executable in the generated code
+        #[structopt(long)]
+        no_fsync: bool,
+
+        /// Retry writing the decoded executable on `ENOSPC`/`EINTR` up to N times in the generated code
+        #[structopt(long, value_name("N"), default_value("0"))]
+        write_retries: u32,
+
+        /// Retry running the decoded executable on `ETXTBSY`/`EAGAIN` up to N times in the generated
+        /// code, sleeping 10ms, 20ms, 40ms, ... in between
+        #[structopt(long, value_name("N"), default_value("0"))]
+        exec_retries: u32,
+
+        /// How the generated code runs the decoded executable. `exec` replaces the process with it,
+        /// `spawn` runs it as a child process with `std::process::Command`, and `posix-spawn` does
+        /// with `posix_spawn(3)`, which some sandboxes allow while restricting `execve(2)` of a
+        /// freshly written file. With `exec`, the decoded executable is left in the temporary
+        /// directory unless `exec` fails
+        #[structopt(
+            long,
+            value_name("METHOD"),
+            default_value("exec"),
+            possible_values(&["exec", "spawn", "posix-spawn"])
+        )]
+        exec_method: ExecMethod,
+
+        /// Same as `--exec-method spawn`
+        #[structopt(long)]
+        spawn: bool,
+
+        /// Remove the decoded executable even if the generated code fails or panics. With
+        /// `--exec-method exec`, nothing is left to remove it once `exec` succeeds, so it is only
+        /// removed if the generated code fails before that
+        #[structopt(long)]
+        self_delete: bool,
+
+        /// Call `prctl(PR_SET_NO_NEW_PRIVS, 1)` in the generated code before running the executable,
+        /// so that it cannot gain privileges through setuid executables (Linux only)
+        #[structopt(long)]
+        no_new_privs: bool,
+
+        /// Forward the environment variable from the environment of the generated code to the
+        /// executable explicitly
+        #[structopt(long, value_name("KEY"), number_of_values(1))]
+        env_passthrough: Vec<String>,
+
+        /// Run the executable with `argv[0]` of the generated code instead of the extracted path
+        #[structopt(long)]
+        preserve_arg0: bool,
+
+        /// Print the text to stderr in the generated code before running the executable
+        #[structopt(long, value_name("TEXT"))]
+        banner: Option<String>,
+
+        /// Inner attribute at the top of the generated code, such as `allow(dead_code)` for
+        /// `#![allow(dead_code)]` (can be repeated)
+        #[structopt(long, value_name("ATTR"), number_of_values(1))]
+        inner_attr: Vec<String>,
+
+        /// Edition of the crate that the generated code is compiled in. `extern` blocks are
+        /// `unsafe extern` for 2024 [default: edition of the bin target, or 2018 with
+        /// `--from-binary`]
+        #[structopt(
+            long,
+            value_name("EDITION"),
+            possible_values(&["2015", "2018", "2021", "2024"])
+        )]
+        edition: Option<u16>,
+
+        /// Write the content of the file instead, replacing `{payload}` with the generated code,
+        /// `{source}` with the source code, `{path}` with `--extract-path` or `--extract-name`,
+        /// `{KEY}` with `--template-var`, and `{{` and `}}` with `{` and `}`
+        #[structopt(long, value_name("PATH"))]
+        template: Option<PathBuf>,
+
+        /// Value for `{KEY}` in `--template` (can be repeated)
+        #[structopt(
+            long,
+            value_name("KEY=VALUE"),
+            number_of_values(1),
+            requires("template")
+        )]
+        template_var: Vec<String>,
+
+        /// Fail on `{KEY}` in `--template` without a value and unused `--template-var`s, instead of
+        /// leaving the former as is and warning about the latter
+        #[structopt(long, requires("template"))]
+        template_strict: bool,
+
+        /// POST the output to the URL with `curl`
+        #[cfg(feature = "upload")]
+        #[structopt(long, value_name("URL"))]
+        upload: Option<String>,
+
+        /// Header for `--upload` (e.g. `Authorization: Bearer xxx`)
+        #[cfg(feature = "upload")]
+        #[structopt(long, value_name("HEADER"), number_of_values(1), requires("upload"))]
+        header: Vec<String>,
+
+        /// Embed the Git commit and the build time in the header comment
+        #[structopt(long)]
+        embed_provenance: bool,
+
+        /// Warn if the file was generated with a different format version
+        #[structopt(long, value_name("FILE"))]
+        version_check: Option<PathBuf>,
+
+        /// Print the difference from the file generated before, with the payloads omitted
+        #[structopt(long, value_name("FILE"))]
+        show_diff: Option<PathBuf>,
+
+        /// Print the paths of the tools that would be used with the options, and exit
+        #[structopt(long)]
+        check_tools: bool,
+
+        /// Print the size of the final executable in bytes to stdout instead of generating the code
+        #[structopt(long, conflicts_with_all(&["output", "output-dir"]))]
+        print_decoded_size: bool,
+
+        /// Fail if the output is larger than BYTES
+        #[structopt(long, value_name("BYTES"))]
+        max_size: Option<usize>,
+
+        /// Check the output against constraints of judges and print a summary before writing it
+        #[structopt(long)]
+        preflight: bool,
+
         /// Path the main source file of the bin target
         #[structopt(long, value_name("PATH"), conflicts_with("bin"))]
         src: Option<PathBuf>,
@@ -56,31 +347,384 @@ pub enum Opt {
         #[structopt(long, value_name("NAME"))]
         bin: Option<String>,
 
+        /// Print the packages of the workspace(s) and all of their targets if no target can be
+        /// chosen
+        #[structopt(long, conflicts_with("from-binary"))]
+        diagnose: bool,
+
+        /// Wrap the executable instead of building one (`--src` is only copied into the output)
+        #[structopt(
+            long,
+            value_name("PATH"),
+            conflicts_with_all(&[
+                "bin",
+                "use-cross",
+                "manifest-path",
+                "target-cpu",
+                "panic",
+                "output-dir",
+            ])
+        )]
+        from_binary: Option<PathBuf>,
+
         /// Build for the target triple
         #[structopt(long, value_name("TRIPLE"), default_value("x86_64-unknown-linux-musl"))]
         target: String,
 
-        /// Path to Cargo.toml
+        /// Build for `x86_64-apple-darwin` and `aarch64-apple-darwin`, and combine them with
+        /// `lipo`. Output file names and `payloads.json` have
+        /// `x86_64-apple-darwin+aarch64-apple-darwin` as the target
+        #[structopt(
+            long,
+            conflicts_with_all(&["target", "use-cross", "from-binary", "target-cpu", "require-musl"])
+        )]
+        universal: bool,
+
+        /// Run for each of the target triples (`x86_64-unknown-linux-musl`,
+        /// `x86_64-pc-windows-gnu`, and `x86_64-apple-darwin` if none are given) in turn
+        /// (`--target` is ignored)
+        #[structopt(
+            long,
+            value_name("TRIPLE"),
+            min_values(0),
+            use_delimiter(true),
+            requires("output-dir"),
+            conflicts_with_all(&["universal", "multiarch"])
+        )]
+        os_matrix: Option<Vec<String>>,
+
+        /// Build for x86-64 and x86-64-v3, and make the generated code extract the latter on CPUs
+        /// that support it
+        #[structopt(
+            long,
+            conflicts_with_all(&[
+                "from-binary",
+                "universal",
+                "target-cpu",
+                "preflight",
+                "print-decoded-size",
+            ])
+        )]
+        multiarch: bool,
+
+        /// Fail if `--target` is not a musl target
+        #[structopt(long)]
+        require_musl: bool,
+
+        /// Make the generated code exit with an error before writing the executable if
+        /// `std::env::consts::{ARCH, OS}` do not match `--target`
+        #[structopt(long)]
+        assert_arch: bool,
+
+        /// Build with `-C target-cpu=<CPU>` appended to the rustflags from the environment or the
+        /// Cargo configuration
+        #[structopt(long, value_name("CPU"))]
+        target_cpu: Option<String>,
+
+        /// Append `-C panic=<STRATEGY>` to the rustflags from the environment or the Cargo
+        /// configuration. With `-Z build-std` configured, `abort` adds `panic_abort` to it
+        /// [possible values: unwind, abort]
+        #[structopt(long, value_name("STRATEGY"))]
+        panic: Option<Panic>,
+
+        /// Build with `$RUSTC_WRAPPER` set to the program, such as `sccache` [default: the existing
+        /// `$RUSTC_WRAPPER`]
+        #[structopt(
+            long,
+            value_name("PATH"),
+            conflicts_with_all(&["use-cross", "from-binary"])
+        )]
+        rustc_wrapper: Option<PathBuf>,
+
+        /// Features to enable, separated by commas or spaces (can be repeated) [env:
+        /// CARGO_EXECUTABLE_PAYLOAD_FEATURES]
+        #[structopt(
+            long,
+            value_name("FEATURES"),
+            number_of_values(1),
+            conflicts_with("from-binary")
+        )]
+        features: Vec<String>,
+
+        /// Link against glibc of the version, such as `2.17`, with `zig cc` as `cargo-zigbuild` does
+        /// (`*-linux-gnu*` targets on Unix hosts)
+        #[structopt(
+            long,
+            value_name("VERSION"),
+            conflicts_with_all(&["use-cross", "from-binary"])
+        )]
+        glibc_version: Option<String>,
+
+        /// Path to Cargo.toml (can be specified multiple times to search multiple workspaces)
+        #[structopt(long, value_name("PATH"), number_of_values(1))]
+        manifest_path: Vec<PathBuf>,
+
+        /// TOML file of defaults for the other options [env: CARGO_EXECUTABLE_PAYLOAD_PROFILE_FILE]
         #[structopt(long, value_name("PATH"))]
-        manifest_path: Option<PathBuf>,
+        profile_file: Option<PathBuf>,
     },
 }
 
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Compression {
+    Gzip,
+    /// The `.lzma` (LZMA1) format of `xz --format=lzma`.
+    Lzma,
+}
+
+impl Compression {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Gzip => "gzip",
+            Self::Lzma => "lzma",
+        }
+    }
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "gzip" => Ok(Self::Gzip),
+            "lzma" => Ok(Self::Lzma),
+            s => Err(format!("unsupported compression format: {:?}", s)),
+        }
+    }
+}
+
+/// External programs that `--compression-level` applies to.
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Compressor {
+    Upx,
+    Gzip,
+    Xz,
+}
+
+impl Compressor {
+    /// Returns the argument for `level`, or the default one for `None`.
+    fn level_arg(self, level: Option<u32>) -> anyhow::Result<String> {
+        let (name, default, levels) = match self {
+            Self::Upx => ("upx", "--best", 1..=9),
+            Self::Gzip => ("gzip", "-9", 1..=9),
+            Self::Xz => ("xz", "-9", 0..=9),
+        };
+        match level {
+            None => Ok(default.to_owned()),
+            Some(level) if levels.contains(&level) => Ok(format!("-{}", level)),
+            Some(level) => bail!(
+                "`{}` does not support compression level {} (expected {} to {})",
+                name,
+                level,
+                levels.start(),
+                levels.end(),
+            ),
+        }
+    }
+}
+
+/// Encoding of the payload in the generated code.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum Encoding {
+    #[default]
+    Base64,
+    /// [Z85](https://rfc.zeromq.org/spec/32/), which is denser than base64.
+    Z85,
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "base64" => Ok(Self::Base64),
+            "z85" => Ok(Self::Z85),
+            s => Err(format!("unknown encoding: {:?}", s)),
+        }
+    }
+}
+
+/// Integrity check embedded in the generated code.
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ChecksumAlgo {
+    None,
+    Crc32,
+    Sha256,
+}
+
+impl ChecksumAlgo {
+    fn checksum(self, data: &[u8]) -> Checksum {
+        match self {
+            Self::None => Checksum::None,
+            Self::Crc32 => Checksum::Crc32(crc32(data)),
+            Self::Sha256 => Checksum::Sha256(sha256(data)),
+        }
+    }
+}
+
+impl FromStr for ChecksumAlgo {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "crc32" => Ok(Self::Crc32),
+            "sha256" => Ok(Self::Sha256),
+            s => Err(format!("unknown checksum algorithm: {:?}", s)),
+        }
+    }
+}
+
+/// Checksum of the executable, verified after decoding it in the generated code.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+enum Checksum {
+    #[default]
+    None,
+    Crc32(u32),
+    Sha256([u8; 32]),
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Emit {
+    Rust,
+    Binary,
+    Split,
+    /// `pub fn run_payload()->std::io::Result<()>`, `decode` and its helpers, and the `PATH`,
+    /// `PAYLOAD`, and `LEN` statics.
+    RustFragment,
+}
+
+impl FromStr for Emit {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "rust" => Ok(Self::Rust),
+            "binary" => Ok(Self::Binary),
+            "split" => Ok(Self::Split),
+            "rust-fragment" => Ok(Self::RustFragment),
+            s => Err(format!("unknown kind of output: {:?}", s)),
+        }
+    }
+}
+
+/// Implementation of the base64 decoding and `inflate` in the generated code.
+#[derive(Clone, Copy, Debug, Default, PartialEq)]
+pub enum RuntimeDecoder {
+    /// Smaller in the source code.
+    #[default]
+    Minimal,
+    /// Decodes base64 four characters at a time through a `u32`, and DEFLATE with lookup tables.
+    Fast,
+}
+
+impl FromStr for RuntimeDecoder {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "minimal" => Ok(Self::Minimal),
+            "fast" => Ok(Self::Fast),
+            s => Err(format!("unknown runtime decoder: {:?}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum Panic {
+    Unwind,
+    Abort,
+}
+
+impl Panic {
+    fn as_str(self) -> &'static str {
+        match self {
+            Self::Unwind => "unwind",
+            Self::Abort => "abort",
+        }
+    }
+}
+
+impl FromStr for Panic {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "unwind" => Ok(Self::Unwind),
+            "abort" => Ok(Self::Abort),
+            s => Err(format!("unknown panic strategy: {:?}", s)),
+        }
+    }
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub enum ExecMethod {
+    Exec,
+    Spawn,
+    PosixSpawn,
+}
+
+impl FromStr for ExecMethod {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "exec" => Ok(Self::Exec),
+            "spawn" => Ok(Self::Spawn),
+            "posix-spawn" => Ok(Self::PosixSpawn),
+            s => Err(format!("unknown exec method: {:?}", s)),
+        }
+    }
+}
+
 pub struct Shell {
-    stderr: StandardStream,
+    stdout: Stdout,
+    stderr: Stderr,
 }
 
 impl Shell {
     pub fn new() -> Self {
         Self {
-            stderr: StandardStream::stderr(if atty::is(atty::Stream::Stderr) {
+            stdout: Stdout::Stream(io::stdout()),
+            stderr: Stderr::Stream(StandardStream::stderr(if atty::is(atty::Stream::Stderr) {
                 ColorChoice::Auto
             } else {
                 ColorChoice::Never
-            }),
+            })),
+        }
+    }
+
+    fn buffered() -> Self {
+        Self {
+            stdout: Stdout::Buffer(vec![]),
+            stderr: Stderr::Buffer(termcolor::Buffer::no_color()),
+        }
+    }
+
+    fn buffered_stdout(&self) -> &[u8] {
+        match &self.stdout {
+            Stdout::Stream(_) => panic!("not buffered"),
+            Stdout::Buffer(buf) => buf,
+        }
+    }
+
+    fn buffered_bytes(&self) -> &[u8] {
+        match &self.stderr {
+            Stderr::Stream(_) => panic!("not buffered"),
+            Stderr::Buffer(buf) => buf.as_slice(),
         }
     }
 
+    #[cfg(test)]
+    fn buffered_output(&self) -> String {
+        String::from_utf8_lossy(self.buffered_bytes()).into_owned()
+    }
+
+    /// Where the output goes, which is kept free of anything else so that it can be piped.
+    pub fn out(&mut self) -> &mut dyn Write {
+        &mut self.stdout
+    }
+
     pub fn err(&mut self) -> &mut dyn Write {
         &mut self.stderr
     }
@@ -93,6 +737,10 @@ impl Shell {
         self.print(status, message, Color::Green, true)
     }
 
+    pub(crate) fn warn(&mut self, message: impl fmt::Display) -> io::Result<()> {
+        self.print("warning", message, Color::Yellow, false)
+    }
+
     pub fn error(&mut self, message: impl fmt::Display) -> io::Result<()> {
         self.print("error", message, Color::Red, false)
     }
@@ -118,319 +766,7753 @@ impl Shell {
     }
 }
 
+enum Stdout {
+    Stream(io::Stdout),
+    Buffer(Vec<u8>),
+}
+
+impl Write for Stdout {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Stream(stream) => stream.write(buf),
+            Self::Buffer(buffer) => buffer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Stream(stream) => stream.flush(),
+            Self::Buffer(buffer) => buffer.flush(),
+        }
+    }
+}
+
+enum Stderr {
+    Stream(StandardStream),
+    Buffer(termcolor::Buffer),
+}
+
+impl Write for Stderr {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        match self {
+            Self::Stream(stream) => stream.write(buf),
+            Self::Buffer(buffer) => buffer.write(buf),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        match self {
+            Self::Stream(stream) => stream.flush(),
+            Self::Buffer(buffer) => buffer.flush(),
+        }
+    }
+}
+
+impl WriteColor for Stderr {
+    fn supports_color(&self) -> bool {
+        match self {
+            Self::Stream(stream) => stream.supports_color(),
+            Self::Buffer(buffer) => buffer.supports_color(),
+        }
+    }
+
+    fn set_color(&mut self, spec: &ColorSpec) -> io::Result<()> {
+        match self {
+            Self::Stream(stream) => stream.set_color(spec),
+            Self::Buffer(buffer) => buffer.set_color(spec),
+        }
+    }
+
+    fn reset(&mut self) -> io::Result<()> {
+        match self {
+            Self::Stream(stream) => stream.reset(),
+            Self::Buffer(buffer) => buffer.reset(),
+        }
+    }
+}
+
 impl Default for Shell {
     fn default() -> Self {
         Self::new()
     }
 }
 
-pub fn run(opt: Opt, shell: &mut Shell) -> anyhow::Result<()> {
+/// Replaces each `@<PATH>` argument with the lines of the file, one argument per line.
+///
+/// Empty lines are ignored. Arguments after `--` are left as they are.
+pub fn expand_response_files(
+    args: impl IntoIterator<Item = OsString>,
+) -> anyhow::Result<Vec<OsString>> {
+    let mut expanded = vec![];
+    let mut args = args.into_iter();
+    if let Some(arg0) = args.next() {
+        expanded.push(arg0);
+    }
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            expanded.push(arg);
+            expanded.extend(args);
+            break;
+        }
+        match arg.to_str().and_then(|arg| arg.strip_prefix('@')) {
+            Some(path) => {
+                let content = std::fs::read_to_string(path)
+                    .with_context(|| format!("could not read the response file `{}`", path))?;
+                expanded.extend(
+                    content
+                        .lines()
+                        .filter(|line| !line.is_empty())
+                        .map(Into::into),
+                );
+            }
+            None => expanded.push(arg),
+        }
+    }
+    Ok(expanded)
+}
+
+/// Environment variable for `--profile-file`.
+pub static PROFILE_FILE_ENV: &str = "CARGO_EXECUTABLE_PAYLOAD_PROFILE_FILE";
+
+/// Returns messages for the options that have no effect with the others, for
+/// `--report-unused-flags`.
+fn unused_flags(opt: &Opt) -> Vec<String> {
     let Opt::ExecutablePayload {
-        use_cross,
         strip_exe,
+        use_objcopy,
+        objcopy_exe,
         no_upx,
-        output,
-        src,
-        bin,
-        target,
-        manifest_path,
+        no_upx_for,
+        verify_upx,
+        slim_elf,
+        compression,
+        compression_level,
+        xz_exe,
+        extract_name,
+        extract_path,
+        emit,
+        minify_source_comment,
+        compress_source_comment,
+        no_fsync,
+        self_delete,
+        no_new_privs,
+        env_passthrough,
+        preserve_arg0,
+        banner,
+        inner_attr,
+        edition,
+        template,
+        embed_provenance,
+        ..
     } = opt;
 
-    let cwd = env::current_dir().with_context(|| "failed to get CWD")?;
-    let manifest_path = if let Some(manifest_path) = manifest_path {
-        cwd.join(manifest_path.strip_prefix(".").unwrap_or(&manifest_path))
-    } else {
-        locate_project(&cwd)?
+    let mut unused = vec![];
+    let mut check = |given: bool, option: &str, reason: &str| {
+        if given {
+            unused.push(format!("`{}` has no effect {}", option, reason));
+        }
     };
-    let metadata = cargo_metadata(&manifest_path, &cwd)?;
-
-    let (bin, bin_package) = if let Some(bin) = bin {
-        bin_target_by_name(&metadata, &bin)
-    } else if let Some(src) = src {
-        bin_target_by_src_path(&metadata, &cwd.join(src))
-    } else {
-        exactly_one_bin_target(&metadata)
-    }?;
 
-    let source_code = std::fs::read_to_string(&bin.src_path)
-        .with_context(|| format!("could not read `{}`", bin.src_path))?;
-
-    let artifact_base64 = build(
-        shell,
-        &metadata.target_directory,
-        &bin_package.manifest_path.with_file_name(""),
-        &bin.name,
-        use_cross,
-        &target,
-        strip_exe.map(|p| cwd.join(p)).as_deref(),
-        no_upx,
-    )?;
+    check(*verify_upx && *no_upx, "--verify-upx", "with `--no-upx`");
+    check(
+        !no_upx_for.is_empty() && *no_upx,
+        "--no-upx-for",
+        "with `--no-upx`",
+    );
+    check(
+        compression_level.is_some() && *no_upx && compression.is_empty(),
+        "--compression-level",
+        "with `--no-upx` and without `--compression`",
+    );
+    check(
+        xz_exe.is_some() && !compression.contains(&Compression::Lzma),
+        "--xz-exe",
+        "without `--compression lzma`",
+    );
+    check(
+        strip_exe.is_some() && *use_objcopy,
+        "--strip-exe",
+        "with `--use-objcopy`",
+    );
+    check(
+        objcopy_exe.is_some() && !use_objcopy && !slim_elf,
+        "--objcopy-exe",
+        "without `--use-objcopy` or `--slim-elf`",
+    );
 
-    let rs = format_with_template(&source_code, &artifact_base64);
-    if let Some(output) = output {
-        std::fs::write(output, rs)?;
-    } else {
-        let mut stdout = io::stdout();
-        stdout.write_all(rs.as_ref())?;
-        stdout.flush()?;
+    // They are for the generated code.
+    if *emit == Emit::Binary {
+        for &(given, option) in &[
+            (!compression.is_empty(), "--compression"),
+            (extract_name.is_some(), "--extract-name"),
+            (extract_path.is_some(), "--extract-path"),
+            (*minify_source_comment, "--minify-source-comment"),
+            (*compress_source_comment, "--compress-source-comment"),
+            (*no_fsync, "--no-fsync"),
+            (*self_delete, "--self-delete"),
+            (*no_new_privs, "--no-new-privs"),
+            (!env_passthrough.is_empty(), "--env-passthrough"),
+            (*preserve_arg0, "--preserve-arg0"),
+            (banner.is_some(), "--banner"),
+            (!inner_attr.is_empty(), "--inner-attr"),
+            (edition.is_some(), "--edition"),
+            (template.is_some(), "--template"),
+            (*embed_provenance, "--embed-provenance"),
+        ] {
+            check(given, option, "with `--emit binary`");
+        }
     }
-    Ok(())
-}
 
-fn locate_project(cwd: &Path) -> anyhow::Result<PathBuf> {
-    cwd.ancestors()
-        .map(|p| p.join("Cargo.toml"))
-        .find(|p| p.exists())
-        .with_context(|| {
-            format!(
-                "could not find `Cargo.toml` in `{}` or any parent directory",
-                cwd.display(),
-            )
-        })
+    unused
 }
 
-fn cargo_metadata(manifest_path: &Path, cwd: &Path) -> cm::Result<cm::Metadata> {
-    cm::MetadataCommand::new()
-        .manifest_path(manifest_path)
-        .current_dir(cwd)
-        .exec()
-}
+/// Environment variable for `--features`, such as `a,b` or `a b`.
+pub static FEATURES_ENV: &str = "CARGO_EXECUTABLE_PAYLOAD_FEATURES";
 
-fn bin_target_by_name<'a>(
-    metadata: &'a cm::Metadata,
-    name: &str,
-) -> anyhow::Result<(&'a cm::Target, &'a cm::Package)> {
-    match *bin_targets(metadata)
-        .filter(|(t, _)| t.name == name)
-        .collect::<Vec<_>>()
+/// Adds the options in the profile file that are not given in `args`. The profile file is specified
+/// with `--profile-file` or `$CARGO_EXECUTABLE_PAYLOAD_PROFILE_FILE`.
+///
+/// The profile file is a TOML file whose top-level keys are names of long options.
+///
+/// ```toml
+/// target = "x86_64-unknown-linux-musl"
+/// compression = ["gzip"]
+/// extract-name = "solution"
+/// max-size = 524288
+/// no-upx = true
+/// ```
+///
+/// A string or an integer is passed as the value, `true` passes the flag, `false` passes nothing,
+/// and an array of strings passes the option for each element. Arguments take precedence over the
+/// profile file, and the profile file takes precedence over the defaults. Options that conflict
+/// with the arguments, such as `output` with `--output-dir`, are ignored. Relative paths are
+/// relative to the current directory, as with arguments.
+pub fn apply_profile_file(args: Vec<OsString>) -> anyhow::Result<Vec<OsString>> {
+    // Errors such as `--help` are left to the caller.
+    let matches = match Opt::clap().get_matches_from_safe(&args) {
+        Ok(matches) => matches,
+        Err(_) => return Ok(args),
+    };
+    let matches = match matches.subcommand_matches("executable-payload") {
+        Some(matches) => matches,
+        None => return Ok(args),
+    };
+    let path = match matches
+        .value_of_os("profile-file")
+        .map(ToOwned::to_owned)
+        .or_else(|| env::var_os(PROFILE_FILE_ENV))
     {
-        [] => bail!("no bin target named `{}`", name),
-        [bin] => Ok(bin),
-        [..] => bail!("multiple bin targets named `{}` in this workspace", name),
+        Some(path) => PathBuf::from(path),
+        None => return Ok(args),
+    };
+
+    let toml = std::fs::read_to_string(&path)
+        .with_context(|| format!("could not read the profile file `{}`", path.display()))?;
+    let entries = toml::from_str::<toml::value::Table>(&toml)
+        .with_context(|| format!("could not parse the profile file `{}`", path.display()))?;
+
+    let mut profile_args = vec![];
+    for (key, value) in entries {
+        let option = OsString::from(format!("--{}", key));
+        let probe = ["cargo".into(), "executable-payload".into(), option.clone()];
+        let is_option = !matches!(
+            Opt::clap().get_matches_from_safe(&probe),
+            Err(err) if err.kind == structopt::clap::ErrorKind::UnknownArgument,
+        );
+        if !is_option || key == "profile-file" || key == "help" || key == "version" {
+            bail!("`{}` in `{}` is not an option", key, path.display());
+        }
+        if matches.occurrences_of(&key) > 0 {
+            continue;
+        }
+        let invalid = || {
+            anyhow!(
+                "`{}` in `{}` must be a string, an integer, a boolean, or an array of strings",
+                key,
+                path.display(),
+            )
+        };
+        let values = match value {
+            toml::Value::String(value) => vec![option, value.into()],
+            toml::Value::Integer(value) => vec![option, value.to_string().into()],
+            toml::Value::Boolean(true) => vec![option],
+            toml::Value::Boolean(false) => vec![],
+            toml::Value::Array(values) => values
+                .into_iter()
+                .map(|value| match value {
+                    toml::Value::String(value) => Ok(vec![option.clone(), value.into()]),
+                    _ => Err(invalid()),
+                })
+                .collect::<anyhow::Result<Vec<_>>>()?
+                .concat(),
+            _ => return Err(invalid()),
+        };
+
+        // Options that conflict with the arguments, such as `output` with `--output-dir`, are
+        // dropped as well.
+        let mut probe = args.clone();
+        let end = probe
+            .iter()
+            .position(|arg| arg == "--")
+            .unwrap_or(probe.len());
+        probe.splice(end..end, values.clone());
+        if matches!(
+            Opt::clap().get_matches_from_safe(&probe),
+            Err(err) if err.kind == structopt::clap::ErrorKind::ArgumentConflict,
+        ) {
+            continue;
+        }
+        profile_args.extend(values);
     }
+
+    let mut args = args;
+    let end = args
+        .iter()
+        .position(|arg| arg == "--")
+        .unwrap_or(args.len());
+    args.splice(end..end, profile_args);
+    Ok(args)
 }
 
-fn bin_target_by_src_path<'a>(
-    metadata: &'a cm::Metadata,
-    src_path: &Path,
-) -> anyhow::Result<(&'a cm::Target, &'a cm::Package)> {
-    match *bin_targets(metadata)
-        .filter(|(t, _)| t.src_path == src_path)
-        .collect::<Vec<_>>()
-    {
-        [] => bail!(
-            "`{}` is not the main source file of any bin targets in this workspace ",
-            src_path.display(),
-        ),
-        [bin] => Ok(bin),
-        [..] => bail!(
-            "multiple bin targets which `src_path` is `{}`",
-            src_path.display(),
-        ),
+/// Checks that `attr` is the content of an attribute, such as `allow(dead_code)` or
+/// `doc = "..."`, for `--inner-attr`.
+fn validate_inner_attr(attr: &str) -> anyhow::Result<()> {
+    let invalid = || anyhow!("invalid attribute: {:?}", attr);
+    let tokens = attr
+        .parse::<TokenStream>()
+        .map_err(|_| invalid())?
+        .into_iter()
+        .collect::<Vec<_>>();
+
+    // The path, such as `clippy::all`.
+    let mut tokens = &tokens[..];
+    loop {
+        match tokens {
+            [TokenTree::Ident(_), TokenTree::Punct(p1), TokenTree::Punct(p2), rest @ ..]
+                if p1.as_char() == ':' && p1.spacing() == Spacing::Joint && p2.as_char() == ':' =>
+            {
+                tokens = rest;
+            }
+            [TokenTree::Ident(_), rest @ ..] => {
+                tokens = rest;
+                break;
+            }
+            _ => return Err(invalid()),
+        }
     }
-}
 
-fn exactly_one_bin_target(metadata: &cm::Metadata) -> anyhow::Result<(&cm::Target, &cm::Package)> {
-    match &*bin_targets(metadata).collect::<Vec<_>>() {
-        [] => bail!("no bin target in this workspace"),
-        [bin] => Ok(*bin),
-        [bins @ ..] => bail!(
-            "could not determine which binary to choose. Use the `--bin` option or `--src` option \
-             to specify a binary.\n\
-             available binaries: {}\n\
-             note: currently `cargo-executable-payload` does not support the `default-run` manifest \
-             key.",
-            bins.iter()
-                .map(|(cm::Target { name, .. }, _)| name)
-                .format(", "),
-        ),
+    match tokens {
+        [] => Ok(()),
+        [TokenTree::Group(group)] if group.delimiter() != Delimiter::None => Ok(()),
+        [TokenTree::Punct(eq), TokenTree::Literal(_)] if eq.as_char() == '=' => Ok(()),
+        _ => Err(invalid()),
     }
 }
 
-fn bin_targets(metadata: &cm::Metadata) -> impl Iterator<Item = (&cm::Target, &cm::Package)> {
-    metadata
-        .packages
-        .iter()
-        .filter(move |cm::Package { id, .. }| metadata.workspace_members.contains(id))
-        .flat_map(|p| p.targets.iter().map(move |t| (t, p)))
-        .filter(|(cm::Target { kind, .. }, _)| *kind == ["bin".to_owned()])
+pub fn run(opt: Opt, shell: &mut Shell) -> anyhow::Result<()> {
+    let Opt::ExecutablePayload {
+        quiet_on_success, ..
+    } = opt;
+    if !quiet_on_success {
+        return run_with_shell(opt, shell);
+    }
+    let mut buffered = Shell::buffered();
+    let result = run_with_shell(opt, &mut buffered);
+    shell.out().write_all(buffered.buffered_stdout())?;
+    shell.out().flush()?;
+    if result.is_err() {
+        shell.err().write_all(buffered.buffered_bytes())?;
+    }
+    result
 }
 
-#[allow(clippy::too_many_arguments)]
-fn build(
-    shell: &mut Shell,
-    target_dir: &Utf8Path,
-    manifest_dir: &Utf8Path,
-    bin_name: &str,
-    use_cross: bool,
-    target: &str,
-    strip_exe: Option<&Path>,
-    no_upx: bool,
-) -> anyhow::Result<String> {
-    fn run_command(
-        shell: &mut Shell,
-        cwd: &Utf8Path,
-        program: impl AsRef<OsStr>,
-        args: &[impl AsRef<OsStr>],
-        before_spawn: fn(&mut duct::Expression),
-    ) -> anyhow::Result<()> {
-        let program = program.as_ref();
-        let program = which::which_in(&program, env::var_os("PATH"), cwd)
-            .map_err(|_| anyhow!("`{}` does not seem to exist", program.to_string_lossy()))?;
-        let args = args.iter().map(AsRef::as_ref).collect::<Vec<_>>();
-
-        let format = format!(
-            "`{}{}`",
-            shell_escape::escape(program.to_string_lossy()),
-            args.iter().format_with("", |arg, f| f(&format_args!(
-                " {}",
-                shell_escape::escape(arg.to_string_lossy()),
-            ))),
-        );
-
-        shell.status("Running", &format)?;
-        let mut cmd = duct::cmd(program, args).dir(cwd);
-        before_spawn(&mut cmd);
-        cmd.run()
-            .with_context(|| format!("{} didn't exit successfully", format))?;
-        Ok(())
+fn run_with_shell(opt: Opt, shell: &mut Shell) -> anyhow::Result<()> {
+    if let Opt::ExecutablePayload {
+        os_matrix: Some(targets),
+        ..
+    } = &opt
+    {
+        let targets = if targets.is_empty() {
+            OS_MATRIX_TARGETS.iter().map(|&t| t.to_owned()).collect()
+        } else {
+            targets.clone()
+        };
+        for target in targets {
+            let mut opt = opt.clone();
+            let Opt::ExecutablePayload {
+                target: opt_target,
+                os_matrix,
+                ..
+            } = &mut opt;
+            *opt_target = target;
+            *os_matrix = None;
+            run_with_shell(opt, shell)?;
+        }
+        return Ok(());
     }
 
-    let tempdir = tempfile::Builder::new()
-        .prefix("cargo-executable-payload-")
-        .tempdir()?;
+    let unused = unused_flags(&opt);
+
+    let Opt::ExecutablePayload {
+        use_cross,
+        strip_exe,
+        use_objcopy,
+        objcopy_exe,
+        no_strip_for,
+        no_upx,
+        no_upx_for,
+        verify_upx,
+        slim_elf,
+        remove_interp,
+        require_tools,
+        report_unused_flags,
+        strict,
+        assert_stable,
+        warn_slow,
+        quiet_tools,
+        quiet_on_success,
+        compression,
+        compression_level,
+        xz_exe,
+        extract_name,
+        extract_path,
+        emit,
+        encoding,
+        const_decode,
+        checksum_algo,
+        runtime_decoder,
+        output,
+        output_dir,
+        output_tempfile,
+        mkdir,
+        sidecar_hash,
+        print_gitignore,
+        minify_source_comment,
+        compress_source_comment,
+        no_fsync,
+        write_retries,
+        exec_retries,
+        exec_method,
+        spawn,
+        self_delete,
+        no_new_privs,
+        env_passthrough,
+        preserve_arg0,
+        banner,
+        inner_attr,
+        edition,
+        template,
+        template_var,
+        template_strict,
+        #[cfg(feature = "upload")]
+        upload,
+        #[cfg(feature = "upload")]
+        header,
+        embed_provenance,
+        version_check,
+        show_diff,
+        check_tools,
+        print_decoded_size,
+        max_size,
+        preflight,
+        src,
+        bin,
+        diagnose,
+        from_binary,
+        target,
+        universal,
+        os_matrix: _,
+        multiarch,
+        require_musl,
+        assert_arch,
+        target_cpu,
+        panic,
+        rustc_wrapper,
+        features,
+        glibc_version,
+        manifest_path,
+        profile_file: _,
+    } = opt;
+
+    if report_unused_flags {
+        for message in &unused {
+            shell.status("Unused", message)?;
+        }
+        if strict && !unused.is_empty() {
+            bail!(
+                "{} (`--strict` is specified)",
+                match unused.len() {
+                    1 => "1 option has no effect".to_owned(),
+                    n => format!("{} options have no effect", n),
+                },
+            );
+        }
+    }
+
+    let cwd = env::current_dir().with_context(|| "failed to get CWD")?;
+
+    // Their output is written to `shell` if they fail.
+    let quiet_tools = quiet_tools || quiet_on_success;
 
-    let program = if use_cross {
-        "cross".into()
+    // `UNIVERSAL_TARGET` is not a real target, so what the user sees is `target_name`.
+    let (target, target_name) = if universal {
+        (UNIVERSAL_TARGET.to_owned(), UNIVERSAL_TARGETS.join("+"))
     } else {
-        env::var_os("CARGO").with_context(|| "`$CARGO` is not present")?
+        (target.clone(), target)
     };
-    let args = vec![
-        OsStr::new("build"),
-        OsStr::new("--release"),
-        OsStr::new("--bin"),
-        OsStr::new(bin_name),
-        OsStr::new("--target"),
-        OsStr::new(target),
-    ];
-    run_command(shell, manifest_dir, program, &args, |_| ())?;
 
-    let mut artifact_path = target_dir.join(target).join("release").join(bin_name);
-    if target.contains("windows") {
-        artifact_path.set_extension("exe");
+    if multiarch {
+        if !target.starts_with("x86_64-") {
+            bail!("`--multiarch` requires an x86_64 target");
+        }
+        if emit != Emit::Rust || encoding != Encoding::Base64 {
+            bail!("`--multiarch` requires `--emit rust` and `--encoding base64`");
+        }
+        if checksum_algo != ChecksumAlgo::None {
+            bail!("`--multiarch` does not support `--checksum-algo`");
+        }
     }
 
-    let artifact_file_name = artifact_path.file_name().unwrap_or("");
+    let exec_method = match (spawn, exec_method) {
+        (true, ExecMethod::PosixSpawn) => {
+            bail!("`--spawn` cannot be used with `--exec-method posix-spawn`")
+        }
+        (true, _) => ExecMethod::Spawn,
+        (false, exec_method) => exec_method,
+    };
+
+    if const_decode && (emit != Emit::Rust || encoding != Encoding::Base64) {
+        bail!("`--const-decode` requires `--emit rust` and `--encoding base64`");
+    }
+
+    if let Some(version_check) = version_check {
+        check_format_version(shell, &cwd.join(version_check))?;
+    }
+
+    let assert_arch = if assert_arch {
+        Some(runtime_arch_os(&target).with_context(|| {
+            format!(
+                "could not determine the architecture and OS of `{}`",
+                target
+            )
+        })?)
+    } else {
+        None
+    };
 
-    std::fs::copy(&artifact_path, tempdir.path().join(artifact_file_name))?;
+    if emit == Emit::Split && (output_dir.is_none() || encoding != Encoding::Base64) {
+        bail!("`--emit split` requires `--output-dir` and `--encoding base64`");
+    }
+    if emit == Emit::Split && multiarch {
+        bail!("`--emit split` cannot be used with `--multiarch`");
+    }
 
-    let artifact_path = tempdir.path().join(artifact_file_name);
+    if show_diff.is_some() && emit != Emit::Rust {
+        bail!("`--show-diff` requires `--emit rust`");
+    }
 
-    let program = strip_exe.unwrap_or_else(|| "strip".as_ref());
-    if let Ok(program) = which::which_in(program, env::var_os("PATH"), manifest_dir) {
-        let args = [OsStr::new("-s"), artifact_path.as_ref()];
-        run_command(shell, manifest_dir, program, &args, |_| ())?;
+    // `--preflight` reports it along with the other checks.
+    if require_musl && !preflight {
+        check_musl(&target)?;
     }
 
+    let strip_exe = strip_exe.map(|p| cwd.join(p));
+    let objcopy_exe = objcopy_exe.map(|p| cwd.join(p));
+    let xz_exe = xz_exe.map(|p| cwd.join(p));
+    let matches_any_target = |patterns: &[String]| {
+        let targets = if universal {
+            UNIVERSAL_TARGETS.to_vec()
+        } else {
+            vec![&*target]
+        };
+        patterns
+            .iter()
+            .any(|p| targets.iter().any(|t| matches_target(p, t)))
+    };
+    let no_strip = matches_any_target(&no_strip_for);
+    let no_upx = no_upx || matches_any_target(&no_upx_for);
+
     if !no_upx {
-        if let Ok(program) = which::which_in("upx", env::var_os("PATH"), manifest_dir) {
-            let args = [OsStr::new("--best"), artifact_path.as_ref()];
-            run_command(shell, manifest_dir, program, &args, |cmd| {
-                *cmd = cmd.stdout_to_stderr();
-            })?;
+        Compressor::Upx.level_arg(compression_level)?;
+    }
+    if compression.contains(&Compression::Gzip) {
+        Compressor::Gzip.level_arg(compression_level)?;
+    }
+    if compression.contains(&Compression::Lzma) {
+        Compressor::Xz.level_arg(compression_level)?;
+    }
+
+    if check_tools {
+        let tools = resolve_tools(
+            &cwd,
+            use_cross,
+            &target,
+            strip_exe.as_deref(),
+            use_objcopy,
+            objcopy_exe.as_deref(),
+            no_strip,
+            no_upx,
+            slim_elf,
+            universal,
+            &compression,
+            xz_exe.as_deref(),
+        );
+        let stdout = shell.out();
+        for (name, resolution) in tools {
+            writeln!(stdout, "{}: {}", name, resolution)?;
         }
+        return stdout.flush().map_err(Into::into);
     }
 
-    let artifact = std::fs::read(artifact_path)?;
-    let artifact = base64::encode(artifact);
+    let from_binary = from_binary.map(|p| cwd.join(p));
 
-    tempdir.close()?;
-    Ok(artifact)
-}
+    let mut metadata = Vec::<cm::Metadata>::new();
+    let input = if let Some(from_binary) = &from_binary {
+        Input::Binary(from_binary)
+    } else {
+        let manifest_paths = if manifest_path.is_empty() {
+            vec![locate_project(&cwd)?]
+        } else {
+            manifest_path
+                .iter()
+                .map(|p| cwd.join(p.strip_prefix(".").unwrap_or(p)))
+                .collect()
+        };
+        for manifest_path in manifest_paths {
+            let m = cargo_metadata(&manifest_path, &cwd)?;
+            if !metadata
+                .iter()
+                .any(|m2| m2.workspace_root == m.workspace_root)
+            {
+                metadata.push(m);
+            }
+        }
 
-fn format_with_template(original_source_code: &str, payload: &str) -> String {
-    formatdoc! {r#"
-        //! This code is generated by [cargo-executable-payload](https://github.com/qryxip/cargo-executable-payload).
+        let target = if let Some(bin) = &bin {
+            bin_target_by_name(&metadata, bin)
+        } else if let Some(src) = &src {
+            bin_target_by_src_path(&metadata, &cwd.join(src))
+        } else {
+            exactly_one_bin_target(&metadata)
+        };
+        if target.is_err() && diagnose {
+            shell
+                .err()
+                .write_all(diagnose_targets(&metadata).as_bytes())?;
+        }
+        Input::Target(target?)
+    };
 
-        original_source_code! {{
-        {original_source_code}}}
+    // With `--from-binary`, the executable stands in for the bin target.
+    let (manifest_dir, package_name, bin_name, src_path) = match input {
+        Input::Target((bin, bin_package, _)) => (
+            bin_package.manifest_path.with_file_name(""),
+            &*bin_package.name,
+            &*bin.name,
+            Some(bin.src_path.clone().into_std_path_buf()),
+        ),
+        Input::Binary(from_binary) => (
+            Utf8Path::from_path(&cwd)
+                .with_context(|| "the CWD is not valid UTF-8")?
+                .to_owned(),
+            "",
+            from_binary
+                .file_stem()
+                .and_then(OsStr::to_str)
+                .with_context(|| format!("invalid file name: {}", from_binary.display()))?,
+            src.as_ref().map(|src| cwd.join(src)),
+        ),
+    };
+    let edition = match (edition, &input) {
+        (Some(edition), _) => edition,
+        (None, Input::Target((bin, ..))) => bin.edition.parse().unwrap_or(2018),
+        (None, Input::Binary(_)) => 2018,
+    };
 
-        fn main()->std::io::Result<()>{{use std::{{fs::{{File,Permissions}},io::Write as _,os::unix::{{fs::PermissionsExt as _,process::CommandExt as _}},process::Command}};let mut file=File::create(PATH)?;file.write_all(&decode())?;file.set_permissions(Permissions::from_mode(0o755))?;file.sync_all()?;drop(file);Err(Command::new(PATH).exec())}}fn decode()->Vec<u8>{{let mut table=[0;256];for(i,&c)in b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/".iter().enumerate(){{table[usize::from(c)]=i as u8;}}let mut acc=vec![];for chunk in PAYLOAD.as_bytes().chunks(4){{let index0=table[usize::from(chunk[0])];let index1=table[usize::from(chunk[1])];let index2=table[usize::from(chunk[2])];let index3=table[usize::from(chunk[3])];acc.push((index0<<2)+(index1>>4));acc.push((index1<<4)+(index2>>2));acc.push((index2<<6)+index3)}}if PAYLOAD.ends_with("=="){{acc.pop();acc.pop();}}else if PAYLOAD.ends_with('='){{acc.pop();}}acc}}#[macro_export]macro_rules!original_source_code{{($($_:tt)*)=>()}}static PATH:&str="/tmp/a.out";static PAYLOAD:&str="{payload}";
-        "#,
-        original_source_code = indent_code(original_source_code),
-        payload = payload,
+    if universal {
+        for target in UNIVERSAL_TARGETS {
+            validate_target(target, rustc_target_list(&manifest_dir)?)?;
+        }
+    } else if !target.ends_with(".json") {
+        validate_target(&target, rustc_target_list(&manifest_dir)?)?;
+        if let Some(target_cpu) = &target_cpu {
+            let target_cpus = rustc_target_cpus(&manifest_dir, &target)?;
+            if !target_cpus.contains(target_cpu) {
+                shell.warn(format!(
+                    "`{}` is not a CPU known to rustc for `{}`. see `rustc --print target-cpus \
+                     --target {}`",
+                    target_cpu, target, target,
+                ))?;
+            }
+        }
     }
-}
 
-fn indent_code(code: &str) -> String {
-    return if code.parse::<TokenStream>().map_or(false, is_safe_to_indent) {
-        code.lines()
-            .map(|line| match line {
-                "" => "\n".to_owned(),
-                line => format!("    {}\n", line),
-            })
-            .join("")
+    let output = if let Some(output_dir) = &output_dir {
+        let file_name = output_file_name(package_name, bin_name, &target_name, emit);
+        Some(cwd.join(output_dir).join(file_name))
+    } else if emit == Emit::Binary {
+        output.map(|p| with_executable_extension(p, &target))
     } else {
-        code.to_owned()
+        output
     };
+    let output = output.filter(|p| !is_stdout(p));
 
-    fn is_safe_to_indent(tokens: TokenStream) -> bool {
-        tokens.into_iter().all(|tt| match tt {
-            TokenTree::Group(group) => is_safe_to_indent(group.stream()),
-            TokenTree::Literal(lit) => lit.span().start().line == lit.span().end().line,
-            _ => true,
+    if sidecar_hash && output.is_none() {
+        bail!("`--sidecar-hash` requires `--output` to be a file");
+    }
+
+    if print_gitignore {
+        let stdout = shell.out();
+        let entry = output
+            .as_deref()
+            .filter(|_| !output_tempfile)
+            .and_then(|output| gitignore_entry(output, &cwd));
+        if let Some(entry) = entry {
+            writeln!(stdout, "{}", entry)?;
+        }
+        return stdout.flush().map_err(Into::into);
+    }
+
+    let (extract_path, exact_extract_path) = if let Some(extract_path) = extract_path {
+        if extract_path.is_empty() {
+            bail!("invalid extract path: {:?}", extract_path);
+        }
+        (extract_path, true)
+    } else {
+        let extract_name = extract_name.unwrap_or_else(|| default_extract_name(bin_name));
+        validate_extract_name(&extract_name)?;
+        (extract_name, false)
+    };
+    for key in &env_passthrough {
+        if key.is_empty() || key.contains(&['=', '\0'][..]) {
+            bail!("invalid environment variable name: {:?}", key);
+        }
+    }
+    if !inner_attr.is_empty() && emit == Emit::RustFragment {
+        bail!("`--inner-attr` cannot be used with `--emit rust-fragment`");
+    }
+    for attr in &inner_attr {
+        validate_inner_attr(attr)?;
+    }
+    let template = template
+        .map(|path| {
+            std::fs::read_to_string(&path)
+                .with_context(|| format!("could not read `{}`", path.display()))
         })
+        .transpose()?;
+    let mut template_vars = vec![];
+    for var in &template_var {
+        let (key, value) = var
+            .split_once('=')
+            .with_context(|| format!("expected `KEY=VALUE` for `--template-var`: {:?}", var))?;
+        if !is_template_key(key) || TEMPLATE_BUILTIN_KEYS.contains(&key) {
+            bail!("invalid key for `--template-var`: {:?}", key);
+        }
+        if template_vars.iter().any(|&(k, _)| k == key) {
+            bail!("`--template-var {}` is given more than once", key);
+        }
+        template_vars.push((key, value));
     }
-}
 
-#[cfg(test)]
-mod tests {
-    use indoc::indoc;
-    use test_case::test_case;
+    let source_code = if let Some(src_path) = &src_path {
+        std::fs::read_to_string(src_path)
+            .with_context(|| format!("could not read `{}`", src_path.display()))?
+    } else {
+        "// The source code is not available.\n".to_owned()
+    };
+    if let (Some(src_path), Some(Panic::Abort)) = (&src_path, panic) {
+        if source_code.contains("catch_unwind") {
+            shell.warn(format!(
+                "`{}` seems to use `catch_unwind`, which does not work with `--panic abort`",
+                src_path.display(),
+            ))?;
+        }
+    }
+    let source_code = if minify_source_comment {
+        minify_code(&source_code)
+    } else {
+        source_code
+    };
 
-    #[test_case(indoc!("") => indoc!(""); "empty")]
-    #[test_case(
-        indoc! {r#"
-            #![warn(rust_2018_idioms)]
+    let extra_rustflags = extra_rustflags(target_cpu.as_deref(), panic);
+    // A bare name is searched in `$PATH` by Cargo.
+    let rustc_wrapper = rustc_wrapper.map(|p| {
+        if p.components().count() > 1 {
+            cwd.join(p)
+        } else {
+            p
+        }
+    });
+    let features = features_arg(&features, env::var_os(FEATURES_ENV).as_deref());
+    let zig_cc = glibc_version
+        .map(|version| -> anyhow::Result<_> {
+            if cfg!(not(unix)) {
+                bail!("`--glibc-version` is only supported on Unix hosts");
+            }
+            let zig_target = zig_glibc_target(&target, &version)?;
+            let zig = find_zig(env::var_os("PATH").as_deref(), cwd.as_ref())?;
+            Ok((zig, zig_target))
+        })
+        .transpose()?;
+    let build = |shell: &mut Shell, extra_rustflags: &[String]| match input {
+        Input::Target((_, _, metadata)) => build(
+            shell,
+            &metadata.target_directory,
+            &manifest_dir,
+            bin_name,
+            use_cross,
+            &target,
+            extra_rustflags,
+            panic == Some(Panic::Abort),
+            rustc_wrapper.as_deref(),
+            features.as_deref(),
+            zig_cc
+                .as_ref()
+                .map(|(zig, zig_target)| (&**zig, &**zig_target)),
+            strip_exe.as_deref(),
+            use_objcopy,
+            objcopy_exe.as_deref(),
+            no_strip,
+            no_upx,
+            compression_level,
+            verify_upx,
+            slim_elf,
+            remove_interp,
+            require_tools,
+            universal,
+            quiet_tools,
+        ),
+        Input::Binary(from_binary) => process_prebuilt(
+            shell,
+            from_binary,
+            &manifest_dir,
+            &target,
+            strip_exe.as_deref(),
+            use_objcopy,
+            objcopy_exe.as_deref(),
+            no_strip,
+            no_upx,
+            compression_level,
+            verify_upx,
+            slim_elf,
+            remove_interp,
+            require_tools,
+            quiet_tools,
+        ),
+    };
+    let started = Instant::now();
+    let artifact = build(shell, &extra_rustflags)?;
+    if assert_stable {
+        check_stable(&artifact, &build(shell, &extra_rustflags)?)?;
+    }
+    // `artifact` is for x86-64 (the default of x86_64 targets) with `--multiarch`.
+    let artifact_v3 = if multiarch {
+        let mut extra_rustflags = extra_rustflags.clone();
+        extra_rustflags.push("-C target-cpu=x86-64-v3".to_owned());
+        Some(build(shell, &extra_rustflags)?)
+    } else {
+        None
+    };
+    if let Some(warn_slow) = warn_slow {
+        note_if_slow(shell, started, Duration::from_secs(warn_slow))?;
+    }
 
-            fn main() {
-                println!("Hello, world!");
+    if print_decoded_size {
+        let stdout = shell.out();
+        self::print_decoded_size(&mut *stdout, &artifact)?;
+        return stdout.flush().map_err(Into::into);
+    }
+
+    // The content of `payload.b64` for `--emit split`.
+    let mut payload_file_content = None;
+    // `artifact` is moved into the output with `--emit binary`.
+    let (output_content, artifact) = match emit {
+        Emit::Rust | Emit::Split | Emit::RustFragment => {
+            let encode = |shell: &mut Shell, artifact: &[u8]| -> anyhow::Result<_> {
+                let mut payload = artifact.to_owned();
+                for &compression in &compression {
+                    payload = compress(
+                        shell,
+                        &manifest_dir,
+                        compression,
+                        compression_level,
+                        xz_exe.as_deref(),
+                        &payload,
+                        quiet_tools,
+                    )?;
+                }
+                Ok(match encoding {
+                    Encoding::Base64 => (base64::encode(payload), 0),
+                    Encoding::Z85 => z85_encode(&payload),
+                })
+            };
+            let (payload, padding) = encode(shell, &artifact)?;
+            if emit == Emit::Split {
+                payload_file_content = Some(payload.clone());
             }
-        "#} => indoc! {r#"
-            |    #![warn(rust_2018_idioms)]
-            |
-            |    fn main() {
-            |        println!("Hello, world!");
-            |    }
-        "#};
-        "no_multi_line_literal"
-    )]
-    #[test_case(
-        indoc! {r#"
-            #![warn(rust_2018_idioms)]
+            let payload_v3 = artifact_v3
+                .as_ref()
+                .map(|artifact_v3| encode(shell, artifact_v3))
+                .transpose()?;
 
-            fn main() {
-                println!(
-                    "Hello, \
-                     world!"
-                );
+            let source_comment = if compress_source_comment {
+                let mut compressed = source_code.clone().into_bytes();
+                for &compression in &compression {
+                    compressed = compress(
+                        shell,
+                        &manifest_dir,
+                        compression,
+                        compression_level,
+                        xz_exe.as_deref(),
+                        &compressed,
+                        quiet_tools,
+                    )?;
+                }
+                compressed_source_comment(&compression, source_code.len(), &compressed)
+            } else {
+                source_code.clone()
+            };
+
+            let provenance = if embed_provenance {
+                Some(Provenance {
+                    commit: git_head(manifest_dir.as_ref()),
+                    built_at: SystemTime::now(),
+                })
+            } else {
+                None
+            };
+
+            let output_content = format_with_template(&Template {
+                original_source_code: &source_comment,
+                payload: &payload,
+                payload_len: artifact.len(),
+                payload_file: if emit == Emit::Split {
+                    Some(PAYLOAD_FILE_NAME)
+                } else {
+                    None
+                },
+                payload_v3: payload_v3
+                    .as_ref()
+                    .zip(artifact_v3.as_ref())
+                    .map(|((payload, _), artifact)| (&**payload, artifact.len())),
+                encoding,
+                padding,
+                const_decode,
+                checksum: checksum_algo.checksum(&artifact),
+                compression: &compression,
+                runtime_decoder,
+                extract_path: &extract_path,
+                exact_extract_path,
+                provenance: provenance.as_ref(),
+                no_fsync,
+                write_retries,
+                exec_retries,
+                spawn: exec_method == ExecMethod::Spawn,
+                posix_spawn: exec_method == ExecMethod::PosixSpawn,
+                self_delete,
+                no_new_privs,
+                env_passthrough: &env_passthrough,
+                preserve_arg0,
+                assert_arch,
+                banner: banner.as_deref(),
+                inner_attrs: &inner_attr,
+                unsafe_extern: edition >= 2024,
+                fragment: emit == Emit::RustFragment,
+            });
+            let output_content = if let Some(template) = &template {
+                let mut vars = vec![
+                    ("payload", &*output_content),
+                    ("source", &source_code),
+                    ("path", &extract_path),
+                ];
+                vars.extend(template_vars.iter().copied());
+                let (rendered, used) = render_template(template, &vars, template_strict)?;
+                let unused = template_vars
+                    .iter()
+                    .map(|&(key, _)| key)
+                    .filter(|key| !used.contains(key))
+                    .collect::<Vec<_>>();
+                if !unused.is_empty() {
+                    let message = format!(
+                        "unused `--template-var`: {}",
+                        unused
+                            .iter()
+                            .format_with(", ", |k, f| f(&format_args!("`{}`", k))),
+                    );
+                    if template_strict {
+                        bail!("{}", message);
+                    }
+                    shell.warn(message)?;
+                }
+                rendered
+            } else {
+                output_content
+            };
+            (output_content.into_bytes(), Some(artifact))
+        }
+        Emit::Binary => (artifact, None),
+    };
+    let artifact = artifact.as_deref().unwrap_or(&output_content);
+
+    if preflight {
+        let checks = preflight_checks(
+            &target,
+            require_musl,
+            max_size,
+            emit,
+            artifact,
+            &output_content,
+            payload_file_content.as_deref(),
+            xz_exe.as_deref(),
+        );
+        report_preflight(shell, &checks)?;
+    } else if let Some(max_size) = max_size {
+        if output_content.len() > max_size {
+            bail!(
+                "the output is {} bytes, which exceeds `--max-size` ({} bytes)",
+                output_content.len(),
+                max_size,
+            );
+        }
+    }
+
+    // This may be the file to be overwritten.
+    if let Some(show_diff) = show_diff {
+        let path = cwd.join(show_diff);
+        let old = std::fs::read_to_string(&path)
+            .with_context(|| format!("could not read `{}`", path.display()))?;
+        let new = std::str::from_utf8(&output_content)?;
+        match compare_generated(&old, new, &path.to_string_lossy()) {
+            GeneratedDiff::Unchanged => {
+                shell.status("Compared", format!("no changes from `{}`", path.display()))?
             }
-        "#} => indoc! {r#"
-            |#![warn(rust_2018_idioms)]
-            |
-            |fn main() {
-            |    println!(
-            |        "Hello, \
-            |         world!"
-            |    );
-            |}
-        "#};
-        "multi_line_literal"
-    )]
-    fn indent_code_margined(code: &str) -> String {
-        crate::indent_code(code)
-            .lines()
-            .map(|s| format!("|{}\n", s))
-            .collect()
+            GeneratedDiff::PayloadOnly => shell.status(
+                "Compared",
+                format!("only the payload changed from `{}`", path.display()),
+            )?,
+            GeneratedDiff::Changed(diff) => {
+                shell.status(
+                    "Compared",
+                    format!("more than the payload changed from `{}`", path.display()),
+                )?;
+                shell.err().write_all(diff.as_bytes())?;
+            }
+        }
+    }
+
+    if let Some(output_dir) = &output_dir {
+        let output_dir = cwd.join(output_dir);
+        std::fs::create_dir_all(&output_dir)
+            .with_context(|| format!("could not create `{}`", output_dir.display()))?;
+    }
+    // Held until `payloads.json` is updated.
+    let mut _lock = None;
+    let output = if output_tempfile {
+        let suffix = match emit {
+            Emit::Rust | Emit::Split | Emit::RustFragment => Some("rs"),
+            Emit::Binary => executable_extension(&target),
+        };
+        let suffix = suffix.map(|s| format!(".{}", s)).unwrap_or_default();
+        let path = write_tempfile(&output_content, &suffix)?;
+        let stdout = shell.out();
+        writeln!(stdout, "{}", path.display())?;
+        stdout.flush()?;
+        Some(path)
+    } else if let Some(output) = output {
+        create_parent(&output, mkdir)?;
+        _lock = lock_output_dir(&output)?;
+        // Keep the mtime for build systems.
+        if std::fs::read(&output).ok().as_deref() == Some(&*output_content) {
+            shell.status("Fresh", format!("`{}`", output.display()))?;
+        } else {
+            write_output(&output, &output_content, mkdir)?;
+        }
+        if sidecar_hash {
+            write_sidecar_hash(&output, &output_content)?;
+        }
+        if let Some(payload) = &payload_file_content {
+            let path = output.with_file_name(PAYLOAD_FILE_NAME);
+            if std::fs::read(&path).ok().as_deref() == Some(payload.as_bytes()) {
+                shell.status("Fresh", format!("`{}`", path.display()))?;
+            } else {
+                write_output(&path, payload.as_bytes(), mkdir)?;
+            }
+        }
+        Some(output)
+    } else {
+        let stdout = shell.out();
+        stdout.write_all(&output_content)?;
+        stdout.flush()?;
+        None
+    };
+
+    if let (Some(output_dir), Some(output)) = (&output_dir, &output) {
+        update_payloads_manifest(
+            &cwd.join(output_dir),
+            &PayloadEntry {
+                package: package_name,
+                bin: bin_name,
+                target: &target_name,
+                emit,
+                executable_size: artifact.len(),
+                size: output_content.len(),
+                file: &output.file_name().unwrap_or_default().to_string_lossy(),
+            },
+        )?;
+    }
+
+    #[cfg(feature = "upload")]
+    if let Some(url) = upload {
+        self::upload(shell, &url, &header, &output_content)?;
+    }
+
+    shell.status(
+        "Finished",
+        finished_message(&target_name, output_content.len(), output.as_deref()),
+    )?;
+    Ok(())
+}
+
+#[cfg(feature = "upload")]
+fn upload(shell: &mut Shell, url: &str, headers: &[String], content: &[u8]) -> anyhow::Result<()> {
+    let curl = which::which("curl").map_err(|_| anyhow!("`curl` does not seem to exist"))?;
+
+    let mut args = vec!["-sS", "-o", "/dev/null", "-w", "%{http_code}"];
+    args.extend(&["-X", "POST", "--data-binary", "@-"]);
+    for header in headers {
+        if !header.contains(':') {
+            bail!("invalid header: {:?}", header);
+        }
+        args.extend(&["-H", header]);
+    }
+    args.push(url);
+
+    shell.status("Uploading", format!("to `{}`", url))?;
+    let status = duct::cmd(curl, args)
+        .stdin_bytes(content)
+        .read()
+        .with_context(|| format!("could not upload to `{}`", url))?;
+    let status = status
+        .parse::<u16>()
+        .with_context(|| format!("unexpected output from `curl`: {:?}", status))?;
+    if !(200..300).contains(&status) {
+        bail!("`{}` responded with status {}", url, status);
+    }
+    shell.status("Uploaded", format!("to `{}` (status {})", url, status))?;
+    Ok(())
+}
+
+fn finished_message(target: &str, size: usize, output: Option<&Path>) -> String {
+    format!(
+        "`release` profile for `{}` ({} bytes) to {}",
+        target,
+        size,
+        output.map_or_else(|| "stdout".to_owned(), |p| format!("`{}`", p.display())),
+    )
+}
+
+fn is_stdout(output: &Path) -> bool {
+    output == Path::new("-")
+}
+
+fn matches_target(pattern: &str, target: &str) -> bool {
+    let mut pieces = pattern.split('*');
+    let first = pieces.next().unwrap_or("");
+    let mut rest = match target.strip_prefix(first) {
+        Some(rest) => rest,
+        None => return false,
+    };
+    let mut pieces = pieces.collect::<Vec<_>>();
+    let last = match pieces.pop() {
+        Some(last) => last,
+        None => return rest.is_empty(),
+    };
+    for piece in pieces {
+        match rest.find(piece) {
+            Some(i) => rest = &rest[i + piece.len()..],
+            None => return false,
+        }
+    }
+    rest.ends_with(last)
+}
+
+fn locate_project(cwd: &Path) -> anyhow::Result<PathBuf> {
+    cwd.ancestors()
+        .map(|p| p.join("Cargo.toml"))
+        .find(|p| p.exists())
+        .with_context(|| {
+            format!(
+                "could not find `Cargo.toml` in `{}` or any parent directory",
+                cwd.display(),
+            )
+        })
+}
+
+fn cargo_metadata(manifest_path: &Path, cwd: &Path) -> cm::Result<cm::Metadata> {
+    cm::MetadataCommand::new()
+        .manifest_path(manifest_path)
+        .current_dir(cwd)
+        .exec()
+}
+
+fn rustc_target_list(cwd: &Utf8Path) -> anyhow::Result<&'static [String]> {
+    static TARGET_LIST: OnceCell<Vec<String>> = OnceCell::new();
+
+    TARGET_LIST
+        .get_or_try_init(|| {
+            let rustc = env::var_os("RUSTC").unwrap_or_else(|| "rustc".into());
+            let target_list = duct::cmd(&rustc, &["--print", "target-list"])
+                .dir(cwd)
+                .read()
+                .with_context(|| {
+                    format!(
+                        "could not get the target list from `{}`",
+                        rustc.to_string_lossy(),
+                    )
+                })?;
+            Ok(target_list.lines().map(ToOwned::to_owned).collect())
+        })
+        .map(|target_list| &**target_list)
+}
+
+fn rustc_host(cwd: &Utf8Path) -> anyhow::Result<&'static str> {
+    static HOST: OnceCell<String> = OnceCell::new();
+
+    HOST.get_or_try_init(|| {
+        let rustc = env::var_os("RUSTC").unwrap_or_else(|| "rustc".into());
+        let version = duct::cmd(&rustc, &["-vV"])
+            .dir(cwd)
+            .read()
+            .with_context(|| {
+                format!("could not get the host from `{}`", rustc.to_string_lossy())
+            })?;
+        version
+            .lines()
+            .find_map(|line| line.strip_prefix("host: "))
+            .map(ToOwned::to_owned)
+            .with_context(|| format!("unexpected output from `{} -vV`", rustc.to_string_lossy()))
+    })
+    .map(|host| &**host)
+}
+
+fn rustc_target_cpus(cwd: &Utf8Path, target: &str) -> anyhow::Result<Vec<String>> {
+    let rustc = env::var_os("RUSTC").unwrap_or_else(|| "rustc".into());
+    let target_cpus = duct::cmd(&rustc, &["--print", "target-cpus", "--target", target])
+        .dir(cwd)
+        .read()
+        .with_context(|| {
+            format!(
+                "could not get the CPU list from `{}`",
+                rustc.to_string_lossy(),
+            )
+        })?;
+    Ok(parse_target_cpus(&target_cpus))
+}
+
+/// Parses the output of `rustc --print target-cpus`, whose first line is a header.
+fn parse_target_cpus(output: &str) -> Vec<String> {
+    output
+        .lines()
+        .skip(1)
+        .flat_map(|line| line.split_whitespace().next())
+        .map(ToOwned::to_owned)
+        .collect()
+}
+
+fn extra_rustflags(target_cpu: Option<&str>, panic: Option<Panic>) -> Vec<String> {
+    let mut extra_rustflags = vec![];
+    if let Some(target_cpu) = target_cpu {
+        extra_rustflags.push(format!("-C target-cpu={}", target_cpu));
+    }
+    if let Some(panic) = panic {
+        extra_rustflags.push(format!("-C panic={}", panic.as_str()));
+    }
+    extra_rustflags
+}
+
+/// Reads the Cargo configuration files for `cwd`, deeper ones first, then the one in
+/// `$CARGO_HOME`.
+fn cargo_configs(
+    cwd: &Path,
+    var: impl Fn(&str) -> Option<String>,
+) -> anyhow::Result<Vec<(PathBuf, toml::Value)>> {
+    let cargo_home = var("CARGO_HOME")
+        .map(PathBuf::from)
+        .or_else(|| var("HOME").map(|home| Path::new(&home).join(".cargo")));
+    let mut config_paths = cwd
+        .ancestors()
+        .map(|dir| dir.join(".cargo"))
+        .chain(cargo_home)
+        .flat_map(|dir| vec![dir.join("config.toml"), dir.join("config")])
+        .filter(|path| path.is_file())
+        .collect::<Vec<_>>();
+    config_paths.dedup();
+    config_paths
+        .into_iter()
+        .map(|path| {
+            let config = std::fs::read_to_string(&path)
+                .with_context(|| format!("could not read `{}`", path.display()))?;
+            let config = toml::from_str::<toml::Value>(&config)
+                .with_context(|| format!("could not parse `{}`", path.display()))?;
+            Ok((path, config))
+        })
+        .collect()
+}
+
+/// Returns `build-std=<CRATES>` for `-Z` if `-Z build-std` is configured with
+/// `$CARGO_UNSTABLE_BUILD_STD` or `unstable.build-std`, with `panic_abort` added to the crates.
+/// `-C panic=abort` needs `panic_abort` to be built with the standard library.
+fn build_std_with_panic_abort(
+    cwd: &Path,
+    var: impl Fn(&str) -> Option<String>,
+) -> anyhow::Result<Option<String>> {
+    let split = |crates: &str| {
+        crates
+            .split(',')
+            .map(str::trim)
+            .filter(|c| !c.is_empty())
+            .map(ToOwned::to_owned)
+            .collect::<Vec<_>>()
+    };
+
+    let crates = if let Some(crates) = var("CARGO_UNSTABLE_BUILD_STD") {
+        split(&crates)
+    } else {
+        let configs = cargo_configs(cwd, &var)?;
+        let value = configs.iter().find_map(|(path, config)| {
+            let value = config.get("unstable")?.get("build-std")?;
+            Some((path, value))
+        });
+        match value {
+            None => return Ok(None),
+            Some((_, toml::Value::String(crates))) => split(crates),
+            Some((path, toml::Value::Array(crates))) => crates
+                .iter()
+                .map(|c| c.as_str().map(ToOwned::to_owned))
+                .collect::<Option<_>>()
+                .with_context(|| {
+                    format!(
+                        "`unstable.build-std` in `{}` is not strings",
+                        path.display()
+                    )
+                })?,
+            Some((path, _)) => bail!(
+                "`unstable.build-std` in `{}` is not a string or an array",
+                path.display(),
+            ),
+        }
+    };
+    // An empty list means `std`.
+    let mut crates = if crates.is_empty() {
+        vec!["std".to_owned()]
+    } else {
+        crates
+    };
+    if !crates.iter().any(|c| c == "panic_abort") {
+        crates.push("panic_abort".to_owned());
+    }
+    Ok(Some(format!("build-std={}", crates.join(","))))
+}
+
+/// Returns the rustflags Cargo would use for `target` in `cwd`, so that ones from the
+/// configuration survive setting `$CARGO_ENCODED_RUSTFLAGS`.
+///
+/// As Cargo does, the first of these is taken:
+///
+/// 1. `$CARGO_ENCODED_RUSTFLAGS`
+/// 2. `$RUSTFLAGS`
+/// 3. `$CARGO_TARGET_<TARGET>_RUSTFLAGS` or `target.<TARGET>.rustflags`
+/// 4. `$CARGO_BUILD_RUSTFLAGS` or `build.rustflags`
+///
+/// `target.'cfg(..)'.rustflags` are not evaluated, so they are an error.
+fn base_rustflags(
+    cwd: &Path,
+    target: &str,
+    var: impl Fn(&str) -> Option<String>,
+) -> anyhow::Result<Vec<String>> {
+    let split = |flags: &str| flags.split_whitespace().map(ToOwned::to_owned).collect();
+
+    if let Some(flags) = var("CARGO_ENCODED_RUSTFLAGS") {
+        return Ok(flags
+            .split('\x1f')
+            .filter(|f| !f.is_empty())
+            .map(ToOwned::to_owned)
+            .collect());
+    }
+    if let Some(flags) = var("RUSTFLAGS") {
+        return Ok(split(&flags));
+    }
+
+    let configs = cargo_configs(cwd, &var)?;
+
+    for (path, config) in &configs {
+        let targets = config.get("target").and_then(toml::Value::as_table);
+        for (key, value) in targets.into_iter().flatten() {
+            if key.starts_with("cfg(") && value.get("rustflags").is_some() {
+                bail!(
+                    "`target.'{}'.rustflags` in `{}` is not supported with `--target-cpu` or \
+                     `--panic`. use `$RUSTFLAGS` instead",
+                    key,
+                    path.display(),
+                );
+            }
+        }
+    }
+
+    // Arrays are merged, and the deepest string wins.
+    let from_config = |keys: &[&str]| -> anyhow::Result<Option<Vec<String>>> {
+        let mut flags = None::<Vec<String>>;
+        for (path, config) in &configs {
+            let value = keys.iter().try_fold(config, |value, key| value.get(key));
+            match value {
+                None => {}
+                Some(toml::Value::String(value)) => {
+                    flags.get_or_insert_with(|| split(value));
+                }
+                Some(toml::Value::Array(values)) => {
+                    let values = values
+                        .iter()
+                        .map(|v| v.as_str().map(ToOwned::to_owned))
+                        .collect::<Option<Vec<_>>>()
+                        .with_context(|| {
+                            format!(
+                                "`{}` in `{}` is not strings",
+                                keys.join("."),
+                                path.display()
+                            )
+                        })?;
+                    flags.get_or_insert_with(Vec::new).extend(values);
+                }
+                Some(_) => bail!(
+                    "`{}` in `{}` is not a string or an array",
+                    keys.join("."),
+                    path.display(),
+                ),
+            }
+        }
+        Ok(flags)
+    };
+
+    let target_key = format!(
+        "CARGO_TARGET_{}_RUSTFLAGS",
+        target.to_uppercase().replace(&['-', '.'][..], "_"),
+    );
+    if let Some(flags) = var(&target_key) {
+        return Ok(split(&flags));
+    }
+    if let Some(flags) = from_config(&["target", target, "rustflags"])? {
+        return Ok(flags);
+    }
+    if let Some(flags) = var("CARGO_BUILD_RUSTFLAGS") {
+        return Ok(split(&flags));
+    }
+    Ok(from_config(&["build", "rustflags"])?.unwrap_or_default())
+}
+
+fn check_musl(target: &str) -> anyhow::Result<()> {
+    let triple = target.strip_suffix(".json").map_or(target, |path| {
+        Path::new(path)
+            .file_name()
+            .and_then(OsStr::to_str)
+            .unwrap_or(path)
+    });
+    if !triple
+        .split('-')
+        .any(|component| component.starts_with("musl"))
+    {
+        bail!(
+            "`{}` is not a musl target (`--require-musl` is specified)",
+            target
+        );
+    }
+    Ok(())
+}
+
+fn validate_target(target: &str, target_list: &[String]) -> anyhow::Result<()> {
+    if target_list.iter().any(|t| t == target) {
+        return Ok(());
+    }
+    let suggestion = target_list
+        .iter()
+        .map(|t| (strsim::levenshtein(t, target), t))
+        .filter(|&(distance, _)| distance <= 3)
+        .min();
+    if let Some((_, suggestion)) = suggestion {
+        bail!(
+            "`{}` is not a target known to rustc. did you mean `{}`?",
+            target,
+            suggestion,
+        );
+    }
+    bail!(
+        "`{}` is not a target known to rustc. see `rustc --print target-list`",
+        target,
+    );
+}
+
+type BinTarget<'a> = (&'a cm::Target, &'a cm::Package, &'a cm::Metadata);
+
+/// What to wrap.
+#[derive(Clone, Copy)]
+enum Input<'a> {
+    Target(BinTarget<'a>),
+    /// An executable specified with `--from-binary`.
+    Binary(&'a Path),
+}
+
+fn bin_target_by_name<'a>(
+    metadata: &'a [cm::Metadata],
+    name: &str,
+) -> anyhow::Result<BinTarget<'a>> {
+    match *bin_targets(metadata)
+        .filter(|(t, ..)| t.name == name)
+        .collect::<Vec<_>>()
+    {
+        [] => bail!("no bin target named `{}`", name),
+        [bin] => Ok(bin),
+        [..] => bail!("multiple bin targets named `{}` in the workspace(s)", name),
+    }
+}
+
+fn bin_target_by_src_path<'a>(
+    metadata: &'a [cm::Metadata],
+    src_path: &Path,
+) -> anyhow::Result<BinTarget<'a>> {
+    match *bin_targets(metadata)
+        .filter(|(t, ..)| t.src_path == src_path)
+        .collect::<Vec<_>>()
+    {
+        [] => bail!(
+            "`{}` is not the main source file of any bin targets in the workspace(s)",
+            src_path.display(),
+        ),
+        [bin] => Ok(bin),
+        [..] => bail!(
+            "multiple bin targets which `src_path` is `{}`",
+            src_path.display(),
+        ),
+    }
+}
+
+fn exactly_one_bin_target(metadata: &[cm::Metadata]) -> anyhow::Result<BinTarget<'_>> {
+    match &*bin_targets(metadata).collect::<Vec<_>>() {
+        [] => match &*example_targets(metadata).collect::<Vec<_>>() {
+            [] => bail!("no bin target in the workspace(s)"),
+            examples => bail!(
+                "no bin target in the workspace(s).\n\
+                 available examples: {}\n\
+                 note: currently `cargo-executable-payload` does not support example targets.",
+                examples
+                    .iter()
+                    .map(|(cm::Target { name, .. }, ..)| name)
+                    .format(", "),
+            ),
+        },
+        [bin] => Ok(*bin),
+        bins => bail!(
+            "could not determine which binary to choose. Use the `--bin` option or `--src` option \
+             to specify a binary.\n\
+             available binaries: {}\n\
+             note: currently `cargo-executable-payload` does not support the `default-run` manifest \
+             key.",
+            bins.iter()
+                .map(|(cm::Target { name, .. }, ..)| name)
+                .format(", "),
+        ),
+    }
+}
+
+/// Lists every target of the workspace members for `--diagnose`, including ones that are never
+/// chosen, such as `lib` and `test` targets.
+fn diagnose_targets(metadata: &[cm::Metadata]) -> String {
+    let mut diagnosis = "".to_owned();
+    for metadata in metadata {
+        diagnosis += &format!("workspace `{}`\n", metadata.workspace_root);
+        let members = metadata
+            .packages
+            .iter()
+            .filter(|cm::Package { id, .. }| metadata.workspace_members.contains(id));
+        for package in members {
+            diagnosis += &format!("  package `{} {}`\n", package.name, package.version);
+            for target in &package.targets {
+                let src_path = target
+                    .src_path
+                    .strip_prefix(&metadata.workspace_root)
+                    .unwrap_or(&target.src_path);
+                diagnosis += &format!(
+                    "    {} `{}` (crate types: {}, src_path: `{}`)\n",
+                    target.kind.join(", "),
+                    target.name,
+                    target.crate_types.join(", "),
+                    src_path,
+                );
+            }
+        }
+    }
+    diagnosis
+}
+
+fn bin_targets(metadata: &[cm::Metadata]) -> impl Iterator<Item = BinTarget<'_>> {
+    executable_targets(metadata, "bin")
+}
+
+fn example_targets(metadata: &[cm::Metadata]) -> impl Iterator<Item = BinTarget<'_>> {
+    executable_targets(metadata, "example")
+}
+
+/// Targets of the workspace members whose kind is `kind` and which are built as executables.
+fn executable_targets<'a>(
+    metadata: &'a [cm::Metadata],
+    kind: &'static str,
+) -> impl Iterator<Item = BinTarget<'a>> {
+    metadata.iter().flat_map(move |metadata| {
+        metadata
+            .packages
+            .iter()
+            .filter(move |cm::Package { id, .. }| metadata.workspace_members.contains(id))
+            .flat_map(move |p| p.targets.iter().map(move |t| (t, p, metadata)))
+            .filter(move |(t, ..)| t.kind == [kind] && t.crate_types == ["bin"])
+    })
+}
+
+/// Returns the target of `zig cc` for `--glibc-version`, such as `x86_64-linux-gnu.2.17`.
+fn zig_glibc_target(target: &str, version: &str) -> anyhow::Result<String> {
+    let env = match target.rsplit('-').next() {
+        Some(env) if env.starts_with("gnu") && target.contains("-linux-") => env,
+        _ => bail!("`--glibc-version` requires a `*-linux-gnu*` target"),
+    };
+    let components = version.split('.').collect::<Vec<_>>();
+    if !(2..=3).contains(&components.len())
+        || components
+            .iter()
+            .any(|c| c.is_empty() || !c.bytes().all(|b| b.is_ascii_digit()))
+    {
+        bail!("invalid glibc version: {:?}", version);
+    }
+    let arch = match target.split('-').next().unwrap_or_default() {
+        "i586" | "i686" => "x86",
+        "riscv64gc" => "riscv64",
+        "riscv32gc" => "riscv32",
+        arch if arch.starts_with("arm") => "arm",
+        arch if arch.starts_with("thumb") => "thumb",
+        arch @ "x86_64"
+        | arch @ "aarch64"
+        | arch @ "aarch64_be"
+        | arch @ "powerpc"
+        | arch @ "powerpc64"
+        | arch @ "powerpc64le"
+        | arch @ "mips"
+        | arch @ "mipsel"
+        | arch @ "mips64"
+        | arch @ "mips64el"
+        | arch @ "s390x"
+        | arch @ "sparc64"
+        | arch @ "loongarch64" => arch,
+        arch => bail!("`--glibc-version` does not support `{}`", arch),
+    };
+    Ok(format!("{}-linux-{}.{}", arch, env, version))
+}
+
+/// Finds `zig` for `--glibc-version`.
+fn find_zig(path_env: Option<&OsStr>, cwd: &Path) -> anyhow::Result<PathBuf> {
+    which::which_in("zig", path_env, cwd)
+        .map_err(|_| anyhow!("`zig` not found (required for `--glibc-version`)"))
+}
+
+/// Content of the wrapper of `zig cc` that is used as the linker and the C compiler.
+fn zig_cc_script(zig: &Path, zig_target: &str) -> String {
+    format!(
+        "#!/bin/sh\nexec {} cc -target {} \"$@\"\n",
+        shell_escape::escape(zig.to_string_lossy()),
+        zig_target,
+    )
+}
+
+/// Environment variables for `cargo build` that make `wrapper` the linker and the C compiler (of the
+/// `cc` crate) for `target`.
+fn zig_cc_envs(target: &str, wrapper: &Path) -> Vec<(String, OsString)> {
+    let target = target.replace(&['-', '.'][..], "_");
+    vec![
+        (
+            format!("CARGO_TARGET_{}_LINKER", target.to_uppercase()),
+            wrapper.into(),
+        ),
+        (format!("CC_{}", target), wrapper.into()),
+    ]
+}
+
+/// Returns the argument for `cargo build --features`. `$CARGO_EXECUTABLE_PAYLOAD_FEATURES` is used
+/// only if `--features` is not given.
+fn features_arg(features: &[String], env_features: Option<&OsStr>) -> Option<String> {
+    let env_features = env_features.map(|f| f.to_string_lossy().into_owned());
+    let features = if features.is_empty() {
+        env_features.as_slice()
+    } else {
+        features
+    };
+    let features = features
+        .iter()
+        .flat_map(|f| f.split(&[',', ' '][..]))
+        .filter(|f| !f.is_empty())
+        .join(",");
+    Some(features).filter(|f| !f.is_empty())
+}
+
+#[allow(clippy::too_many_arguments)]
+fn build(
+    shell: &mut Shell,
+    target_dir: &Utf8Path,
+    manifest_dir: &Utf8Path,
+    bin_name: &str,
+    use_cross: bool,
+    target: &str,
+    extra_rustflags: &[String],
+    panic_abort: bool,
+    rustc_wrapper: Option<&Path>,
+    features: Option<&str>,
+    zig_cc: Option<(&Path, &str)>,
+    strip_exe: Option<&Path>,
+    use_objcopy: bool,
+    objcopy_exe: Option<&Path>,
+    no_strip: bool,
+    no_upx: bool,
+    compression_level: Option<u32>,
+    verify_upx: bool,
+    slim_elf: bool,
+    remove_interp: bool,
+    require_tools: bool,
+    universal: bool,
+    quiet_tools: bool,
+) -> anyhow::Result<Vec<u8>> {
+    // Kept in the target directory so that Cargo does not see a new linker every time.
+    let zig_cc_envs = zig_cc
+        .map(|(zig, zig_target)| -> anyhow::Result<_> {
+            let dir = target_dir.join("executable-payload");
+            std::fs::create_dir_all(&dir)?;
+            let wrapper = dir.join(format!("zig-cc-{}", zig_target));
+            std::fs::write(&wrapper, zig_cc_script(zig, zig_target))
+                .with_context(|| format!("could not write `{}`", wrapper))?;
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::PermissionsExt as _;
+                std::fs::set_permissions(&wrapper, std::fs::Permissions::from_mode(0o755))?;
+            }
+            Ok(zig_cc_envs(target, wrapper.as_ref()))
+        })
+        .transpose()?
+        .unwrap_or_default();
+
+    let build_std = if panic_abort {
+        build_std_with_panic_abort(manifest_dir.as_ref(), |key| env::var(key).ok())?
+    } else {
+        None
+    };
+
+    let cargo_build = |shell: &mut Shell, target| {
+        let program = if use_cross {
+            "cross".into()
+        } else {
+            env::var_os("CARGO").with_context(|| "`$CARGO` is not present")?
+        };
+        let mut args = vec![
+            OsStr::new("build"),
+            OsStr::new("--release"),
+            OsStr::new("--bin"),
+            OsStr::new(bin_name),
+            OsStr::new("--target"),
+            OsStr::new(target),
+        ];
+        if let Some(features) = features {
+            args.extend(vec![OsStr::new("--features"), OsStr::new(features)]);
+        }
+        if let Some(build_std) = &build_std {
+            args.extend(vec![OsStr::new("-Z"), OsStr::new(build_std)]);
+        }
+        let rustflags = if extra_rustflags.is_empty() {
+            None
+        } else {
+            let mut rustflags =
+                base_rustflags(manifest_dir.as_ref(), target, |key| env::var(key).ok())?;
+            rustflags.extend(
+                extra_rustflags
+                    .iter()
+                    .flat_map(|flags| flags.split_whitespace())
+                    .map(ToOwned::to_owned),
+            );
+            Some(rustflags)
+        };
+        run_command(shell, manifest_dir, program, &args, quiet_tools, |cmd| {
+            // `cross` does not pass `$CARGO_ENCODED_RUSTFLAGS` to the container.
+            if let Some(rustflags) = &rustflags {
+                *cmd = if use_cross {
+                    cmd.env("RUSTFLAGS", rustflags.join(" "))
+                } else {
+                    cmd.env("CARGO_ENCODED_RUSTFLAGS", rustflags.join("\x1f"))
+                };
+            }
+            if let Some(rustc_wrapper) = rustc_wrapper {
+                *cmd = cmd.env("RUSTC_WRAPPER", rustc_wrapper);
+            }
+            for (key, value) in &zig_cc_envs {
+                *cmd = cmd.env(key, value);
+            }
+        })?;
+        Ok::<_, anyhow::Error>(artifact_path(target_dir, target, bin_name))
+    };
+
+    let artifact_path = if universal {
+        let thin = UNIVERSAL_TARGETS
+            .iter()
+            .map(|target| cargo_build(shell, target))
+            .collect::<anyhow::Result<Vec<_>>>()?;
+        let fat = artifact_path(target_dir, target, bin_name);
+        lipo(shell, manifest_dir, &thin, &fat, quiet_tools)?;
+        fat
+    } else {
+        cargo_build(shell, target)?
+    };
+
+    let rustc_wrapper = rustc_wrapper
+        .map(Into::into)
+        .or_else(|| env::var_os("RUSTC_WRAPPER"));
+    if let Some(sccache) =
+        rustc_wrapper.filter(|w| Path::new(w).file_stem() == Some("sccache".as_ref()))
+    {
+        // The statistics are only informative.
+        let args = [OsStr::new("--show-stats")];
+        if let Err(err) = run_command(shell, manifest_dir, sccache, &args, quiet_tools, |_| ()) {
+            shell.warn(format!(
+                "could not show the statistics of `sccache`: {}",
+                err
+            ))?;
+        }
+    }
+
+    // Nothing about the temporary directory should leak into the result.
+    let tempdir = tempfile::Builder::new()
+        .prefix("cargo-executable-payload-")
+        .tempdir()?;
+
+    let artifact = process_artifact(
+        shell,
+        tempdir.path(),
+        &artifact_path,
+        manifest_dir,
+        env::var_os("PATH").as_deref(),
+        use_cross,
+        target,
+        strip_exe,
+        use_objcopy,
+        objcopy_exe,
+        no_strip,
+        no_upx,
+        compression_level,
+        verify_upx,
+        slim_elf,
+        remove_interp,
+        require_tools,
+        quiet_tools,
+    )?;
+
+    tempdir.close()?;
+    Ok(artifact)
+}
+
+/// Targets for `--os-matrix` without values.
+static OS_MATRIX_TARGETS: &[&str] = &[
+    "x86_64-unknown-linux-musl",
+    "x86_64-pc-windows-gnu",
+    "x86_64-apple-darwin",
+];
+
+/// Targets combined with `--universal`.
+static UNIVERSAL_TARGETS: &[&str] = &["x86_64-apple-darwin", "aarch64-apple-darwin"];
+
+/// Stands in for `--target` with `--universal` inside this crate. It is not shown to the user.
+static UNIVERSAL_TARGET: &str = "universal2-apple-darwin";
+
+/// Returns `<target-dir>/<target>/release/<bin>[.<ext>]`.
+fn artifact_path(target_dir: &Utf8Path, target: &str, bin_name: &str) -> Utf8PathBuf {
+    let mut artifact_path = target_dir.join(target).join("release").join(bin_name);
+    if let Some(extension) = executable_extension(target) {
+        artifact_path.set_extension(extension);
+    }
+    artifact_path
+}
+
+/// Combines the Mach-O executables into a fat one with `lipo -create`.
+fn lipo(
+    shell: &mut Shell,
+    cwd: &Utf8Path,
+    inputs: &[Utf8PathBuf],
+    output: &Utf8Path,
+    quiet: bool,
+) -> anyhow::Result<()> {
+    let program = which::which_in("lipo", env::var_os("PATH"), cwd)
+        .map_err(|_| anyhow!("`--universal` requires `lipo`, which does not seem to exist"))?;
+    if let Some(parent) = output.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| format!("could not create `{}`", parent))?;
+    }
+    let mut args = vec![
+        OsStr::new("-create"),
+        OsStr::new("-output"),
+        output.as_ref(),
+    ];
+    args.extend(inputs.iter().map(|input| input.as_os_str()));
+    run_command(shell, cwd, program, &args, quiet, |_| ())
+}
+
+/// Processes an executable built elsewhere, for `--from-binary`.
+#[allow(clippy::too_many_arguments)]
+fn process_prebuilt(
+    shell: &mut Shell,
+    path: &Path,
+    cwd: &Utf8Path,
+    target: &str,
+    strip_exe: Option<&Path>,
+    use_objcopy: bool,
+    objcopy_exe: Option<&Path>,
+    no_strip: bool,
+    no_upx: bool,
+    compression_level: Option<u32>,
+    verify_upx: bool,
+    slim_elf: bool,
+    remove_interp: bool,
+    require_tools: bool,
+    quiet_tools: bool,
+) -> anyhow::Result<Vec<u8>> {
+    if !path.is_file() {
+        bail!(
+            "`{}` (specified with `--from-binary`) is not a file",
+            path.display()
+        );
+    }
+    let path = Utf8Path::from_path(path)
+        .with_context(|| format!("the path is not valid UTF-8: {}", path.display()))?;
+
+    let tempdir = tempfile::Builder::new()
+        .prefix("cargo-executable-payload-")
+        .tempdir()?;
+    let artifact = process_artifact(
+        shell,
+        tempdir.path(),
+        path,
+        cwd,
+        env::var_os("PATH").as_deref(),
+        false,
+        target,
+        strip_exe,
+        use_objcopy,
+        objcopy_exe,
+        no_strip,
+        no_upx,
+        compression_level,
+        verify_upx,
+        slim_elf,
+        remove_interp,
+        require_tools,
+        quiet_tools,
+    )?;
+    tempdir.close()?;
+    Ok(artifact)
+}
+
+/// Copies `artifact_path` into `workdir` and strips/compresses the copy there.
+///
+/// `strip`, `objcopy`, and `upx` are searched in `path_env` (`$PATH`).
+#[allow(clippy::too_many_arguments)]
+fn process_artifact(
+    shell: &mut Shell,
+    workdir: &Path,
+    artifact_path: &Utf8Path,
+    manifest_dir: &Utf8Path,
+    path_env: Option<&OsStr>,
+    use_cross: bool,
+    target: &str,
+    strip_exe: Option<&Path>,
+    use_objcopy: bool,
+    objcopy_exe: Option<&Path>,
+    no_strip: bool,
+    no_upx: bool,
+    compression_level: Option<u32>,
+    verify_upx: bool,
+    slim_elf: bool,
+    remove_interp: bool,
+    require_tools: bool,
+    quiet_tools: bool,
+) -> anyhow::Result<Vec<u8>> {
+    let artifact_file_name = artifact_path.file_name().unwrap_or("");
+
+    // `workdir` may be shared with other builds running at the same time, so the copy gets its own
+    // name.
+    let copy_file_name = unique_file_name(artifact_file_name);
+
+    std::fs::copy(artifact_path, workdir.join(&copy_file_name))?;
+
+    let artifact_path = workdir.join(&copy_file_name);
+
+    let (stripper, stripper_exe) = if use_objcopy {
+        (Stripper::Objcopy, objcopy_exe)
+    } else {
+        (Stripper::Strip, strip_exe)
+    };
+
+    if no_strip {
+        // Skip.
+    } else if use_cross {
+        // `strip` on the host may not understand the target architecture.
+        let engine = cross_container_engine();
+        let (program, args) = cross_tool_command(
+            &engine,
+            &cross_image(manifest_dir.as_ref(), target)?,
+            workdir,
+            cross_container_user(&engine, workdir)?.as_deref(),
+            &copy_file_name,
+            stripper,
+            stripper_exe,
+            &[stripper.strip_all_flag()],
+        );
+        strip(
+            shell,
+            manifest_dir,
+            program,
+            &args,
+            &artifact_path,
+            quiet_tools,
+        )?;
+    } else {
+        if let Some(program) = find_tool(stripper, stripper_exe, path_env, manifest_dir)? {
+            let args = stripper.args(&artifact_path);
+            strip(
+                shell,
+                manifest_dir,
+                program,
+                &args,
+                &artifact_path,
+                quiet_tools,
+            )?;
+        } else if require_tools {
+            bail!(
+                "`{}` not found (`--require-tools` is specified)",
+                stripper.name(),
+            );
+        }
+    }
+
+    if slim_elf {
+        let (program, args) = if use_cross {
+            // Same as `strip`.
+            let engine = cross_container_engine();
+            cross_tool_command(
+                &engine,
+                &cross_image(manifest_dir.as_ref(), target)?,
+                workdir,
+                cross_container_user(&engine, workdir)?.as_deref(),
+                &copy_file_name,
+                Stripper::Objcopy,
+                objcopy_exe,
+                &SLIM_ELF_FLAGS,
+            )
+        } else {
+            let program = find_tool(Stripper::Objcopy, objcopy_exe, path_env, manifest_dir)?
+                .with_context(|| "`--slim-elf` requires `objcopy`")?;
+            let args = SLIM_ELF_FLAGS
+                .iter()
+                .map(Into::into)
+                .chain(std::iter::once(artifact_path.clone().into()))
+                .collect();
+            (program.into(), args)
+        };
+        remove_sections(
+            shell,
+            manifest_dir,
+            program,
+            &args,
+            &artifact_path,
+            quiet_tools,
+        )?;
+    }
+
+    if remove_interp {
+        let mut elf = std::fs::read(&artifact_path)?;
+        match self::remove_interp(&mut elf).with_context(|| {
+            format!("could not remove `PT_INTERP` from `{}`", artifact_file_name)
+        })? {
+            InterpRemoval::Removed => {
+                std::fs::write(&artifact_path, elf)?;
+                shell.status(
+                    "Removed",
+                    format!("`PT_INTERP` from `{}`", artifact_file_name),
+                )?;
+            }
+            InterpRemoval::NotFound => {}
+            InterpRemoval::Dynamic => shell.warn(format!(
+                "`{}` needs shared libraries. not removing `PT_INTERP`",
+                artifact_file_name,
+            ))?,
+        }
+    }
+
+    // Unlike `strip`, `upx` on the host can compress executables for any architectures.
+    if !no_upx {
+        if let Ok(program) = which::which_in("upx", path_env, manifest_dir) {
+            let level = Compressor::Upx.level_arg(compression_level)?;
+            let args = [OsStr::new(&level), artifact_path.as_ref()];
+            run_command(shell, manifest_dir, program, &args, quiet_tools, |cmd| {
+                *cmd = cmd.stdout_to_stderr();
+            })?;
+
+            if verify_upx {
+                if !use_cross && target == rustc_host(manifest_dir)? {
+                    verify_launch(shell, &artifact_path)?;
+                } else {
+                    shell.status(
+                        "Skipped",
+                        format!("verifying `upx` output for non-host target `{}`", target),
+                    )?;
+                }
+            }
+        } else if require_tools {
+            bail!("`upx` not found (`--require-tools` is specified)");
+        }
+    }
+
+    let artifact = std::fs::read(&artifact_path)?;
+    std::fs::remove_file(&artifact_path)?;
+    Ok(artifact)
+}
+
+/// Prefixes `file_name` with the process ID and a counter so that no two calls in any running
+/// processes return the same name.
+fn unique_file_name(file_name: &str) -> String {
+    static COUNTER: AtomicUsize = AtomicUsize::new(0);
+    let n = COUNTER.fetch_add(1, atomic::Ordering::Relaxed);
+    format!("{}-{}-{}", std::process::id(), n, file_name)
+}
+
+/// Runs `path --help` and warns if it is killed by a signal, which is what a failed self-extraction
+/// of `upx` looks like. One still running after a few seconds is considered launched.
+fn verify_launch(shell: &mut Shell, path: &Path) -> anyhow::Result<()> {
+    const TIMEOUT: Duration = Duration::from_secs(5);
+
+    let mut child = std::process::Command::new(path)
+        .arg("--help")
+        .stdin(Stdio::null())
+        .stdout(Stdio::null())
+        .stderr(Stdio::null())
+        .spawn()
+        .with_context(|| format!("could not run `{}`", path.display()))?;
+
+    let started = Instant::now();
+    let status = loop {
+        if let Some(status) = child.try_wait()? {
+            break status;
+        }
+        if started.elapsed() > TIMEOUT {
+            child.kill()?;
+            child.wait()?;
+            return Ok(());
+        }
+        std::thread::sleep(Duration::from_millis(10));
+    };
+
+    if status.code().is_none() {
+        shell.warn(format!(
+            "`{}` compressed by `upx` did not launch ({})",
+            path.display(),
+            status,
+        ))?;
+    }
+    Ok(())
+}
+
+/// Resolves the tools as `build` and `compress` would, for `--check-tools`.
+#[allow(clippy::too_many_arguments)]
+fn resolve_tools(
+    cwd: &Path,
+    use_cross: bool,
+    target: &str,
+    strip_exe: Option<&Path>,
+    use_objcopy: bool,
+    objcopy_exe: Option<&Path>,
+    no_strip: bool,
+    no_upx: bool,
+    slim_elf: bool,
+    universal: bool,
+    compression: &[Compression],
+    xz_exe: Option<&Path>,
+) -> Vec<(&'static str, String)> {
+    let resolve = |program: &OsStr| match which::which_in(program, env::var_os("PATH"), cwd) {
+        Ok(path) => path.display().to_string(),
+        Err(_) => format!("`{}` (not found)", program.to_string_lossy()),
+    };
+    let skipped = || "(skipped)".to_owned();
+
+    let mut tools = vec![];
+
+    if use_cross {
+        tools.push(("cross", resolve("cross".as_ref())));
+    } else {
+        let cargo = match env::var_os("CARGO") {
+            Some(cargo) => resolve(&cargo),
+            None => "`$CARGO` is not present".to_owned(),
+        };
+        tools.push(("cargo", cargo));
+    }
+
+    let (stripper, stripper_exe) = if use_objcopy {
+        (Stripper::Objcopy, objcopy_exe)
+    } else {
+        (Stripper::Strip, strip_exe)
+    };
+    let in_cross_image = |tool: Stripper, exe: Option<&Path>| {
+        let program = match exe {
+            Some(exe) => exe.display().to_string(),
+            None => format!("${{CROSS_TOOLCHAIN_PREFIX}}{}", tool.name()),
+        };
+        match cross_image(cwd, target) {
+            Ok(image) => format!("{} (in `{}`)", program, image),
+            Err(err) => format!("{} ({})", program, err),
+        }
+    };
+    let strip = if no_strip {
+        skipped()
+    } else if use_cross {
+        in_cross_image(stripper, stripper_exe)
+    } else {
+        resolve(stripper_exe.map_or(stripper.name().as_ref(), Path::as_os_str))
+    };
+    tools.push((stripper.name(), strip));
+
+    if slim_elf {
+        let objcopy = if use_cross {
+            in_cross_image(Stripper::Objcopy, objcopy_exe)
+        } else {
+            resolve(objcopy_exe.map_or("objcopy".as_ref(), Path::as_os_str))
+        };
+        tools.push(("objcopy (--slim-elf)", objcopy));
+    }
+
+    tools.push((
+        "upx",
+        if no_upx {
+            skipped()
+        } else {
+            resolve("upx".as_ref())
+        },
+    ));
+
+    if compression.contains(&Compression::Gzip) {
+        tools.push(("gzip", resolve("gzip".as_ref())));
+    }
+    if compression.contains(&Compression::Lzma) {
+        tools.push(("xz", resolve(xz_exe.map_or("xz".as_ref(), Path::as_os_str))));
+    }
+
+    if universal {
+        tools.push(("lipo", resolve("lipo".as_ref())));
+    }
+
+    tools
+}
+
+fn compress(
+    shell: &mut Shell,
+    cwd: &Utf8Path,
+    compression: Compression,
+    level: Option<u32>,
+    xz_exe: Option<&Path>,
+    artifact: &[u8],
+    quiet: bool,
+) -> anyhow::Result<Vec<u8>> {
+    let tempdir = tempfile::Builder::new()
+        .prefix("cargo-executable-payload-")
+        .tempdir()?;
+    let path = tempdir.path().join("payload");
+    std::fs::write(&path, artifact)?;
+
+    let compressed = match compression {
+        Compression::Gzip => {
+            let level = Compressor::Gzip.level_arg(level)?;
+            let args = [OsStr::new(&level), OsStr::new("-n"), path.as_ref()];
+            run_command(shell, cwd, "gzip", &args, quiet, |_| ())?;
+            std::fs::read(path.with_extension("gz"))?
+        }
+        Compression::Lzma => {
+            let program = xz_exe.map_or("xz".as_ref(), Path::as_os_str);
+            let level = Compressor::Xz.level_arg(level)?;
+            let args = [
+                OsStr::new(&level),
+                OsStr::new("--format=lzma"),
+                path.as_ref(),
+            ];
+            run_command(shell, cwd, program, &args, quiet, |_| ())?;
+            std::fs::read(path.with_extension("lzma"))?
+        }
+    };
+
+    tempdir.close()?;
+    Ok(compressed)
+}
+
+fn cross_container_engine() -> OsString {
+    env::var_os("CROSS_CONTAINER_ENGINE").unwrap_or_else(|| {
+        if which::which("docker").is_err() && which::which("podman").is_ok() {
+            "podman".into()
+        } else {
+            "docker".into()
+        }
+    })
+}
+
+/// Returns the image `cross` would use for `target`.
+///
+/// As `cross` does, `$CROSS_TARGET_<TARGET>_IMAGE` takes precedence over `target.<TARGET>.image` in
+/// `Cross.toml`, which is `$CROSS_CONFIG` or the nearest one from `manifest_dir`.
+fn cross_image(manifest_dir: &Path, target: &str) -> anyhow::Result<String> {
+    let key = format!(
+        "CROSS_TARGET_{}_IMAGE",
+        target.to_uppercase().replace(&['-', '.'][..], "_"),
+    );
+    if let Ok(image) = env::var(key) {
+        return Ok(image);
+    }
+    let config = env::var_os("CROSS_CONFIG").map(PathBuf::from).or_else(|| {
+        manifest_dir
+            .ancestors()
+            .map(|dir| dir.join("Cross.toml"))
+            .find(|path| path.exists())
+    });
+    let config = config
+        .map(|path| {
+            std::fs::read_to_string(&path)
+                .with_context(|| format!("could not read `{}`", path.display()))
+                .map(|toml| (path, toml))
+        })
+        .transpose()?;
+    let config = config.as_ref().map(|(path, toml)| (&**path, &**toml));
+    cross_image_from_config(target, config)
+}
+
+/// `config` is the path and the content of `Cross.toml`.
+fn cross_image_from_config(target: &str, config: Option<(&Path, &str)>) -> anyhow::Result<String> {
+    if let Some((path, toml)) = config {
+        let config = toml::from_str::<toml::Value>(toml)
+            .with_context(|| format!("could not parse `{}`", path.display()))?;
+        // `image` is either the name or a table with the name.
+        let image = config
+            .get("target")
+            .and_then(|targets| targets.get(target))
+            .and_then(|target| target.get("image"));
+        if let Some(image) = image {
+            return image
+                .as_str()
+                .or_else(|| image.get("name").and_then(toml::Value::as_str))
+                .map(ToOwned::to_owned)
+                .with_context(|| {
+                    format!(
+                        "`target.{}.image` in `{}` is not an image name",
+                        target,
+                        path.display(),
+                    )
+                });
+        }
+    }
+    Ok(format!("ghcr.io/cross-rs/{}:main", target))
+}
+
+/// Returns `<UID>:<GID>` of the owner of `dir` for `--user`, so that the files the container writes
+/// in `dir` are not owned by root. As `cross` does, it is not needed for rootless engines, which
+/// Podman is assumed to be unless `$CROSS_ROOTLESS_CONTAINER_ENGINE` says otherwise.
+fn cross_container_user(engine: &OsStr, dir: &Path) -> io::Result<Option<String>> {
+    let rootless = match env::var("CROSS_ROOTLESS_CONTAINER_ENGINE").as_deref() {
+        Ok("1") | Ok("true") => true,
+        Ok("0") | Ok("false") => false,
+        _ => Path::new(engine).file_stem() != Some("docker".as_ref()),
+    };
+    if rootless {
+        return Ok(None);
+    }
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt as _;
+        let metadata = std::fs::metadata(dir)?;
+        Ok(Some(format!("{}:{}", metadata.uid(), metadata.gid())))
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = dir;
+        Ok(None)
+    }
+}
+
+/// Builds a command that runs `strip` or `objcopy` for the target with `flags` in the `cross`
+/// image, as `user` if any.
+///
+/// The images set `$CROSS_TOOLCHAIN_PREFIX` for targets that need a prefixed `strip`.
+#[allow(clippy::too_many_arguments)]
+fn cross_tool_command(
+    engine: &OsStr,
+    image: &str,
+    dir: &Path,
+    user: Option<&str>,
+    file_name: &str,
+    tool: Stripper,
+    exe: Option<&Path>,
+    flags: &[&str],
+) -> (OsString, Vec<OsString>) {
+    let program = match exe {
+        Some(exe) => shell_escape::escape(exe.to_string_lossy()).into_owned(),
+        None => format!("\"${{CROSS_TOOLCHAIN_PREFIX}}{}\"", tool.name()),
+    };
+    let script = format!("exec {} {} \"$0\"", program, flags.join(" "));
+    let mut volume = dir.as_os_str().to_owned();
+    volume.push(":/payload");
+    let mut args = vec!["run".into(), "--rm".into()];
+    if let Some(user) = user {
+        args.extend(vec!["--user".into(), user.into()]);
+    }
+    args.extend(vec![
+        "-v".into(),
+        volume,
+        image.into(),
+        "sh".into(),
+        "-c".into(),
+        script.into(),
+        format!("/payload/{}", file_name).into(),
+    ]);
+    (engine.to_owned(), args)
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+enum Stripper {
+    Strip,
+    Objcopy,
+}
+
+impl Stripper {
+    fn name(self) -> &'static str {
+        match self {
+            Self::Strip => "strip",
+            Self::Objcopy => "objcopy",
+        }
+    }
+
+    fn option(self) -> &'static str {
+        match self {
+            Self::Strip => "--strip-exe",
+            Self::Objcopy => "--objcopy-exe",
+        }
+    }
+
+    fn strip_all_flag(self) -> &'static str {
+        match self {
+            Self::Strip => "-s",
+            Self::Objcopy => "--strip-all",
+        }
+    }
+
+    fn args(self, artifact_path: &Path) -> [&OsStr; 2] {
+        [OsStr::new(self.strip_all_flag()), artifact_path.as_ref()]
+    }
+}
+
+/// Finds `strip` or `objcopy`. A missing one is only an error if it is explicitly specified.
+fn find_tool(
+    tool: Stripper,
+    exe: Option<&Path>,
+    path_env: Option<&OsStr>,
+    cwd: &Utf8Path,
+) -> anyhow::Result<Option<PathBuf>> {
+    match exe {
+        Some(exe) => which::which_in(exe, path_env, cwd).map(Some).map_err(|_| {
+            anyhow!(
+                "`{}` (specified with `{}`) does not exist or is not executable",
+                exe.display(),
+                tool.option(),
+            )
+        }),
+        None => Ok(which::which_in(tool.name(), path_env, cwd).ok()),
+    }
+}
+
+fn strip(
+    shell: &mut Shell,
+    cwd: &Utf8Path,
+    program: impl AsRef<OsStr>,
+    args: &[impl AsRef<OsStr>],
+    artifact_path: &Path,
+    quiet: bool,
+) -> anyhow::Result<()> {
+    let program = program.as_ref();
+    let unstripped = std::fs::read(artifact_path)?;
+    run_command(shell, cwd, program, args, quiet, |_| ())?;
+
+    // Some versions of `strip` break static-PIE executables.
+    if is_executable_elf(&unstripped) && !is_executable_elf(&std::fs::read(artifact_path)?) {
+        shell.warn(format!(
+            "`{}` produced a broken executable. using the unstripped one",
+            program.to_string_lossy(),
+        ))?;
+        std::fs::write(artifact_path, unstripped)?;
+    }
+    Ok(())
+}
+
+/// Flags of `objcopy` for `--slim-elf`.
+static SLIM_ELF_FLAGS: [&str; 4] = [
+    "--remove-section",
+    ".comment",
+    "--remove-section",
+    ".note.gnu.build-id",
+];
+
+/// Runs `objcopy` with `args`, which modifies `artifact_path` with [`SLIM_ELF_FLAGS`].
+fn remove_sections(
+    shell: &mut Shell,
+    cwd: &Utf8Path,
+    objcopy: impl AsRef<OsStr>,
+    args: &[impl AsRef<OsStr>],
+    artifact_path: &Path,
+    quiet: bool,
+) -> anyhow::Result<()> {
+    let original_len = std::fs::metadata(artifact_path)?.len();
+    run_command(shell, cwd, objcopy, args, quiet, |_| ())?;
+    let len = std::fs::metadata(artifact_path)?.len();
+
+    shell.status(
+        "Slimmed",
+        format!(
+            "`{}` ({} bytes saved)",
+            artifact_path
+                .file_name()
+                .unwrap_or_default()
+                .to_string_lossy(),
+            original_len.saturating_sub(len),
+        ),
+    )?;
+    Ok(())
+}
+
+#[derive(Debug, PartialEq)]
+enum Check {
+    Passed(String),
+    Failed(String),
+    Skipped(String),
+}
+
+/// Runs the checks for `--preflight`. `output_content` is the output before it is written.
+#[allow(clippy::too_many_arguments)]
+fn preflight_checks(
+    target: &str,
+    require_musl: bool,
+    max_size: Option<usize>,
+    emit: Emit,
+    artifact: &[u8],
+    output_content: &[u8],
+    payload_file_content: Option<&str>,
+    xz_exe: Option<&Path>,
+) -> Vec<(&'static str, Check)> {
+    let size = match max_size {
+        Some(max_size) if output_content.len() > max_size => Check::Failed(format!(
+            "{} bytes (`--max-size` is {} bytes)",
+            output_content.len(),
+            max_size,
+        )),
+        Some(max_size) => Check::Passed(format!(
+            "{} bytes (`--max-size` is {} bytes)",
+            output_content.len(),
+            max_size,
+        )),
+        None => Check::Skipped("`--max-size` is not specified".to_owned()),
+    };
+
+    let musl = match check_musl(target) {
+        Ok(()) => Check::Passed(format!("`{}`", target)),
+        Err(_) if require_musl => Check::Failed(format!("`{}` is not a musl target", target)),
+        Err(_) => Check::Skipped("`--require-musl` is not specified".to_owned()),
+    };
+
+    let static_linking = match has_interp(artifact) {
+        Ok((Some(_), true)) => Check::Failed("the executable needs shared libraries".to_owned()),
+        Ok(_) => Check::Passed("the executable does not need shared libraries".to_owned()),
+        Err(err) => Check::Skipped(err.to_string()),
+    };
+
+    // For `--emit split`, the content of `payload.b64` is put in place of `include_str!`.
+    let inlined = payload_file_content.and_then(|payload| {
+        let output_content = std::str::from_utf8(output_content).ok()?;
+        let include = format!("include_str!({:?})", PAYLOAD_FILE_NAME);
+        Some(output_content.replacen(&include, &format!("{:?}", payload), 1))
+    });
+    let decoding = match emit {
+        Emit::Rust | Emit::Split => {
+            let output_content = inlined.as_ref().map_or(output_content, |s| s.as_ref());
+            match decode_generated(output_content, xz_exe) {
+                Ok(decoded) if decoded == artifact => {
+                    Check::Passed("the payload decodes to the executable".to_owned())
+                }
+                Ok(_) => Check::Failed("the payload decodes to a different file".to_owned()),
+                Err(err) => Check::Failed(format!("{:#}", err)),
+            }
+        }
+        Emit::Binary => Check::Skipped("`--emit binary`".to_owned()),
+        Emit::RustFragment => Check::Skipped("`--emit rust-fragment`".to_owned()),
+    };
+
+    vec![
+        ("size", size),
+        ("musl", musl),
+        ("static linking", static_linking),
+        ("decoding", decoding),
+    ]
+}
+
+/// Decodes the payload of the generated code on the host, with `gzip -d` for `--compression gzip`
+/// and `xz -d` (`xz_exe` if given) for `--compression lzma`.
+fn decode_generated(output_content: &[u8], xz_exe: Option<&Path>) -> anyhow::Result<Vec<u8>> {
+    let generated = parse_generated(std::str::from_utf8(output_content)?)?;
+    let mut decoded = generated.payload;
+    for compression in generated.compression.iter().rev() {
+        decoded = match compression {
+            Compression::Gzip => {
+                duct::cmd!("gzip", "-d", "-c")
+                    .stdin_bytes(decoded)
+                    .stdout_capture()
+                    .stderr_null()
+                    .run()
+                    .with_context(|| "could not decompress the payload with `gzip`")?
+                    .stdout
+            }
+            Compression::Lzma => {
+                let program = xz_exe.map_or("xz".as_ref(), Path::as_os_str);
+                duct::cmd!(program, "-d", "--format=lzma", "-c")
+                    .stdin_bytes(decoded)
+                    .stdout_capture()
+                    .stderr_null()
+                    .run()
+                    .with_context(|| "could not decompress the payload with `xz`")?
+                    .stdout
+            }
+        };
+    }
+    Ok(decoded)
+}
+
+fn report_preflight(shell: &mut Shell, checks: &[(&str, Check)]) -> anyhow::Result<()> {
+    for (name, check) in checks {
+        match check {
+            Check::Passed(msg) => shell.status("Passed", format!("{}: {}", name, msg))?,
+            Check::Failed(msg) => {
+                shell.print("Failed", format!("{}: {}", name, msg), Color::Red, true)?
+            }
+            Check::Skipped(msg) => shell.status("Skipped", format!("{}: {}", name, msg))?,
+        }
+    }
+    let failed = checks
+        .iter()
+        .filter(|(_, check)| matches!(check, Check::Failed(_)))
+        .count();
+    if failed > 0 {
+        bail!("{} of {} preflight checks failed", failed, checks.len());
+    }
+    Ok(())
+}
+
+#[derive(Debug, PartialEq)]
+enum InterpRemoval {
+    Removed,
+    NotFound,
+    /// Not removed because the executable has `DT_NEEDED`.
+    Dynamic,
+}
+
+/// Replaces `PT_INTERP` with `PT_NULL` in place.
+fn remove_interp(elf: &mut [u8]) -> anyhow::Result<InterpRemoval> {
+    match has_interp(elf)? {
+        (None, _) => Ok(InterpRemoval::NotFound),
+        (Some(_), true) => Ok(InterpRemoval::Dynamic),
+        (Some(phdr), false) => {
+            elf[phdr..phdr + 4].copy_from_slice(&[0; 4]);
+            Ok(InterpRemoval::Removed)
+        }
+    }
+}
+
+/// Looks for `PT_INTERP` without modifying `elf`.
+///
+/// Returns the offset of its program header if any, and whether the executable has `DT_NEEDED`.
+fn has_interp(elf: &[u8]) -> anyhow::Result<(Option<usize>, bool)> {
+    const PT_DYNAMIC: u64 = 2;
+    const PT_INTERP: u64 = 3;
+    const DT_NEEDED: u64 = 1;
+
+    if !is_executable_elf(elf) {
+        bail!("not an ELF executable");
+    }
+    let is_64 = elf[4] == 2;
+    let little_endian = elf[5] == 1;
+    let word = if is_64 { 8 } else { 4 };
+    let read = |elf: &[u8], offset: u64, len: usize| {
+        let offset = usize::try_from(offset).ok()?;
+        let bytes = elf.get(offset..offset.checked_add(len)?)?;
+        let fold = |acc, &b| acc << 8 | u64::from(b);
+        Some(if little_endian {
+            bytes.iter().rev().fold(0, fold)
+        } else {
+            bytes.iter().fold(0, fold)
+        })
+    };
+    let malformed = || anyhow!("malformed program headers");
+
+    let (e_phoff, e_phentsize, e_phnum) = if is_64 {
+        (read(elf, 0x20, 8), read(elf, 0x36, 2), read(elf, 0x38, 2))
+    } else {
+        (read(elf, 0x1c, 4), read(elf, 0x2a, 2), read(elf, 0x2c, 2))
+    };
+    let (e_phoff, e_phentsize, e_phnum) = (
+        e_phoff.ok_or_else(malformed)?,
+        e_phentsize.ok_or_else(malformed)?,
+        e_phnum.ok_or_else(malformed)?,
+    );
+
+    let mut interp = None;
+    let mut needs_shared_libraries = false;
+    for i in 0..e_phnum {
+        let phdr = e_phoff + i * e_phentsize;
+        match read(elf, phdr, 4).ok_or_else(malformed)? {
+            PT_INTERP => interp = Some(phdr),
+            PT_DYNAMIC => {
+                let (p_offset, p_filesz) = if is_64 {
+                    (read(elf, phdr + 0x08, 8), read(elf, phdr + 0x20, 8))
+                } else {
+                    (read(elf, phdr + 0x04, 4), read(elf, phdr + 0x10, 4))
+                };
+                let (p_offset, p_filesz) = (
+                    p_offset.ok_or_else(malformed)?,
+                    p_filesz.ok_or_else(malformed)?,
+                );
+                needs_shared_libraries |= (0..p_filesz / (2 * word as u64))
+                    .map(|j| read(elf, p_offset + j * 2 * word as u64, word))
+                    .take_while(|d_tag| !matches!(d_tag, None | Some(0)))
+                    .any(|d_tag| d_tag == Some(DT_NEEDED));
+            }
+            _ => {}
+        }
+    }
+
+    let interp = interp.map(usize::try_from).transpose()?;
+    Ok((interp, needs_shared_libraries))
+}
+
+fn is_executable_elf(bytes: &[u8]) -> bool {
+    const ET_EXEC: u64 = 2;
+    const ET_DYN: u64 = 3;
+
+    if bytes.len() < 0x40 || !bytes.starts_with(b"\x7fELF") {
+        return false;
+    }
+    let little_endian = match bytes[5] {
+        1 => true,
+        2 => false,
+        _ => return false,
+    };
+    let read = |offset: usize, len: usize| {
+        let bytes = &bytes[offset..offset + len];
+        let fold = |acc, &b| acc << 8 | u64::from(b);
+        if little_endian {
+            bytes.iter().rev().fold(0, fold)
+        } else {
+            bytes.iter().fold(0, fold)
+        }
+    };
+    let e_type = read(0x10, 2);
+    let e_entry = match bytes[4] {
+        1 => read(0x18, 4),
+        2 => read(0x18, 8),
+        _ => return false,
+    };
+    matches!(e_type, ET_EXEC | ET_DYN) && e_entry != 0
+}
+
+fn program_not_found(program: &OsStr) -> anyhow::Error {
+    if program == "cross" {
+        anyhow!(
+            "`cross` does not seem to exist. install it with `cargo install cross` \
+             (https://github.com/cross-rs/cross)",
+        )
+    } else {
+        anyhow!("`{}` does not seem to exist", program.to_string_lossy())
+    }
+}
+
+fn run_command(
+    shell: &mut Shell,
+    cwd: &Utf8Path,
+    program: impl AsRef<OsStr>,
+    args: &[impl AsRef<OsStr>],
+    quiet: bool,
+    before_spawn: impl FnOnce(&mut duct::Expression),
+) -> anyhow::Result<()> {
+    let program = program.as_ref();
+    let program = which::which_in(program, env::var_os("PATH"), cwd)
+        .map_err(|_| program_not_found(program))?;
+    let args = args.iter().map(AsRef::as_ref).collect::<Vec<_>>();
+
+    let format = format!(
+        "`{}{}`",
+        shell_escape::escape(program.to_string_lossy()),
+        args.iter().format_with("", |arg, f| f(&format_args!(
+            " {}",
+            shell_escape::escape(arg.to_string_lossy()),
+        ))),
+    );
+
+    shell.status("Running", &format)?;
+    let mut cmd = duct::cmd(program, args).dir(cwd);
+    before_spawn(&mut cmd);
+    if quiet {
+        // Keep the output to show it if the command fails.
+        let output = cmd
+            .stdout_to_stderr()
+            .stderr_capture()
+            .unchecked()
+            .run()
+            .with_context(|| format!("could not execute {}", format))?;
+        if !output.status.success() {
+            shell.err().write_all(&output.stderr)?;
+            bail!("{} didn't exit successfully: {}", format, output.status);
+        }
+    } else if let Stderr::Buffer(_) = shell.stderr {
+        let output = cmd
+            .stdout_to_stderr()
+            .stderr_capture()
+            .unchecked()
+            .run()
+            .with_context(|| format!("could not execute {}", format))?;
+        shell.err().write_all(&output.stderr)?;
+        if !output.status.success() {
+            bail!("{} didn't exit successfully: {}", format, output.status);
+        }
+    } else {
+        // Our stdout may be the output, e.g. `--emit binary -o -` piped to another program.
+        cmd.stdout_to_stderr()
+            .run()
+            .with_context(|| format!("{} didn't exit successfully", format))?;
+    }
+    Ok(())
+}
+
+struct Provenance {
+    commit: Option<String>,
+    built_at: SystemTime,
+}
+
+impl fmt::Display for Provenance {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let secs = self
+            .built_at
+            .duration_since(SystemTime::UNIX_EPOCH)
+            .map_or(0, |d| d.as_secs());
+        let (days, secs) = (secs / 86400, secs % 86400);
+        let (year, month, day) = civil_from_days(days);
+
+        writeln!(f, "//!")?;
+        if let Some(commit) = &self.commit {
+            writeln!(f, "//! - Commit: `{}`", commit)?;
+        } else {
+            writeln!(f, "//! - Commit: (not a Git repository)")?;
+        }
+        writeln!(
+            f,
+            "//! - Built at: {:04}-{:02}-{:02}T{:02}:{:02}:{:02}Z",
+            year,
+            month,
+            day,
+            secs / 3600,
+            secs / 60 % 60,
+            secs % 60,
+        )
+    }
+}
+
+/// <http://howardhinnant.github.io/date_algorithms.html#civil_from_days>
+fn civil_from_days(days: u64) -> (u64, u64, u64) {
+    let z = days + 719_468;
+    let era = z / 146_097;
+    let doe = z % 146_097;
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + u64::from(month <= 2);
+    (year, month, day)
+}
+
+fn git_head(dir: &Path) -> Option<String> {
+    let output = duct::cmd!("git", "rev-parse", "HEAD")
+        .dir(dir)
+        .stdout_capture()
+        .stderr_null()
+        .unchecked()
+        .run()
+        .ok()?;
+    if !output.status.success() {
+        return None;
+    }
+    Some(String::from_utf8(output.stdout).ok()?.trim_end().to_owned())
+}
+
+fn note_if_slow(shell: &mut Shell, started: Instant, threshold: Duration) -> io::Result<()> {
+    let elapsed = started.elapsed();
+    if elapsed <= threshold {
+        return Ok(());
+    }
+    shell.status(
+        "Note",
+        format!(
+            "the build took {:.1}s. `$CARGO_BUILD_JOBS`, `RUSTC_WRAPPER=sccache`, or \
+             `CARGO_NET_OFFLINE=true` may speed it up",
+            elapsed.as_secs_f64(),
+        ),
+    )
+}
+
+fn check_stable(first: &[u8], second: &[u8]) -> anyhow::Result<()> {
+    if first == second {
+        return Ok(());
+    }
+    let offset = first
+        .iter()
+        .zip(second)
+        .position(|(a, b)| a != b)
+        .unwrap_or_else(|| first.len().min(second.len()));
+    bail!(
+        "the two builds differ (at byte {}, {} bytes vs {} bytes)\n\
+         note: likely culprits are `upx`, timestamps or paths embedded by build scripts, and \
+         randomness in procedural macros",
+        offset,
+        first.len(),
+        second.len(),
+    );
+}
+
+/// Keys of `--template` that are not from `--template-var`.
+static TEMPLATE_BUILTIN_KEYS: &[&str] = &["payload", "source", "path"];
+
+fn is_template_key(key: &str) -> bool {
+    !key.is_empty()
+        && key
+            .chars()
+            .all(|c| c.is_ascii_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Renders `--template`, returning the result and the keys that appeared.
+///
+/// `{{` and `}}` are `{` and `}`, and `{KEY}` is the value in `vars`. `{KEY}` without a value is
+/// left as is unless `strict`. Other braces, such as the ones of blocks, are left as is.
+fn render_template<'a>(
+    template: &str,
+    vars: &[(&'a str, &str)],
+    strict: bool,
+) -> anyhow::Result<(String, Vec<&'a str>)> {
+    let (mut rendered, mut used) = (String::with_capacity(template.len()), vec![]);
+    let mut rest = template;
+    while let Some(i) = rest.find(&['{', '}'][..]) {
+        rendered += &rest[..i];
+        rest = &rest[i..];
+        if rest.starts_with("{{") || rest.starts_with("}}") {
+            rendered += &rest[..1];
+            rest = &rest[2..];
+            continue;
+        }
+        let key = rest
+            .strip_prefix('{')
+            .and_then(|s| s.split_once('}'))
+            .map(|(key, _)| key)
+            .filter(|key| is_template_key(key));
+        if let Some(key) = key {
+            if let Some(&(key, value)) = vars.iter().find(|&&(k, _)| k == key) {
+                rendered += value;
+                if !used.contains(&key) {
+                    used.push(key);
+                }
+            } else if strict {
+                bail!("`{{{}}}` in the template has no value", key);
+            } else {
+                rendered += &rest[..key.len() + 2];
+            }
+            rest = &rest[key.len() + 2..];
+        } else {
+            rendered += &rest[..1];
+            rest = &rest[1..];
+        }
+    }
+    rendered += rest;
+    Ok((rendered, used))
+}
+
+/// Content of `original_source_code!` for `--compress-source-comment`. `base64` can be decoded in
+/// the same way as `PAYLOAD`.
+fn compressed_source_comment(compression: &[Compression], len: usize, compressed: &[u8]) -> String {
+    format!(
+        "compression = [{}],\nlen = {},\nbase64 = \"{}\",\n",
+        compression
+            .iter()
+            .format_with(", ", |c, f| f(&format_args!("{:?}", c.as_str()))),
+        len,
+        base64::encode(compressed),
+    )
+}
+
+fn write_tempfile(content: &[u8], suffix: &str) -> anyhow::Result<PathBuf> {
+    let mut file = tempfile::Builder::new()
+        .prefix("cargo-executable-payload-")
+        .suffix(suffix)
+        .tempfile()?;
+    file.write_all(content)?;
+    let (_, path) = file.keep()?;
+    Ok(path)
+}
+
+/// Writes `content` to `path` through a temporary file in the same directory, so that readers never
+/// see a partial file and concurrent writers do not interleave (the last one wins).
+fn write_output(path: &Path, content: &[u8], mkdir: bool) -> anyhow::Result<()> {
+    let path = &resolve_symlinks(path);
+    create_parent(path, mkdir)?;
+
+    // Such as `/dev/null` and `/dev/stdout`, which cannot be replaced.
+    if matches!(std::fs::metadata(path), Ok(m) if !m.is_file()) {
+        return std::fs::OpenOptions::new()
+            .write(true)
+            .open(path)
+            .and_then(|mut file| file.write_all(content))
+            .with_context(|| format!("could not write `{}`", path.display()));
+    }
+
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    let temp_path = path.with_file_name(format!(".{}.tmp", unique_file_name(&file_name)));
+    let result = (|| {
+        let mut file = std::fs::OpenOptions::new()
+            .write(true)
+            .create_new(true)
+            .open(&temp_path)?;
+        if let Ok(metadata) = std::fs::metadata(path) {
+            file.set_permissions(metadata.permissions())?;
+        }
+        file.write_all(content)?;
+        file.sync_all()?;
+        drop(file);
+        std::fs::rename(&temp_path, path)
+    })();
+    if result.is_err() {
+        let _ = std::fs::remove_file(&temp_path);
+    }
+    result.with_context(|| format!("could not write `{}`", path.display()))
+}
+
+/// Creates the parent directory of `path` for `--mkdir`, or checks that it exists.
+fn create_parent(path: &Path, mkdir: bool) -> anyhow::Result<()> {
+    if let Some(parent) = path.parent().filter(|p| !p.as_os_str().is_empty()) {
+        if mkdir {
+            std::fs::create_dir_all(parent)
+                .with_context(|| format!("could not create `{}`", parent.display()))?;
+        } else if !parent.is_dir() {
+            bail!(
+                "`{}` does not exist (use `--mkdir` to create it)",
+                parent.display(),
+            );
+        }
+    }
+    Ok(())
+}
+
+/// Follows `path` while it is a symbolic link, so that the target is replaced instead of the link.
+fn resolve_symlinks(path: &Path) -> PathBuf {
+    let mut path = path.to_owned();
+    // As `MAXSYMLINKS` of Linux.
+    for _ in 0..40 {
+        match std::fs::read_link(&path) {
+            Ok(target) => path = path.parent().unwrap_or_else(|| "".as_ref()).join(target),
+            Err(_) => break,
+        }
+    }
+    path
+}
+
+/// Name of the lock file that [`lock_output_dir`] creates.
+static LOCK_FILE_NAME: &str = ".cargo-executable-payload.lock";
+
+/// Takes an exclusive advisory lock on `<dir of path>/.cargo-executable-payload.lock`, which is held
+/// until the returned file is dropped. Runs writing to the same directory, such as the output, the
+/// sidecar hash, and `payloads.json`, are serialized with it. Nothing is locked if `path` is not a
+/// regular file, such as `/dev/null`.
+fn lock_output_dir(path: &Path) -> anyhow::Result<Option<std::fs::File>> {
+    let path = resolve_symlinks(path);
+    if matches!(std::fs::metadata(&path), Ok(m) if !m.is_file()) {
+        return Ok(None);
+    }
+    let lock_path = path.with_file_name(LOCK_FILE_NAME);
+    let file = std::fs::OpenOptions::new()
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(&lock_path)
+        .with_context(|| format!("could not open `{}`", lock_path.display()))?;
+    fs2::FileExt::lock_exclusive(&file)
+        .with_context(|| format!("could not lock `{}`", lock_path.display()))?;
+    Ok(Some(file))
+}
+
+/// Writes `<path>.sha256` for `content` of `path`, so that `sha256sum -c` can check it.
+fn write_sidecar_hash(path: &Path, content: &[u8]) -> anyhow::Result<PathBuf> {
+    let mut sidecar = path.as_os_str().to_owned();
+    sidecar.push(".sha256");
+    let sidecar = PathBuf::from(sidecar);
+    let hash = sha256(content)
+        .iter()
+        .map(|b| format!("{:02x}", b))
+        .collect::<String>();
+    let file_name = path.file_name().unwrap_or_default().to_string_lossy();
+    std::fs::write(&sidecar, format!("{}  {}\n", hash, file_name))
+        .with_context(|| format!("could not write `{}`", sidecar.display()))?;
+    Ok(sidecar)
+}
+
+fn print_decoded_size(mut wtr: impl Write, artifact: &[u8]) -> io::Result<()> {
+    writeln!(wtr, "{}", artifact.len())
+}
+
+/// Returns a `.gitignore` entry for `path` anchored at `cwd`, or `None` if it is outside `cwd`.
+fn gitignore_entry(path: &Path, cwd: &Path) -> Option<String> {
+    let path = if path.is_absolute() {
+        path.strip_prefix(cwd).ok()?
+    } else {
+        path
+    };
+    let mut entry = String::new();
+    for component in path.components() {
+        match component {
+            std::path::Component::CurDir => {}
+            std::path::Component::Normal(component) => {
+                entry.push('/');
+                for c in component.to_str()?.chars() {
+                    if matches!(c, '\\' | '*' | '?' | '[') {
+                        entry.push('\\');
+                    }
+                    entry.push(c);
+                }
+            }
+            _ => return None,
+        }
+    }
+    Some(entry).filter(|entry| !entry.is_empty())
+}
+
+fn executable_extension(target: &str) -> Option<&'static str> {
+    if target.contains("windows") {
+        Some("exe")
+    } else if target.starts_with("wasm") {
+        Some("wasm")
+    } else {
+        None
+    }
+}
+
+/// Returns the values of `std::env::consts::{ARCH, OS}` for `target`, with two architectures for
+/// `--universal`.
+fn runtime_arch_os(target: &str) -> Option<(&'static [&'static str], &'static str)> {
+    if target == UNIVERSAL_TARGET {
+        return Some((&["x86_64", "aarch64"], "macos"));
+    }
+    let arch: &[_] = match target.split('-').next()? {
+        "x86_64" => &["x86_64"],
+        "i386" | "i586" | "i686" => &["x86"],
+        "aarch64" => &["aarch64"],
+        arch if arch.starts_with("arm") || arch.starts_with("thumb") => &["arm"],
+        arch if arch.starts_with("riscv64") => &["riscv64"],
+        "powerpc64" | "powerpc64le" => &["powerpc64"],
+        "s390x" => &["s390x"],
+        "wasm32" => &["wasm32"],
+        _ => return None,
+    };
+    // `*-linux-android` is not `linux`.
+    let os = if target.contains("android") {
+        "android"
+    } else {
+        target.split('-').skip(1).find_map(|component| {
+            Some(match component {
+                "linux" => "linux",
+                "darwin" => "macos",
+                "ios" => "ios",
+                "windows" => "windows",
+                "freebsd" => "freebsd",
+                "netbsd" => "netbsd",
+                "openbsd" => "openbsd",
+                "illumos" => "illumos",
+                "wasi" => "wasi",
+                _ => return None,
+            })
+        })?
+    };
+    Some((arch, os))
+}
+
+/// Adds the extension for `target` unless `path` already has one.
+fn with_executable_extension(mut path: PathBuf, target: &str) -> PathBuf {
+    if path.extension().is_none() && !is_stdout(&path) {
+        if let Some(extension) = executable_extension(target) {
+            path.set_extension(extension);
+        }
+    }
+    path
+}
+
+fn output_file_name(package_name: &str, bin_name: &str, target: &str, emit: Emit) -> String {
+    let file_stem = format!("{}-{}-{}", package_name, bin_name, target);
+    let extension = match emit {
+        Emit::Rust | Emit::RustFragment => Some("rs"),
+        Emit::Binary => executable_extension(target),
+        Emit::Split => return LOADER_FILE_NAME.to_owned(),
+    };
+    match extension {
+        Some(extension) => format!("{}.{}", file_stem, extension),
+        None => file_stem,
+    }
+}
+
+/// Index of the files in `--output-dir`.
+static PAYLOADS_MANIFEST: &str = "payloads.json";
+
+/// An entry of `payloads.json`.
+///
+/// ```json
+/// {
+///   "payloads": [
+///     {
+///       "bin": "a",
+///       "emit": "rust",
+///       "executable_size": 389584,
+///       "file": "practice-a-x86_64-unknown-linux-musl.rs",
+///       "package": "practice",
+///       "size": 519510,
+///       "target": "x86_64-unknown-linux-musl"
+///     }
+///   ],
+///   "version": 1
+/// }
+/// ```
+///
+/// `executable_size` is the size of the executable before compression, and `size` is the size of
+/// `file`. `target` is `x86_64-apple-darwin+aarch64-apple-darwin` with `--universal`. The entries
+/// are sorted by `file`.
+struct PayloadEntry<'a> {
+    package: &'a str,
+    bin: &'a str,
+    target: &'a str,
+    emit: Emit,
+    executable_size: usize,
+    size: usize,
+    file: &'a str,
+}
+
+/// Adds `entry` to `<output_dir>/payloads.json`, replacing the one for the same file.
+fn update_payloads_manifest(output_dir: &Path, entry: &PayloadEntry<'_>) -> anyhow::Result<()> {
+    let path = output_dir.join(PAYLOADS_MANIFEST);
+
+    let mut payloads = if path.exists() {
+        let manifest = std::fs::read_to_string(&path)
+            .with_context(|| format!("could not read `{}`", path.display()))?;
+        let manifest = serde_json::from_str::<serde_json::Value>(&manifest)
+            .with_context(|| format!("could not parse `{}`", path.display()))?;
+        match manifest.get("payloads") {
+            Some(serde_json::Value::Array(payloads)) => payloads.clone(),
+            _ => bail!("`{}` does not have `payloads`", path.display()),
+        }
+    } else {
+        vec![]
+    };
+
+    payloads.retain(|p| p["file"] != entry.file);
+    payloads.push(serde_json::json!({
+        "package": entry.package,
+        "bin": entry.bin,
+        "target": entry.target,
+        "emit": match entry.emit {
+            Emit::Rust => "rust",
+            Emit::Binary => "binary",
+            Emit::Split => "split",
+            Emit::RustFragment => "rust-fragment",
+        },
+        "executable_size": entry.executable_size,
+        "size": entry.size,
+        "file": entry.file,
+    }));
+    payloads.sort_by(|p1, p2| p1["file"].as_str().cmp(&p2["file"].as_str()));
+
+    let manifest = serde_json::json!({ "version": 1, "payloads": payloads });
+    let manifest = serde_json::to_string_pretty(&manifest)? + "\n";
+    std::fs::write(&path, manifest).with_context(|| format!("could not write `{}`", path.display()))
+}
+
+fn default_extract_name(bin_name: &str) -> String {
+    bin_name
+        .chars()
+        .map(|c| match c {
+            'a'..='z' | 'A'..='Z' | '0'..='9' | '-' | '_' | '.' => c,
+            _ => '_',
+        })
+        .collect()
+}
+
+/// `.` and `..` are not file names, even though `default_extract_name` keeps them.
+fn validate_extract_name(extract_name: &str) -> anyhow::Result<()> {
+    if matches!(extract_name, "" | "." | "..") || extract_name.contains('/') {
+        bail!("invalid extract name: {:?}", extract_name);
+    }
+    Ok(())
+}
+
+/// Files written to `--output-dir` for `--emit split`. The former is exactly the base64 payload,
+/// without a trailing newline.
+static PAYLOAD_FILE_NAME: &str = "payload.b64";
+static LOADER_FILE_NAME: &str = "loader.rs";
+
+/// Version of the output format. Increment this when the template changes.
+const FORMAT_VERSION: u32 = 9;
+
+const FORMAT_VERSION_MARKER: &str = "// cargo-executable-payload format version: ";
+
+fn format_version_of(rs: &str) -> Option<u32> {
+    rs.lines()
+        .find_map(|line| line.strip_prefix(FORMAT_VERSION_MARKER))
+        .and_then(|version| version.parse().ok())
+}
+
+fn check_format_version(shell: &mut Shell, path: &Path) -> anyhow::Result<()> {
+    let rs = std::fs::read_to_string(path)
+        .with_context(|| format!("could not read `{}`", path.display()))?;
+    match format_version_of(&rs) {
+        Some(FORMAT_VERSION) => {}
+        Some(version) => shell.warn(format!(
+            "`{}` was generated in format version {}, but the current one is {}",
+            path.display(),
+            version,
+            FORMAT_VERSION,
+        ))?,
+        None => shell.warn(format!(
+            "`{}` was generated in an old format, or not generated by cargo-executable-payload",
+            path.display(),
+        ))?,
+    }
+    Ok(())
+}
+
+#[derive(Debug, PartialEq)]
+enum GeneratedDiff {
+    Unchanged,
+    PayloadOnly,
+    /// Unified diff with the payloads omitted.
+    Changed(String),
+}
+
+/// Compares two generated files ignoring `PAYLOAD`, `PAYLOADS`, and `LEN`.
+fn compare_generated(old: &str, new: &str, old_name: &str) -> GeneratedDiff {
+    fn omit_payloads(src: &str) -> String {
+        let mut src = src.to_owned();
+        for (start, end) in &[
+            ("static PAYLOAD:&str=\"", "\""),
+            ("static PAYLOADS:[(&str,usize);2]=[", "];"),
+            ("static LEN:usize=", ";"),
+        ] {
+            if let Some(i) = src.find(start) {
+                let i = i + start.len();
+                if let Some(n) = src[i..].find(end) {
+                    src.replace_range(i..i + n, "..");
+                }
+            }
+        }
+        src
+    }
+
+    if old == new {
+        return GeneratedDiff::Unchanged;
+    }
+    let (old, new) = (omit_payloads(old), omit_payloads(new));
+    if old == new {
+        GeneratedDiff::PayloadOnly
+    } else {
+        GeneratedDiff::Changed(unified_diff(&old, &new, old_name, "(new)"))
+    }
+}
+
+/// Computes a unified diff of lines with 3 lines of context.
+fn unified_diff(old: &str, new: &str, old_name: &str, new_name: &str) -> String {
+    similar::TextDiff::from_lines(old, new)
+        .unified_diff()
+        .context_radius(3)
+        .header(old_name, new_name)
+        .to_string()
+}
+
+/// Components of a file generated by cargo-executable-payload.
+#[derive(Clone, Debug, PartialEq)]
+pub struct Generated {
+    /// `None` if the file is older than format version 3.
+    pub format_version: Option<u32>,
+    pub extract_path: String,
+    /// Applied in this order before encoding.
+    pub compression: Vec<Compression>,
+    /// Payload decoded from base64 or Z85. This is still compressed if `compression` is not empty.
+    pub payload: Vec<u8>,
+    /// Length of the executable. `None` if the file is older than format version 2.
+    pub payload_len: Option<usize>,
+}
+
+/// Parses a file generated by cargo-executable-payload.
+pub fn parse_generated(src: &str) -> anyhow::Result<Generated> {
+    fn static_str(src: &str, name: &str) -> anyhow::Result<String> {
+        let pattern = format!("static {}:&str=", name);
+        let start = src
+            .find(&pattern)
+            .with_context(|| format!("`{}` not found", name))?;
+        parse_str_literal(&src[start + pattern.len()..])
+            .with_context(|| format!("could not parse the value of `{}`", name))
+    }
+
+    fn parse_str_literal(s: &str) -> Option<String> {
+        let mut chars = s.strip_prefix('"')?.chars();
+        let mut value = String::new();
+        loop {
+            match chars.next()? {
+                '"' => break Some(value),
+                '\\' => value.push(match chars.next()? {
+                    'n' => '\n',
+                    'r' => '\r',
+                    't' => '\t',
+                    '0' => '\0',
+                    'u' => {
+                        let rest = chars.as_str().strip_prefix('{')?;
+                        let (hex, rest) = rest.split_at(rest.find('}')?);
+                        chars = rest[1..].chars();
+                        std::char::from_u32(u32::from_str_radix(hex, 16).ok()?)?
+                    }
+                    c => c,
+                }),
+                c => value.push(c),
+            }
+        }
+    }
+
+    let extract_path = static_str(src, "PATH")?;
+    let payload = static_str(src, "PAYLOAD")?;
+    let payload = if src.contains(Z85_DECODE_MARKER) {
+        let padding_pattern = "acc.truncate(acc.len()-";
+        let padding = src
+            .find(padding_pattern)
+            .and_then(|start| {
+                let rest = &src[start + padding_pattern.len()..];
+                rest[..rest.find(')')?].parse::<usize>().ok()
+            })
+            .with_context(|| "could not parse the padding of the payload")?;
+        let mut payload = z85_decode(&payload).with_context(|| "the payload is not valid Z85")?;
+        let len = payload
+            .len()
+            .checked_sub(padding)
+            .with_context(|| "the padding is longer than the payload")?;
+        payload.truncate(len);
+        payload
+    } else {
+        base64::decode(payload).with_context(|| "the payload is not valid base64")?
+    };
+
+    let len_pattern = "static LEN:usize=";
+    let payload_len = src
+        .find(len_pattern)
+        .map(|start| {
+            let rest = &src[start + len_pattern.len()..];
+            rest[..rest.find(';').unwrap_or(rest.len())].parse()
+        })
+        .transpose()
+        .with_context(|| "could not parse the value of `LEN`")?;
+
+    // The layers are decompressed in the reverse order.
+    let mut compression = src
+        .match_indices("let acc=")
+        .flat_map(|(i, _)| match &src[i..] {
+            s if s.starts_with("let acc=inflate(&acc);") => Some(Compression::Gzip),
+            s if s.starts_with("let acc=unlzma(&acc);") => Some(Compression::Lzma),
+            _ => None,
+        })
+        .collect::<Vec<_>>();
+    compression.reverse();
+
+    Ok(Generated {
+        format_version: format_version_of(src),
+        extract_path,
+        compression,
+        payload,
+        payload_len,
+    })
+}
+
+#[derive(Default)]
+struct Template<'a> {
+    original_source_code: &'a str,
+    payload: &'a str,
+    payload_len: usize,
+    /// File that `PAYLOAD` is `include_str!`ed from instead, for `--emit split`.
+    payload_file: Option<&'a str>,
+    /// Payload and its length for x86-64-v3 CPUs, with `--multiarch`.
+    payload_v3: Option<(&'a str, usize)>,
+    encoding: Encoding,
+    /// Number of zero bytes appended to the payload before encoding it with Z85.
+    padding: usize,
+    /// Decodes the base64 payload at compile time of the generated code.
+    const_decode: bool,
+    checksum: Checksum,
+    /// Applied in this order, so they are undone in the reverse order.
+    compression: &'a [Compression],
+    runtime_decoder: RuntimeDecoder,
+    /// Joined to `std::env::temp_dir()` and suffixed with the PID and the time at runtime.
+    extract_path: &'a str,
+    /// Uses `extract_path` as is instead.
+    exact_extract_path: bool,
+    provenance: Option<&'a Provenance>,
+    no_fsync: bool,
+    write_retries: u32,
+    exec_retries: u32,
+    spawn: bool,
+    /// Takes precedence over `spawn`.
+    posix_spawn: bool,
+    self_delete: bool,
+    no_new_privs: bool,
+    env_passthrough: &'a [String],
+    preserve_arg0: bool,
+    /// Expected values of `std::env::consts::ARCH` (any of them) and `std::env::consts::OS`.
+    assert_arch: Option<(&'a [&'a str], &'a str)>,
+    banner: Option<&'a str>,
+    /// Contents of `#![..]` at the top.
+    inner_attrs: &'a [String],
+    /// Declares `extern` blocks as `unsafe extern`, which edition 2024 requires and Rust before 1.82
+    /// rejects.
+    unsafe_extern: bool,
+    /// Emits `pub fn run_payload` instead of `main`, without the doc comment and the original
+    /// source code.
+    fragment: bool,
+}
+
+fn format_with_template(template: &Template<'_>) -> String {
+    let Template {
+        original_source_code,
+        payload,
+        payload_len,
+        payload_file,
+        payload_v3,
+        encoding,
+        padding,
+        const_decode,
+        checksum,
+        compression,
+        runtime_decoder,
+        extract_path,
+        exact_extract_path,
+        provenance,
+        no_fsync,
+        write_retries,
+        exec_retries,
+        spawn,
+        posix_spawn,
+        self_delete,
+        no_new_privs,
+        env_passthrough,
+        preserve_arg0,
+        assert_arch,
+        banner,
+        inner_attrs,
+        unsafe_extern,
+        fragment,
+    } = *template;
+
+    // The capacity is the decoded length before the padding is removed, so `acc` never grows.
+    let decode_payload = match encoding {
+        Encoding::Base64 => match runtime_decoder {
+            RuntimeDecoder::Minimal => DECODE_BASE64,
+            RuntimeDecoder::Fast => DECODE_BASE64_FAST,
+        }
+        .to_owned(),
+        Encoding::Z85 => format!(
+            r#"let mut table=[0;256];for(i,&c)in b"{}".iter().enumerate(){{table[usize::from(c)]=i as u32;}}let mut acc=Vec::with_capacity(PAYLOAD.len()/5*4);{}{{let mut v=0u32;for&c in chunk{{v=v*85+table[usize::from(c)];}}acc.extend_from_slice(&v.to_be_bytes());}}acc.truncate(acc.len()-{});"#,
+            Z85_ALPHABET, Z85_DECODE_MARKER, padding
+        ),
+    };
+
+    // `long_running_const_eval` is denied by default and a large payload easily reaches it.
+    let (decode_payload, const_decoder) = if const_decode {
+        let len = payload.len() / 4 * 3 - payload.bytes().rev().take_while(|&b| b == b'=').count();
+        (
+            "let acc=DECODED.to_vec();".to_owned(),
+            format!(
+                "#[allow(unknown_lints,long_running_const_eval)]static DECODED:[u8;{len}]=decode_const(PAYLOAD.as_bytes());#[allow(clippy::all)]const fn decode_const<const N:usize>(p:&[u8])->[u8;N]{{{body}}}",
+                len = len,
+                body = CONST_DECODE_BASE64,
+            ),
+        )
+    } else {
+        (decode_payload, "".to_owned())
+    };
+
+    let (select_payload, payloads) = match payload_v3 {
+        None => (
+            "",
+            match payload_file {
+                Some(payload_file) => format!(
+                    "static PAYLOAD:&str=include_str!({:?});static LEN:usize={};",
+                    payload_file, payload_len,
+                ),
+                None => format!(
+                    "static PAYLOAD:&str=\"{}\";static LEN:usize={};",
+                    payload, payload_len,
+                ),
+            },
+        ),
+        Some((payload_v3, payload_v3_len)) => (
+            "#[allow(non_snake_case)]let(PAYLOAD,LEN)=PAYLOADS[usize::from(is_x86_64_v3())];",
+            format!(
+                "{}static PAYLOADS:[(&str,usize);2]=[(\"{}\",{}),(\"{}\",{})];",
+                IS_X86_64_V3, payload, payload_len, payload_v3, payload_v3_len,
+            ),
+        ),
+    };
+
+    let (verify, verifier) = match checksum {
+        Checksum::None => ("".to_owned(), "".to_owned()),
+        Checksum::Crc32(crc) => (
+            format!("assert!(crc32(&acc)=={},\"payload corrupted\");", crc),
+            CRC32.to_owned(),
+        ),
+        Checksum::Sha256(hash) => (
+            format!("assert!(sha256(&acc)=={:?},\"payload corrupted\");", hash),
+            format!(
+                "#[allow(clippy::all)]fn sha256(d:&[u8])->[u8;32]{{const K:[u32;64]={:?};let mut h:[u32;8]={:?};{}",
+                SHA256_K, SHA256_H, SHA256_BODY,
+            ),
+        ),
+    };
+
+    let decompress = compression
+        .iter()
+        .rev()
+        .map(|compression| match compression {
+            Compression::Gzip => "let acc=inflate(&acc);",
+            Compression::Lzma => "let acc=unlzma(&acc);",
+        })
+        .collect::<String>();
+    let inflate = match runtime_decoder {
+        _ if !compression.contains(&Compression::Gzip) => "",
+        RuntimeDecoder::Minimal => INFLATE,
+        RuntimeDecoder::Fast => INFLATE_FAST,
+    };
+    let unlzma = if compression.contains(&Compression::Lzma) {
+        UNLZMA
+    } else {
+        ""
+    };
+    let decompressor = format!("{}{}", inflate, unlzma);
+
+    // A file left at an exact path is removed so that `create_new` succeeds.
+    let extract_path_expr = if exact_extract_path {
+        "{let path=std::path::PathBuf::from(PATH);let _=std::fs::remove_file(&path);path}"
+    } else {
+        r#"std::env::temp_dir().join(format!("{}.{}.{}",PATH,std::process::id(),std::time::SystemTime::now().duration_since(std::time::UNIX_EPOCH).map_or(0,|d|d.as_nanos())))"#
+    };
+
+    let sync_all = if no_fsync { "" } else { "file.sync_all()?;" };
+    let write = if write_retries == 0 {
+        format!("let mut file=OpenOptions::new().write(true).create_new(true).mode(0o755).open(&path)?;file.write_all(&decode())?;file.set_permissions(Permissions::from_mode(0o755))?;{}drop(file);", sync_all)
+    } else {
+        // A partial file is removed before retrying.
+        format!("{}let acc=decode();let mut retries={};while let Err(e)=(||->std::io::Result<()>{{let mut file=OpenOptions::new().write(true).create_new(true).mode(0o755).open(&path)?;file.write_all(&acc)?;file.set_permissions(Permissions::from_mode(0o755))?;{}Ok(())}})(){{if retries==0||!(e.kind()==std::io::ErrorKind::Interrupted||e.raw_os_error()==Some(ENOSPC)){{return Err(e);}}retries-=1;let _=std::fs::remove_file(&path);}}", errno_consts(&[("ENOSPC", 28)]), write_retries, sync_all)
+    };
+
+    // `CommandExt` is not imported with `--spawn`.
+    let (command, passthrough) = if env_passthrough.is_empty() && !preserve_arg0 {
+        ("Command::new(&path)", "".to_owned())
+    } else {
+        let mut passthrough = "let mut cmd=Command::new(&path);".to_owned();
+        if !env_passthrough.is_empty() {
+            let keys = env_passthrough
+                .iter()
+                .format_with(",", |k, f| f(&format_args!("{:?}", k)));
+            passthrough += &format!(
+                "for k in&[{}]{{if let Some(v)=std::env::var_os(k){{cmd.env(k,v);}}}}",
+                keys
+            );
+        }
+        if preserve_arg0 {
+            passthrough += "if let Some(arg0)=std::env::args_os().next(){std::os::unix::process::CommandExt::arg0(&mut cmd,arg0);}";
+        }
+        ("cmd", passthrough)
+    };
+
+    // `ETXTBSY` and `EAGAIN`, which a loaded system may return for a file that has just been
+    // written. `ErrorKind::WouldBlock` is `EAGAIN` on every OS.
+    let etxtbsy = errno_consts(&[("ETXTBSY", 26)]);
+    let is_transient =
+        "(e.raw_os_error()==Some(ETXTBSY)||e.kind()==std::io::ErrorKind::WouldBlock)";
+    let backoff =
+        "exec_retries-=1;std::thread::sleep(std::time::Duration::from_millis(wait));wait*=2;";
+
+    let extern_c = if unsafe_extern {
+        r#"unsafe extern "C""#
+    } else {
+        r#"extern "C""#
+    };
+
+    // `PR_SET_NO_NEW_PRIVS` is 38, and is inherited by the child with `--spawn`.
+    let no_new_privs = if no_new_privs {
+        format!(
+            r#"#[cfg(target_os="linux")]{{{}{{fn prctl(option:i32,...)->i32;}}if unsafe{{prctl(38,1usize,0usize,0usize,0usize)}}!=0{{return Err(std::io::Error::last_os_error());}}}}"#,
+            extern_c,
+        )
+    } else {
+        "".to_owned()
+    };
+
+    // `std::process::exit` does not run destructors, so the guard is dropped explicitly.
+    let (guard, remove) = if self_delete {
+        (
+            "struct Guard<'a>(&'a std::path::Path);impl Drop for Guard<'_>{fn drop(&mut self){let _=std::fs::remove_file(self.0);}}let _guard=Guard(&path);",
+            "drop(_guard);",
+        )
+    } else {
+        ("", "let _=std::fs::remove_file(&path);")
+    };
+
+    // The child inherits stdin/stdout/stderr either way, but be explicit about it for `spawn`.
+    // `posix_spawn` passes the whole `environ`, so `env_passthrough` is not needed for it.
+    let (uses, run) = if posix_spawn {
+        (
+            "os::unix::fs::{OpenOptionsExt as _,PermissionsExt as _}",
+            format!(
+                "let code=posix_spawn(&path)?;{}std::process::exit(code)",
+                remove
+            ),
+        )
+    } else if spawn {
+        let status = format!(
+            "{}.stdin(Stdio::inherit()).stdout(Stdio::inherit()).stderr(Stdio::inherit()).status()",
+            command
+        );
+        let status = if exec_retries == 0 {
+            format!("{}?", status)
+        } else {
+            format!("{{{}let mut exec_retries={};let mut wait=10;loop{{match {}{{Err(e)if exec_retries>0&&{}=>{{{}}}r=>break r?,}}}}}}", etxtbsy, exec_retries, status, is_transient, backoff)
+        };
+        (
+            "os::unix::fs::{OpenOptionsExt as _,PermissionsExt as _},process::{Command,Stdio}",
+            format!(
+                "{}let status={};{}std::process::exit(status.code().unwrap_or(1))",
+                passthrough, status, remove
+            ),
+        )
+    } else {
+        // The executable is left if `exec` succeeds, since nothing runs after it.
+        let exec = if exec_retries == 0 {
+            format!("let e={}.exec();{}Err(e)", command, remove)
+        } else {
+            format!("{}let mut exec_retries={};let mut wait=10;let e=loop{{let e={}.exec();if exec_retries==0||!{}{{break e;}}{}}};{}Err(e)", etxtbsy, exec_retries, command, is_transient, backoff, remove)
+        };
+        (
+            "os::unix::{fs::{OpenOptionsExt as _,PermissionsExt as _},process::CommandExt as _},process::Command",
+            format!("{}{}", passthrough, exec),
+        )
+    };
+
+    // `pid_t` is `i32` on Linux and macOS. A status not from `exit` is a termination by a signal.
+    let posix_spawner = if posix_spawn {
+        let arg0 = if preserve_arg0 {
+            "let arg0=match std::env::args_os().next(){Some(arg0)=>CString::new(arg0.as_bytes())?,None=>path.clone()};"
+        } else {
+            "let arg0=path.clone();"
+        };
+        let spawn = "unsafe{posix_spawn(&mut pid,path.as_ptr(),ptr::null(),ptr::null(),argv.as_ptr(),environ)}";
+        let spawn = if exec_retries == 0 {
+            format!("let e={};", spawn)
+        } else {
+            format!("{}let mut exec_retries={};let mut wait=10;let e=loop{{let e={};if exec_retries==0||!(e==ETXTBSY||std::io::Error::from_raw_os_error(e).kind()==std::io::ErrorKind::WouldBlock){{break e;}}{}}};", etxtbsy, exec_retries, spawn, backoff)
+        };
+        format!(
+            r#"#[cfg(unix)]#[allow(clippy::all)]fn posix_spawn(path:&std::path::Path)->std::io::Result<i32>{{use std::{{ffi::CString,os::{{raw::{{c_char,c_int,c_void}},unix::ffi::OsStrExt as _}},ptr}};{}{{fn posix_spawn(pid:*mut i32,path:*const c_char,file_actions:*const c_void,attrp:*const c_void,argv:*const *mut c_char,envp:*const *mut c_char)->c_int;fn waitpid(pid:i32,status:*mut c_int,options:c_int)->i32;static environ:*const *mut c_char;}}let path=CString::new(path.as_os_str().as_bytes())?;{}let argv=[arg0.as_ptr()as *mut c_char,ptr::null_mut()];let mut pid=0;{}if e!=0{{return Err(std::io::Error::from_raw_os_error(e));}}let mut status=0;while unsafe{{waitpid(pid,&mut status,0)}}==-1{{let e=std::io::Error::last_os_error();if e.kind()!=std::io::ErrorKind::Interrupted{{return Err(e);}}}}Ok(if status&0x7f==0{{status>>8&0xff}}else{{1}})}}"#,
+            extern_c, arg0, spawn,
+        )
+    } else {
+        "".to_owned()
+    };
+
+    let (header, entry, source_code_macro) = if fragment {
+        (
+            format!("{}{}\n", FORMAT_VERSION_MARKER, FORMAT_VERSION),
+            "pub fn run_payload",
+            "",
+        )
+    } else {
+        (
+            formatdoc! {r#"
+                {inner_attrs}//! This code is generated by [cargo-executable-payload](https://github.com/qryxip/cargo-executable-payload).
+                {format_version_marker}{format_version}
+                {provenance}
+                original_source_code! {{
+                {original_source_code}}}
+
+            "#,
+                inner_attrs = inner_attrs
+                    .iter()
+                    .map(|attr| format!("#![{}]\n", attr))
+                    .collect::<String>(),
+                format_version_marker = FORMAT_VERSION_MARKER,
+                format_version = FORMAT_VERSION,
+                provenance = provenance.map(ToString::to_string).unwrap_or_default(),
+                original_source_code = indent_code(original_source_code),
+            },
+            "fn main",
+            "#[macro_export]macro_rules!original_source_code{($($_:tt)*)=>()}",
+        )
+    };
+
+    formatdoc! {r#"
+        {header}#[allow(clippy::all)]{entry}()->std::io::Result<()>{{{assert_arch}{banner}use std::{{fs::{{OpenOptions,Permissions}},io::Write as _,{uses}}};let path={extract_path_expr};{guard}{write}{no_new_privs}{run}}}#[allow(clippy::all)]fn decode()->Vec<u8>{{{select_payload}{decode_payload}{decompress}assert!(acc.len()==LEN,"payload truncated");{verify}acc}}{decompressor}{verifier}{const_decoder}{posix_spawner}{source_code_macro}static PATH:&str={extract_path:?};{payloads}
+        "#,
+        header = header,
+        entry = entry,
+        source_code_macro = source_code_macro,
+        select_payload = select_payload,
+        payloads = payloads,
+        assert_arch = assert_arch
+            .map(|(arches, os)| format!(
+                "{{use std::env::consts::{{ARCH,OS}};if!{:?}.contains(&ARCH)||OS!={:?}{{eprintln!(\"this program is for {}-{}, but the current platform is {{}}-{{}}\",ARCH,OS);std::process::exit(1);}}}}",
+                arches,
+                os,
+                arches.join("|"),
+                os,
+            ))
+            .unwrap_or_default(),
+        banner = banner
+            .map(|banner| format!("eprintln!(\"{{}}\",{:?});", banner))
+            .unwrap_or_default(),
+        uses = uses,
+        guard = guard,
+        no_new_privs = no_new_privs,
+        run = run,
+        write = write,
+        decode_payload = decode_payload,
+        decompress = decompress,
+        decompressor = decompressor,
+        verify = verify,
+        verifier = verifier,
+        const_decoder = const_decoder,
+        posix_spawner = posix_spawner,
+        extract_path = extract_path,
+        extract_path_expr = extract_path_expr,
+    }
+}
+
+/// Declares errno constants in the generated code. `values` are the ones of Linux, which macOS and
+/// the BSDs agree with for the constants used. On the other OSes, they are `-1`, which no error
+/// has.
+fn errno_consts(values: &[(&str, i32)]) -> String {
+    let oses = r#"any(target_os="linux",target_os="android",target_os="macos",target_os="ios",target_os="freebsd",target_os="netbsd",target_os="openbsd",target_os="dragonfly")"#;
+    values
+        .iter()
+        .map(|(name, value)| {
+            format!(
+                "#[cfg({oses})]const {name}:i32={value};#[cfg(not({oses}))]const {name}:i32=-1;",
+                oses = oses,
+                name = name,
+                value = value,
+            )
+        })
+        .collect()
+}
+
+static Z85_ALPHABET: &str =
+    "0123456789abcdefghijklmnopqrstuvwxyzABCDEFGHIJKLMNOPQRSTUVWXYZ.-:+=^!/*?&<>()[]{}@%$#";
+
+/// Identifies the Z85 decoder in the generated code.
+static Z85_DECODE_MARKER: &str = "for chunk in PAYLOAD.as_bytes().chunks_exact(5)";
+
+/// Encodes `data` with Z85, padding it with zero bytes to a multiple of 4 bytes.
+///
+/// Returns the encoded string and the number of the padding bytes.
+fn z85_encode(data: &[u8]) -> (String, usize) {
+    let padding = (4 - data.len() % 4) % 4;
+    let alphabet = Z85_ALPHABET.as_bytes();
+    let mut encoded = String::with_capacity((data.len() + padding) / 4 * 5);
+    for chunk in data.chunks(4) {
+        let mut word = [0; 4];
+        word[..chunk.len()].copy_from_slice(chunk);
+        let mut value = u32::from_be_bytes(word);
+        let mut digits = [0; 5];
+        for digit in digits.iter_mut().rev() {
+            *digit = alphabet[(value % 85) as usize];
+            value /= 85;
+        }
+        encoded.extend(digits.iter().map(|&d| char::from(d)));
+    }
+    (encoded, padding)
+}
+
+/// Decodes Z85 without removing the padding. Returns `None` if `s` is not valid Z85.
+fn z85_decode(s: &str) -> Option<Vec<u8>> {
+    let chunks = s.as_bytes().chunks_exact(5);
+    if !chunks.remainder().is_empty() {
+        return None;
+    }
+    let mut decoded = Vec::with_capacity(s.len() / 5 * 4);
+    for chunk in chunks {
+        let mut value = 0u32;
+        for &c in chunk {
+            let digit = Z85_ALPHABET.bytes().position(|d| d == c)?;
+            value = value.checked_mul(85)?.checked_add(digit as u32)?;
+        }
+        decoded.extend_from_slice(&value.to_be_bytes());
+    }
+    Some(decoded)
+}
+
+/// Detects x86-64-v3 CPUs in the generated code, for `--multiarch`.
+///
+/// `movbe` is not checked since `is_x86_feature_detected!` did not support it until recently.
+static IS_X86_64_V3: &str = r#"#[allow(clippy::all)]fn is_x86_64_v3()->bool{is_x86_feature_detected!("avx")&&is_x86_feature_detected!("avx2")&&is_x86_feature_detected!("bmi1")&&is_x86_feature_detected!("bmi2")&&is_x86_feature_detected!("f16c")&&is_x86_feature_detected!("fma")&&is_x86_feature_detected!("lzcnt")&&is_x86_feature_detected!("popcnt")&&is_x86_feature_detected!("sse4.2")&&is_x86_feature_detected!("xsave")}"#;
+
+/// Body of the `const fn` base64 decoder in the generated code, for `--const-decode`.
+static CONST_DECODE_BASE64: &str = "const fn v(c:u8)->u32{(match c{b'A'..=b'Z'=>c-b'A',b'a'..=b'z'=>c-b'a'+26,b'0'..=b'9'=>c-b'0'+52,b'+'=>62,b'/'=>63,_=>0})as u32}let mut r=[0;N];let(mut i,mut j)=(0,0);while i+4<=p.len(){let n=v(p[i])<<18|v(p[i+1])<<12|v(p[i+2])<<6|v(p[i+3]);let b=[(n>>16)as u8,(n>>8)as u8,n as u8];let mut k=0;while k<3&&j<N{r[j]=b[k];j+=1;k+=1;}i+=4;}r";
+
+/// CRC-32 (IEEE 802.3) in the generated code, for `--checksum-algo crc32`.
+static CRC32: &str = "#[allow(clippy::all)]fn crc32(d:&[u8])->u32{let mut c=!0u32;for&b in d{c^=u32::from(b);for _ in 0..8{c=if c&1>0{c>>1^0xedb88320}else{c>>1};}}!c}";
+
+/// Rest of SHA-256 in the generated code after `K` and `h`, for `--checksum-algo sha256`.
+static SHA256_BODY: &str = "let mut m=d.to_vec();m.push(0x80);while m.len()%64!=56{m.push(0);}m.extend_from_slice(&(d.len()as u64*8).to_be_bytes());for c in m.chunks(64){let mut w=[0u32;64];for i in 0..16{w[i]=u32::from_be_bytes([c[4*i],c[4*i+1],c[4*i+2],c[4*i+3]]);}for i in 16..64{let s0=w[i-15].rotate_right(7)^w[i-15].rotate_right(18)^w[i-15]>>3;let s1=w[i-2].rotate_right(17)^w[i-2].rotate_right(19)^w[i-2]>>10;w[i]=w[i-16].wrapping_add(s0).wrapping_add(w[i-7]).wrapping_add(s1);}let mut v=h;for i in 0..64{let s1=v[4].rotate_right(6)^v[4].rotate_right(11)^v[4].rotate_right(25);let ch=v[4]&v[5]^!v[4]&v[6];let t1=v[7].wrapping_add(s1).wrapping_add(ch).wrapping_add(K[i]).wrapping_add(w[i]);let s0=v[0].rotate_right(2)^v[0].rotate_right(13)^v[0].rotate_right(22);let mj=v[0]&v[1]^v[0]&v[2]^v[1]&v[2];let t2=s0.wrapping_add(mj);v=[t1.wrapping_add(t2),v[0],v[1],v[2],v[3].wrapping_add(t1),v[4],v[5],v[6]];}for i in 0..8{h[i]=h[i].wrapping_add(v[i]);}}let mut r=[0;32];for i in 0..8{r[4*i..4*i+4].copy_from_slice(&h[i].to_be_bytes());}r}";
+
+const SHA256_H: [u32; 8] = [
+    0x6a09e667, 0xbb67ae85, 0x3c6ef372, 0xa54ff53a, 0x510e527f, 0x9b05688c, 0x1f83d9ab, 0x5be0cd19,
+];
+
+const SHA256_K: [u32; 64] = [
+    0x428a2f98, 0x71374491, 0xb5c0fbcf, 0xe9b5dba5, 0x3956c25b, 0x59f111f1, 0x923f82a4, 0xab1c5ed5,
+    0xd807aa98, 0x12835b01, 0x243185be, 0x550c7dc3, 0x72be5d74, 0x80deb1fe, 0x9bdc06a7, 0xc19bf174,
+    0xe49b69c1, 0xefbe4786, 0x0fc19dc6, 0x240ca1cc, 0x2de92c6f, 0x4a7484aa, 0x5cb0a9dc, 0x76f988da,
+    0x983e5152, 0xa831c66d, 0xb00327c8, 0xbf597fc7, 0xc6e00bf3, 0xd5a79147, 0x06ca6351, 0x14292967,
+    0x27b70a85, 0x2e1b2138, 0x4d2c6dfc, 0x53380d13, 0x650a7354, 0x766a0abb, 0x81c2c92e, 0x92722c85,
+    0xa2bfe8a1, 0xa81a664b, 0xc24b8b70, 0xc76c51a3, 0xd192e819, 0xd6990624, 0xf40e3585, 0x106aa070,
+    0x19a4c116, 0x1e376c08, 0x2748774c, 0x34b0bcb5, 0x391c0cb3, 0x4ed8aa4a, 0x5b9cca4f, 0x682e6ff3,
+    0x748f82ee, 0x78a5636f, 0x84c87814, 0x8cc70208, 0x90befffa, 0xa4506ceb, 0xbef9a3f7, 0xc67178f2,
+];
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut crc = !0u32;
+    for &b in data {
+        crc ^= u32::from(b);
+        for _ in 0..8 {
+            crc = if crc & 1 == 1 {
+                (crc >> 1) ^ 0xedb8_8320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+fn sha256(data: &[u8]) -> [u8; 32] {
+    let mut message = data.to_owned();
+    message.push(0x80);
+    while message.len() % 64 != 56 {
+        message.push(0);
+    }
+    message.extend_from_slice(&(data.len() as u64 * 8).to_be_bytes());
+
+    let mut h = SHA256_H;
+    for chunk in message.chunks(64) {
+        let mut w = [0u32; 64];
+        for (i, word) in chunk.chunks(4).enumerate() {
+            w[i] = u32::from_be_bytes([word[0], word[1], word[2], word[3]]);
+        }
+        for i in 16..64 {
+            let s0 = w[i - 15].rotate_right(7) ^ w[i - 15].rotate_right(18) ^ (w[i - 15] >> 3);
+            let s1 = w[i - 2].rotate_right(17) ^ w[i - 2].rotate_right(19) ^ (w[i - 2] >> 10);
+            w[i] = w[i - 16]
+                .wrapping_add(s0)
+                .wrapping_add(w[i - 7])
+                .wrapping_add(s1);
+        }
+
+        let [mut a, mut b, mut c, mut d, mut e, mut f, mut g, mut hh] = h;
+        for (&k, &w) in SHA256_K.iter().zip(&w) {
+            let s1 = e.rotate_right(6) ^ e.rotate_right(11) ^ e.rotate_right(25);
+            let ch = (e & f) ^ (!e & g);
+            let t1 = hh
+                .wrapping_add(s1)
+                .wrapping_add(ch)
+                .wrapping_add(k)
+                .wrapping_add(w);
+            let s0 = a.rotate_right(2) ^ a.rotate_right(13) ^ a.rotate_right(22);
+            let maj = (a & b) ^ (a & c) ^ (b & c);
+            let t2 = s0.wrapping_add(maj);
+            hh = g;
+            g = f;
+            f = e;
+            e = d.wrapping_add(t1);
+            d = c;
+            c = b;
+            b = a;
+            a = t1.wrapping_add(t2);
+        }
+        for (h, v) in h.iter_mut().zip(&[a, b, c, d, e, f, g, hh]) {
+            *h = h.wrapping_add(*v);
+        }
+    }
+
+    let mut hash = [0; 32];
+    for (bytes, h) in hash.chunks_mut(4).zip(&h) {
+        bytes.copy_from_slice(&h.to_be_bytes());
+    }
+    hash
+}
+
+/// Decodes `PAYLOAD` into `acc` one byte at a time, in the generated code.
+static DECODE_BASE64: &str = r#"let mut table=[0;256];for(i,&c)in b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/".iter().enumerate(){table[usize::from(c)]=i as u8;}let mut acc=Vec::with_capacity(PAYLOAD.len()/4*3);for chunk in PAYLOAD.as_bytes().chunks_exact(4){let index0=table[usize::from(chunk[0])];let index1=table[usize::from(chunk[1])];let index2=table[usize::from(chunk[2])];let index3=table[usize::from(chunk[3])];acc.push((index0<<2)+(index1>>4));acc.push((index1<<4)+(index2>>2));acc.push((index2<<6)+index3)}if PAYLOAD.ends_with("=="){acc.pop();acc.pop();}else if PAYLOAD.ends_with('='){acc.pop();}"#;
+
+/// Same as `DECODE_BASE64`, but writes each four characters into the preallocated `acc` as a `u32`.
+static DECODE_BASE64_FAST: &str = r#"let mut table=[0;256];for(i,&c)in b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/".iter().enumerate(){table[usize::from(c)]=i as u32;}let mut acc=vec![0;PAYLOAD.len()/4*3];for(chunk,bytes)in PAYLOAD.as_bytes().chunks_exact(4).zip(acc.chunks_exact_mut(3)){let v=table[usize::from(chunk[0])]<<18|table[usize::from(chunk[1])]<<12|table[usize::from(chunk[2])]<<6|table[usize::from(chunk[3])];bytes.copy_from_slice(&v.to_be_bytes()[1..]);}if PAYLOAD.ends_with("=="){acc.pop();acc.pop();}else if PAYLOAD.ends_with('='){acc.pop();}"#;
+
+/// A minimal DEFLATE decoder for gzip streams, used in the generated code.
+static INFLATE: &str = r#"#[allow(clippy::all)]fn inflate(d:&[u8])->Vec<u8>{struct B<'a>{d:&'a[u8],p:usize,}impl B<'_>{fn b(&mut self,n:usize)->usize{let mut v=0;for i in 0..n{v|=usize::from(self.d.get(self.p/8).expect("payload truncated")>>(self.p%8)&1)<<i;self.p+=1;}v}fn s(&mut self,h:&(Vec<usize>,Vec<usize>))->usize{let(mut c,mut f,mut i)=(0,0,0);for l in 1..16{c|=self.b(1);let n=h.0[l];if c<f+n{return h.1[i+c-f];}i+=n;f=(f+n)<<1;c<<=1;}panic!("invalid payload")}}fn h(l:&[usize])->(Vec<usize>,Vec<usize>){let mut c=vec![0;16];for&l in l{c[l]+=1;}c[0]=0;let mut o=vec![0;16];for i in 1..16{o[i]=o[i-1]+c[i-1];}let mut s=vec![0;l.len()];for(i,&l)in l.iter().enumerate(){if l>0{s[o[l]]=i;o[l]+=1;}}(c,s)}let f=d[3];let mut p=10;if f&4>0{p+=2+usize::from(d[10])+usize::from(d[11])*256;}for&m in&[8,16]{if f&m>0{while d[p]>0{p+=1;}p+=1;}}if f&2>0{p+=2;}let mut r=B{d,p:p*8};let mut o=vec![];loop{let last=r.b(1);match r.b(2){0=>{r.p=(r.p+7)/8*8;let n=r.b(16);r.b(16);for _ in 0..n{o.push(r.b(8)as u8);}}t=>{let(lh,dh)=if t==1{let mut l=[8;288];for x in&mut l[144..256]{*x=9;}for x in&mut l[256..280]{*x=7;}(h(&l),h(&[5;30]))}else{let(nl,nd,nc)=(r.b(5)+257,r.b(5)+1,r.b(4)+4);let mut l=[0;19];for&i in&[16,17,18,0,8,7,9,6,10,5,11,4,12,3,13,2,14,1,15][..nc]{l[i]=r.b(3);}let ch=h(&l);let mut l=vec![];while l.len()<nl+nd{match r.s(&ch){16=>{let x=*l.last().unwrap();for _ in 0..3+r.b(2){l.push(x);}}17=>{for _ in 0..3+r.b(3){l.push(0);}}18=>{for _ in 0..11+r.b(7){l.push(0);}}x=>l.push(x),}}(h(&l[..nl]),h(&l[nl..]))};loop{let x=r.s(&lh);if x<256{o.push(x as u8);}else if x==256{break;}else{let x=x-257;let n=if x==28{258}else if x<4{x+3}else{let e=x/4-1;((4+x%4)<<e)+3+r.b(e)};let y=r.s(&dh);let n2=if y<4{y+1}else{let e=y/2-1;((2+y%2)<<e)+1+r.b(e)};for _ in 0..n{o.push(o[o.len()-n2]);}}}}}if last>0{break o;}}}"#;
+
+/// A minimal LZMA decoder for `.lzma` streams, used in the generated code. The output doubles as
+/// the dictionary.
+static UNLZMA: &str = r#"#[allow(clippy::all)]fn unlzma(d:&[u8])->Vec<u8>{struct R<'a>{d:&'a[u8],i:usize,r:u32,c:u32}impl R<'_>{fn n(&mut self){if self.r<1<<24{self.r<<=8;self.c=self.c<<8|u32::from(self.d[self.i]);self.i+=1;}}fn b(&mut self,p:&mut u16)->usize{let b=(self.r>>11)*u32::from(*p);let x=if self.c<b{*p+=(2048-*p)>>5;self.r=b;0}else{*p-=*p>>5;self.c-=b;self.r-=b;1};self.n();x}fn t(&mut self,p:&mut[u16],n:u32)->usize{let mut m=1;for _ in 0..n{m=m<<1|self.b(&mut p[m]);}m-(1<<n)}fn v(&mut self,p:&mut[u16],n:u32)->usize{let(mut m,mut s)=(1,0);for i in 0..n{let b=self.b(&mut p[m]);m=m<<1|b;s|=b<<i;}s}fn l(&mut self,p:&mut[u16],s:usize)->usize{if self.b(&mut p[0])==0{self.t(&mut p[2+s*8..],3)}else if self.b(&mut p[1])==0{8+self.t(&mut p[130+s*8..],3)}else{16+self.t(&mut p[258..],8)}}}let(lc,lp,pb)=(usize::from(d[0]%9),usize::from(d[0]/9%5),usize::from(d[0]/45));let z=u64::from_le_bytes([d[5],d[6],d[7],d[8],d[9],d[10],d[11],d[12]]);let mut r=R{d,i:18,r:!0,c:u32::from_be_bytes([d[14],d[15],d[16],d[17]])};let mut q=vec![1024u16;0x300<<(lc+lp)];let mut p=[1024u16;1846];let mut o=vec![];let(mut s,mut k)=(0,[0usize;4]);while o.len()as u64!=z{let ps=o.len()&((1<<pb)-1);if r.b(&mut p[s<<4|ps])==0{let q=&mut q[0x300*(((o.len()&((1<<lp)-1))<<lc)+(usize::from(o.last().copied().unwrap_or(0u8))>>(8-lc)))..];let mut y=1;if s>=7{let mut m=usize::from(o[o.len()-k[0]-1]);while y<0x100{let a=m>>7&1;m<<=1;let b=r.b(&mut q[((1+a)<<8)+y]);y=y<<1|b;if a!=b{break;}}}while y<0x100{y=y<<1|r.b(&mut q[y]);}o.push(y as u8);s=if s<4{0}else if s<10{s-3}else{s-6};continue;}let n=if r.b(&mut p[192+s])==1{if r.b(&mut p[204+s])==0{if r.b(&mut p[240+(s<<4|ps)])==0{s=if s<7{9}else{11};o.push(o[o.len()-k[0]-1]);continue;}}else{let x=if r.b(&mut p[216+s])==0{1}else if r.b(&mut p[228+s])==0{2}else{3};k[..=x].rotate_right(1);}s=if s<7{8}else{11};r.l(&mut p[1332..],ps)}else{k.rotate_right(1);let n=r.l(&mut p[818..],ps);s=if s<7{7}else{10};let t=r.t(&mut p[432+n.min(3)*64..],6);k[0]=if t<4{t}else{let c=(t as u32>>1)-1;let mut x=2|t&1;if t<14{x<<=c;x+=r.v(&mut p[687+x-t..],c);}else{for _ in 0..c-4{r.r>>=1;r.c=r.c.wrapping_sub(r.r);let m=0u32.wrapping_sub(r.c>>31);r.c=r.c.wrapping_add(r.r&m);r.n();x=x<<1|m.wrapping_add(1)as usize;}x=(x<<4)+r.v(&mut p[802..],4);}x&0xffffffff};if k[0]==0xffffffff{break;}n};for _ in 0..n+2{o.push(o[o.len()-k[0]-1]);}}o}"#;
+
+/// Same as `INFLATE`, but reads bits through a 64-bit buffer and decodes Huffman codes with lookup
+/// tables.
+static INFLATE_FAST: &str = r#"#[allow(clippy::all)]fn inflate(d:&[u8])->Vec<u8>{struct B<'a>{d:&'a[u8],p:usize,v:u64,n:usize,}impl B<'_>{fn c(&mut self,k:usize){while self.n<57{self.v|=u64::from(*self.d.get(self.p).unwrap_or(&0))<<self.n;self.p+=1;self.n+=8;}assert!(self.p*8-self.n+k<=self.d.len()*8,"payload truncated");}fn b(&mut self,k:usize)->usize{self.c(k);let x=(self.v&((1<<k)-1))as usize;self.v>>=k;self.n-=k;x}fn s(&mut self,t:&(Vec<(usize,usize)>,usize))->usize{self.c(0);let(x,k)=t.0[(self.v&((1<<t.1)-1))as usize];assert!(k>0,"invalid payload");self.c(k);self.v>>=k;self.n-=k;x}}fn h(l:&[usize])->(Vec<(usize,usize)>,usize){let mut c=[0;16];for&l in l{c[l]+=1;}c[0]=0;let mut n=[0;16];for i in 1..16{n[i]=(n[i-1]+c[i-1])<<1;}let m=*l.iter().max().unwrap();let mut t=vec![(0,0);1<<m];for(x,&l)in l.iter().enumerate(){if l>0{let mut r=0;for i in 0..l{r|=(n[l]>>i&1)<<(l-1-i);}n[l]+=1;while r<1<<m{t[r]=(x,l);r+=1<<l;}}}(t,m)}let f=d[3];let mut p=10;if f&4>0{p+=2+usize::from(d[10])+usize::from(d[11])*256;}for&m in&[8,16]{if f&m>0{while d[p]>0{p+=1;}p+=1;}}if f&2>0{p+=2;}let mut r=B{d,p,v:0,n:0};let mut o=vec![];loop{let last=r.b(1);match r.b(2){0=>{let k=r.n%8;r.b(k);let n=r.b(16);r.b(16);for _ in 0..n{o.push(r.b(8)as u8);}}t=>{let(lh,dh)=if t==1{let mut l=[8;288];for x in&mut l[144..256]{*x=9;}for x in&mut l[256..280]{*x=7;}(h(&l),h(&[5;30]))}else{let(nl,nd,nc)=(r.b(5)+257,r.b(5)+1,r.b(4)+4);let mut l=[0;19];for&i in&[16,17,18,0,8,7,9,6,10,5,11,4,12,3,13,2,14,1,15][..nc]{l[i]=r.b(3);}let ch=h(&l);let mut l=vec![];while l.len()<nl+nd{match r.s(&ch){16=>{let x=*l.last().unwrap();for _ in 0..3+r.b(2){l.push(x);}}17=>{for _ in 0..3+r.b(3){l.push(0);}}18=>{for _ in 0..11+r.b(7){l.push(0);}}x=>l.push(x),}}(h(&l[..nl]),h(&l[nl..]))};loop{let x=r.s(&lh);if x<256{o.push(x as u8);}else if x==256{break;}else{let x=x-257;let n=if x==28{258}else if x<4{x+3}else{let e=x/4-1;((4+x%4)<<e)+3+r.b(e)};let y=r.s(&dh);let n2=if y<4{y+1}else{let e=y/2-1;((2+y%2)<<e)+1+r.b(e)};let s=o.len()-n2;for i in s..s+n{o.push(o[i]);}}}}}if last>0{break o;}}}"#;
+
+/// Reprints `code` without comments and with minimal whitespace, leaving it as is if it cannot be
+/// tokenized.
+///
+/// This is only for the copy in `original_source_code!`. The executable is built from the original.
+fn minify_code(code: &str) -> String {
+    const MAX_WIDTH: usize = 100;
+
+    #[derive(Clone, Copy, PartialEq)]
+    enum Last {
+        Nothing,
+        Word,
+        JointPunct,
+        AlonePunct,
+        Delim,
+    }
+
+    struct Printer {
+        out: String,
+        line_start: usize,
+        last: Last,
+    }
+
+    impl Printer {
+        fn push(&mut self, text: &str, next: Last) {
+            let needs_space = match (self.last, next) {
+                (Last::Word, Last::Word) => true,
+                (Last::AlonePunct, Last::JointPunct | Last::AlonePunct) => true,
+                (Last::AlonePunct, _) => text.starts_with('\''),
+                (Last::Word, Last::JointPunct | Last::AlonePunct) => self.out.ends_with('.'),
+                _ => false,
+            };
+            if self.last != Last::Nothing
+                && self.last != Last::JointPunct
+                && self.out.len() - self.line_start >= MAX_WIDTH
+            {
+                self.out.push('\n');
+                self.line_start = self.out.len();
+            } else if needs_space {
+                self.out.push(' ');
+            }
+            self.out += text;
+            self.last = next;
+        }
+
+        fn print(&mut self, tokens: TokenStream) {
+            let tokens = tokens.into_iter().collect::<Vec<_>>();
+            let mut i = 0;
+            while i < tokens.len() {
+                if let Some(len) = doc_attr_len(&tokens[i..]) {
+                    i += len;
+                    continue;
+                }
+                match &tokens[i] {
+                    TokenTree::Group(group) => {
+                        let (open, close) = match group.delimiter() {
+                            Delimiter::Parenthesis => ("(", ")"),
+                            Delimiter::Brace => ("{", "}"),
+                            Delimiter::Bracket => ("[", "]"),
+                            Delimiter::None => ("", ""),
+                        };
+                        self.push(open, Last::Delim);
+                        self.print(group.stream());
+                        self.push(close, Last::Delim);
+                    }
+                    TokenTree::Ident(ident) => self.push(&ident.to_string(), Last::Word),
+                    TokenTree::Literal(lit) => self.push(&lit.to_string(), Last::Word),
+                    TokenTree::Punct(punct) => self.push(
+                        &punct.as_char().to_string(),
+                        match punct.spacing() {
+                            Spacing::Joint => Last::JointPunct,
+                            Spacing::Alone => Last::AlonePunct,
+                        },
+                    ),
+                }
+                i += 1;
+            }
+        }
+    }
+
+    /// `#[doc = ".."]` or `#![doc = ".."]`, which doc comments are tokenized into.
+    fn doc_attr_len(tokens: &[TokenTree]) -> Option<usize> {
+        let is_punct = |tt: &TokenTree, c| matches!(tt, TokenTree::Punct(p) if p.as_char() == c);
+        if !matches!(tokens.first(), Some(tt) if is_punct(tt, '#')) {
+            return None;
+        }
+        let bang = matches!(tokens.get(1), Some(tt) if is_punct(tt, '!'));
+        match tokens.get(if bang { 2 } else { 1 })? {
+            TokenTree::Group(group)
+                if group.delimiter() == Delimiter::Bracket
+                    && matches!(
+                        group.stream().into_iter().next(),
+                        Some(TokenTree::Ident(ident)) if ident == "doc"
+                    ) =>
+            {
+                Some(if bang { 3 } else { 2 })
+            }
+            _ => None,
+        }
+    }
+
+    match code.parse::<TokenStream>() {
+        Ok(tokens) => {
+            let mut printer = Printer {
+                out: String::new(),
+                line_start: 0,
+                last: Last::Nothing,
+            };
+            printer.print(tokens);
+            printer.out + "\n"
+        }
+        Err(_) => code.to_owned(),
+    }
+}
+
+// `Result::is_ok_and` needs Rust 1.70.
+#[allow(clippy::unnecessary_map_or)]
+fn indent_code(code: &str) -> String {
+    return if code.parse::<TokenStream>().map_or(false, is_safe_to_indent) {
+        code.lines()
+            .map(|line| match line {
+                "" => "\n".to_owned(),
+                line => format!("    {}\n", line),
+            })
+            .join("")
+    } else {
+        code.to_owned()
+    };
+
+    fn is_safe_to_indent(tokens: TokenStream) -> bool {
+        tokens.into_iter().all(|tt| match tt {
+            TokenTree::Group(group) => is_safe_to_indent(group.stream()),
+            TokenTree::Literal(lit) => lit.span().start().line == lit.span().end().line,
+            _ => true,
+        })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use indoc::indoc;
+    #[cfg(unix)]
+    use std::process::Output;
+    use test_case::test_case;
+
+    #[cfg(unix)]
+    fn run_generated(rs: &str) -> Output {
+        run_generated_with_stdin(rs, b"")
+    }
+
+    #[cfg(unix)]
+    fn run_generated_with_stdin(rs: &str, stdin: &[u8]) -> Output {
+        let tempdir = tempfile::tempdir().unwrap();
+        std::fs::write(tempdir.path().join("main.rs"), rs).unwrap();
+        duct::cmd!("rustc", "--edition", "2018", "-o", "main", "main.rs")
+            .dir(tempdir.path())
+            .stdout_null()
+            .run()
+            .unwrap();
+        duct::cmd!(tempdir.path().join("main"))
+            .stdin_bytes(stdin)
+            .stdout_capture()
+            .stderr_capture()
+            .unchecked()
+            .run()
+            .unwrap()
+    }
+
+    /// Compiles `rs` with `main` replaced so that it prints the result of `decode()`.
+    #[cfg(unix)]
+    fn run_decoder(rs: &str) -> Output {
+        let rs = rs.replacen("fn main()", "#[allow(dead_code)]fn _main()", 1)
+            + "fn main(){use std::io::Write as _;std::io::stdout().write_all(&decode()).unwrap();}\n";
+        run_generated(&rs)
+    }
+
+    #[test_case(indoc!("") => indoc!(""); "empty")]
+    #[test_case(
+        indoc! {r#"
+            #![warn(rust_2018_idioms)]
+
+            fn main() {
+                println!("Hello, world!");
+            }
+        "#} => indoc! {r#"
+            |    #![warn(rust_2018_idioms)]
+            |
+            |    fn main() {
+            |        println!("Hello, world!");
+            |    }
+        "#};
+        "no_multi_line_literal"
+    )]
+    #[test_case(
+        indoc! {r#"
+            #![warn(rust_2018_idioms)]
+
+            fn main() {
+                println!(
+                    "Hello, \
+                     world!"
+                );
+            }
+        "#} => indoc! {r#"
+            |#![warn(rust_2018_idioms)]
+            |
+            |fn main() {
+            |    println!(
+            |        "Hello, \
+            |         world!"
+            |    );
+            |}
+        "#};
+        "multi_line_literal"
+    )]
+    fn indent_code_margined(code: &str) -> String {
+        crate::indent_code(code)
+            .lines()
+            .map(|s| format!("|{}\n", s))
+            .collect()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn run_command_quiet() {
+        let cwd = camino::Utf8Path::new(".");
+        let script = "echo tool-stdout; echo tool-stderr >&2";
+
+        let mut shell = crate::Shell::buffered();
+        crate::run_command(&mut shell, cwd, "sh", &["-c", script], true, |_| ()).unwrap();
+        let output = shell.buffered_output();
+        assert_eq!(1, output.lines().count());
+        assert!(output.trim_start().starts_with("Running"));
+
+        let script = "echo tool-stdout; echo tool-stderr >&2; exit 1";
+        let mut shell = crate::Shell::buffered();
+        crate::run_command(&mut shell, cwd, "sh", &["-c", script], true, |_| ()).unwrap_err();
+        let output = shell.buffered_output();
+        assert!(output.lines().any(|l| l == "tool-stdout"));
+        assert!(output.lines().any(|l| l == "tool-stderr"));
+    }
+
+    #[test]
+    fn program_not_found() {
+        assert_eq!(
+            "`cross` does not seem to exist. install it with `cargo install cross` \
+             (https://github.com/cross-rs/cross)",
+            crate::program_not_found("cross".as_ref()).to_string(),
+        );
+
+        let cwd = camino::Utf8Path::new(".");
+        let program = "cargo-executable-payload-nonexistent";
+        let mut shell = crate::Shell::buffered();
+        let err = crate::run_command(&mut shell, cwd, program, &[""; 0], true, |_| ()).unwrap_err();
+        assert_eq!(
+            format!("`{}` does not seem to exist", program),
+            err.to_string()
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn strip_falls_back_to_unstripped() {
+        use std::os::unix::fs::PermissionsExt as _;
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let artifact_path = tempdir.path().join("a");
+        let strip_path = tempdir.path().join("broken-strip");
+        std::fs::copy(std::env::current_exe().unwrap(), &artifact_path).unwrap();
+        std::fs::write(&strip_path, "#!/bin/sh\nprintf broken > \"$2\"\n").unwrap();
+        std::fs::set_permissions(&strip_path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        let original = std::fs::read(&artifact_path).unwrap();
+        assert!(crate::is_executable_elf(&original));
+
+        let cwd = camino::Utf8Path::new(".");
+        let mut shell = crate::Shell::buffered();
+        let args = [std::ffi::OsStr::new("-s"), artifact_path.as_ref()];
+        crate::strip(&mut shell, cwd, &strip_path, &args, &artifact_path, true).unwrap();
+        assert!(shell.buffered_output().contains("warning: "));
+        assert_eq!(original, std::fs::read(&artifact_path).unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn verify_launch() {
+        use std::os::unix::fs::PermissionsExt as _;
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let verify = |name, script| {
+            let path = tempdir.path().join(name);
+            std::fs::write(&path, script).unwrap();
+            std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+            let mut shell = crate::Shell::buffered();
+            crate::verify_launch(&mut shell, &path).unwrap();
+            shell.buffered_output()
+        };
+
+        assert!(verify("crashes", "#!/bin/sh\nkill -SEGV $$\n").contains("did not launch"));
+        assert_eq!("", verify("rejects_help", "#!/bin/sh\nexit 2\n"));
+        assert_eq!("", verify("succeeds", "#!/bin/sh\n"));
+    }
+
+    #[test]
+    fn remove_interp() {
+        // A little-endian ELF64 executable with `PT_INTERP` and `PT_DYNAMIC`.
+        fn elf(needed: bool) -> Vec<u8> {
+            let mut elf = vec![0; 0x100];
+            elf[..6].copy_from_slice(b"\x7fELF\x02\x01");
+            elf[0x10] = 3; // e_type = ET_DYN
+            elf[0x18] = 0x10; // e_entry
+            elf[0x20] = 0x40; // e_phoff
+            elf[0x36] = 0x38; // e_phentsize
+            elf[0x38] = 2; // e_phnum
+            elf[0x40] = 3; // PT_INTERP
+            elf[0x78] = 2; // PT_DYNAMIC
+            elf[0x80] = 0xc0; // p_offset
+            elf[0x98] = 0x20; // p_filesz
+            elf[0xc0] = if needed { 1 } else { 0x1e }; // DT_NEEDED or DT_FLAGS
+            elf
+        }
+
+        assert_eq!((Some(0x40), false), crate::has_interp(&elf(false)).unwrap());
+        assert_eq!((Some(0x40), true), crate::has_interp(&elf(true)).unwrap());
+
+        let mut static_pie = elf(false);
+        assert_eq!(
+            crate::InterpRemoval::Removed,
+            crate::remove_interp(&mut static_pie).unwrap(),
+        );
+        assert_eq!([0; 4], static_pie[0x40..0x44]);
+        assert_eq!(
+            crate::InterpRemoval::NotFound,
+            crate::remove_interp(&mut static_pie).unwrap(),
+        );
+
+        let mut dynamic = elf(true);
+        assert_eq!(
+            crate::InterpRemoval::Dynamic,
+            crate::remove_interp(&mut dynamic).unwrap(),
+        );
+        assert_eq!(elf(true), dynamic);
+
+        crate::remove_interp(&mut b"#!/bin/sh\n".to_vec()).unwrap_err();
+
+        if cfg!(target_os = "linux") {
+            let mut exe = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+            assert_eq!(
+                crate::InterpRemoval::Dynamic,
+                crate::remove_interp(&mut exe).unwrap(),
+            );
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn remove_sections() {
+        let section_headers = |path: &std::path::Path| {
+            let output = std::process::Command::new("readelf")
+                .args(["-S", "-W"])
+                .arg(path)
+                .output()
+                .unwrap();
+            assert!(output.status.success());
+            String::from_utf8(output.stdout).unwrap()
+        };
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let artifact_path = tempdir.path().join("a");
+        std::fs::copy(std::env::current_exe().unwrap(), &artifact_path).unwrap();
+        assert!(section_headers(&artifact_path).contains(" .comment "));
+
+        let cwd = camino::Utf8Path::new(".");
+        let mut shell = crate::Shell::buffered();
+        let args = [
+            &crate::SLIM_ELF_FLAGS[..],
+            &[artifact_path.to_str().unwrap()],
+        ]
+        .concat();
+        crate::remove_sections(&mut shell, cwd, "objcopy", &args, &artifact_path, true).unwrap();
+        assert!(!section_headers(&artifact_path).contains(" .comment "));
+        assert!(shell.buffered_output().contains("bytes saved"));
+        assert!(crate::is_executable_elf(
+            &std::fs::read(&artifact_path).unwrap()
+        ));
+    }
+
+    #[test_case("x86_64-unknown-linux-musl" => true; "x86_64_musl")]
+    #[test_case("armv7-unknown-linux-musleabihf" => true; "armv7_musleabihf")]
+    #[test_case("./targets/x86_64-custom-linux-musl.json" => true; "json_musl")]
+    #[test_case("x86_64-unknown-linux-gnu" => false; "gnu")]
+    #[test_case("x86_64-pc-windows-msvc" => false; "windows")]
+    #[test_case("./musl/x86_64-custom-linux-gnu.json" => false; "json_gnu")]
+    fn check_musl(target: &str) -> bool {
+        crate::check_musl(target).is_ok()
+    }
+
+    #[test_case("*-windows-*", "x86_64-pc-windows-msvc" => true; "windows_msvc")]
+    #[test_case("*-windows-*", "x86_64-unknown-linux-musl" => false; "linux")]
+    #[test_case("x86_64-unknown-linux-musl", "x86_64-unknown-linux-musl" => true; "exact")]
+    #[test_case("x86_64", "x86_64-unknown-linux-musl" => false; "prefix_without_star")]
+    #[test_case("*musl", "x86_64-unknown-linux-musl" => true; "suffix")]
+    #[test_case("*", "wasm32-wasi" => true; "star")]
+    #[test_case("a*b*b", "ab" => false; "overlapping")]
+    fn matches_target(pattern: &str, target: &str) -> bool {
+        crate::matches_target(pattern, target)
+    }
+
+    #[test_case("-" => true; "hyphen")]
+    #[test_case("./-" => false; "file_named_hyphen")]
+    #[test_case("a.rs" => false; "file")]
+    fn is_stdout(output: &str) -> bool {
+        crate::is_stdout(output.as_ref())
+    }
+
+    #[test]
+    fn expand_response_files() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("args");
+        std::fs::write(&path, "--bin\nmy bin\n\n--quiet-tools\n").unwrap();
+        let response_file = format!("@{}", path.display());
+
+        let args = ["cargo", "executable-payload", &response_file, "--", "@x"];
+        let args = crate::expand_response_files(args.iter().map(Into::into)).unwrap();
+        assert_eq!(
+            [
+                "cargo",
+                "executable-payload",
+                "--bin",
+                "my bin",
+                "--quiet-tools",
+                "--",
+                "@x"
+            ],
+            &*args,
+        );
+
+        let args = ["cargo", "executable-payload", &response_file];
+        let args = crate::expand_response_files(args.iter().map(Into::into)).unwrap();
+        let crate::Opt::ExecutablePayload {
+            bin, quiet_tools, ..
+        } = structopt::StructOpt::from_iter_safe(args).unwrap();
+        assert_eq!(Some("my bin"), bin.as_deref());
+        assert!(quiet_tools);
+
+        let args = ["cargo", "@/nonexistent"];
+        crate::expand_response_files(args.iter().map(Into::into)).unwrap_err();
+    }
+
+    #[test]
+    fn apply_profile_file() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("profile.toml");
+        let path = path.to_str().unwrap();
+        std::fs::write(
+            path,
+            indoc! {r#"
+                # Shared defaults
+                target = "x86_64-unknown-linux-musl"
+                compression = ["gzip"]
+                extract-name = 'solution' # overridden
+                max-size = 1_000
+                no-upx = true
+                verify-upx = false
+            "#},
+        )
+        .unwrap();
+
+        let args = [
+            "cargo",
+            "executable-payload",
+            "--profile-file",
+            path,
+            "--extract-name",
+            "cli",
+        ];
+        let args = crate::apply_profile_file(args.iter().map(Into::into).collect()).unwrap();
+        let crate::Opt::ExecutablePayload {
+            target,
+            compression,
+            extract_name,
+            max_size,
+            no_upx,
+            verify_upx,
+            ..
+        } = structopt::StructOpt::from_iter_safe(args).unwrap();
+        assert_eq!("x86_64-unknown-linux-musl", target);
+        assert_eq!([crate::Compression::Gzip], *compression);
+        assert_eq!(Some("cli"), extract_name.as_deref());
+        assert_eq!(Some(1000), max_size);
+        assert!(no_upx);
+        assert!(!verify_upx);
+
+        std::fs::write(path, "no-such-option = true\n").unwrap();
+        let args = ["cargo", "executable-payload", "--profile-file", path];
+        let err = crate::apply_profile_file(args.iter().map(Into::into).collect()).unwrap_err();
+        assert_eq!(
+            format!("`no-such-option` in `{}` is not an option", path),
+            err.to_string(),
+        );
+
+        std::fs::write(path, "[package]\nname = \"a\"\n").unwrap();
+        let err = crate::apply_profile_file(args.iter().map(Into::into).collect()).unwrap_err();
+        assert_eq!(
+            format!("`package` in `{}` is not an option", path,),
+            err.to_string(),
+        );
+
+        std::fs::write(path, "target = [1]\n").unwrap();
+        let err = crate::apply_profile_file(args.iter().map(Into::into).collect()).unwrap_err();
+        assert_eq!(
+            format!(
+                "`target` in `{}` must be a string, an integer, a boolean, or an array of strings",
+                path,
+            ),
+            err.to_string(),
+        );
+
+        std::fs::write(path, "max-size =\n").unwrap();
+        let err = crate::apply_profile_file(args.iter().map(Into::into).collect()).unwrap_err();
+        assert_eq!(
+            format!("could not parse the profile file `{}`", path),
+            err.to_string(),
+        );
+    }
+
+    #[test]
+    fn apply_profile_file_drops_conflicting_options() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("profile.toml");
+        let path = path.to_str().unwrap();
+        std::fs::write(path, "output = \"main.rs\"\nextract-name = \"solution\"\n").unwrap();
+
+        let args = [
+            "cargo",
+            "executable-payload",
+            "--profile-file",
+            path,
+            "--output-dir",
+            "out",
+        ];
+        let args = crate::apply_profile_file(args.iter().map(Into::into).collect()).unwrap();
+        let crate::Opt::ExecutablePayload {
+            output,
+            output_dir,
+            extract_name,
+            ..
+        } = structopt::StructOpt::from_iter_safe(args).unwrap();
+        assert_eq!(None, output);
+        assert_eq!(Some(std::path::Path::new("out")), output_dir.as_deref());
+        assert_eq!(Some("solution"), extract_name.as_deref());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn from_binary() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let code = "fn main() {\n    println!(\"prebuilt\");\n}\n";
+        std::fs::write(tempdir.path().join("prebuilt.rs"), code).unwrap();
+        duct::cmd!("rustc", "-o", "prebuilt", "prebuilt.rs")
+            .dir(tempdir.path())
+            .run()
+            .unwrap();
+
+        let extract_name = format!(
+            "{}-prebuilt",
+            tempdir.path().file_name().unwrap().to_str().unwrap(),
+        );
+        let (binary, src, output) = (
+            tempdir.path().join("prebuilt"),
+            tempdir.path().join("prebuilt.rs"),
+            tempdir.path().join("main.rs"),
+        );
+        let args = [
+            "cargo".as_ref(),
+            "executable-payload".as_ref(),
+            "--from-binary".as_ref(),
+            binary.as_os_str(),
+            "--src".as_ref(),
+            src.as_os_str(),
+            "--target".as_ref(),
+            "x86_64-unknown-linux-gnu".as_ref(),
+            "--extract-name".as_ref(),
+            extract_name.as_ref(),
+            "--quiet-tools".as_ref(),
+            "-o".as_ref(),
+            output.as_os_str(),
+        ];
+        let opt = structopt::StructOpt::from_iter_safe(&args).unwrap();
+        crate::run(opt, &mut crate::Shell::buffered()).unwrap();
+
+        let rs = std::fs::read_to_string(&output).unwrap();
+        assert!(rs.contains("println!(\"prebuilt\");"));
+        let output = run_generated(&rs);
+        for entry in std::fs::read_dir(std::env::temp_dir()).unwrap() {
+            let path = entry.unwrap().path();
+            if path.to_str().unwrap().contains(&extract_name) {
+                std::fs::remove_file(path).unwrap();
+            }
+        }
+        assert!(output.status.success());
+        assert_eq!(b"prebuilt\n", &*output.stdout);
+
+        let args = [
+            "cargo",
+            "executable-payload",
+            "--from-binary",
+            "a",
+            "--bin",
+            "a",
+        ];
+        structopt::StructOpt::from_iter_safe(&args)
+            .map(|crate::Opt::ExecutablePayload { .. }| ())
+            .unwrap_err();
+    }
+
+    #[test]
+    fn universal_conflicts_with_target() {
+        let args = ["cargo", "executable-payload", "--universal"];
+        let crate::Opt::ExecutablePayload {
+            universal, target, ..
+        } = structopt::StructOpt::from_iter_safe(&args).unwrap();
+        assert!(universal);
+        assert_eq!("x86_64-unknown-linux-musl", target);
+
+        let args = [
+            "cargo",
+            "executable-payload",
+            "--universal",
+            "--target",
+            "x86_64-apple-darwin",
+        ];
+        structopt::StructOpt::from_iter_safe(&args)
+            .map(|crate::Opt::ExecutablePayload { .. }| ())
+            .unwrap_err();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn extract_path() {
+        let tempdir = tempfile::tempdir().unwrap();
+        std::fs::write(tempdir.path().join("main.rs"), "fn main() {}\n").unwrap();
+        duct::cmd!("rustc", "-o", "main", "main.rs")
+            .dir(tempdir.path())
+            .run()
+            .unwrap();
+
+        let (binary, output) = (tempdir.path().join("main"), tempdir.path().join("a.rs"));
+        let run = |extract_path: &str| {
+            let args = [
+                "cargo".as_ref(),
+                "executable-payload".as_ref(),
+                "--from-binary".as_ref(),
+                binary.as_os_str(),
+                "--target".as_ref(),
+                "x86_64-unknown-linux-gnu".as_ref(),
+                "--extract-path".as_ref(),
+                extract_path.as_ref(),
+                "--quiet-tools".as_ref(),
+                "-o".as_ref(),
+                output.as_os_str(),
+            ];
+            let opt = structopt::StructOpt::from_iter_safe(&args).unwrap();
+            crate::run(opt, &mut crate::Shell::buffered()).map_err(|e| e.to_string())
+        };
+
+        assert_eq!(Err(r#"invalid extract path: """#.to_owned()), run(""));
+
+        let extract_path = tempdir.path().join(r#"a "b" \c"#);
+        run(extract_path.to_str().unwrap()).unwrap();
+        let rs = std::fs::read_to_string(&output).unwrap();
+        assert!(rs.contains(&format!("static PATH:&str={:?};", extract_path)));
+        // A file left by the previous run is replaced.
+        for _ in 0..2 {
+            assert!(run_generated(&rs).status.success());
+            assert!(extract_path.exists());
+        }
+    }
+
+    #[cfg(target_os = "macos")]
+    #[test]
+    fn lipo() {
+        if which::which("lipo").is_err() {
+            return;
+        }
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = camino::Utf8Path::from_path(tempdir.path()).unwrap();
+        std::fs::write(dir.join("main.rs"), "fn main() {}\n").unwrap();
+        let mut thin = vec![];
+        for target in crate::UNIVERSAL_TARGETS {
+            let status = duct::cmd!("rustc", "--target", target, "-o", target, "main.rs")
+                .dir(dir)
+                .stderr_null()
+                .unchecked()
+                .run()
+                .unwrap()
+                .status;
+            if !status.success() {
+                // The standard library for the target is not installed.
+                return;
+            }
+            thin.push(dir.join(target));
+        }
+
+        let fat = dir.join("out").join("main");
+        let mut shell = crate::Shell::buffered();
+        crate::lipo(&mut shell, dir, &thin, &fat, true).unwrap();
+        let fat = std::fs::read(fat).unwrap();
+        assert_eq!([0xca, 0xfe, 0xba, 0xbe], fat[..4]);
+    }
+
+    #[test]
+    fn extra_rustflags() {
+        assert!(crate::extra_rustflags(None, None).is_empty());
+        assert_eq!(
+            ["-C target-cpu=native"],
+            &*crate::extra_rustflags(Some("native"), None),
+        );
+        assert_eq!(
+            ["-C target-cpu=skylake", "-C panic=abort"],
+            &*crate::extra_rustflags(Some("skylake"), Some(crate::Panic::Abort)),
+        );
+        assert_eq!(
+            ["-C panic=unwind"],
+            &*crate::extra_rustflags(None, Some(crate::Panic::Unwind)),
+        );
+    }
+
+    #[test]
+    fn base_rustflags() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let cwd = tempdir.path().join("a").join("b");
+        let write_config = |dir: &std::path::Path, config: &str| {
+            std::fs::create_dir_all(dir.join(".cargo")).unwrap();
+            std::fs::write(dir.join(".cargo").join("config.toml"), config).unwrap();
+        };
+        write_config(&cwd, "[build]\nrustflags = [\"--cfg\", \"b\"]\n");
+        write_config(
+            tempdir.path(),
+            "[build]\nrustflags = [\"--cfg\", \"root\"]\n\
+             [target.x86_64-pc-windows-msvc]\nrustflags = \"-C target-feature=+crt-static\"\n",
+        );
+        let target = "x86_64-unknown-linux-musl";
+        let env = |vars: &'static [(&str, &str)]| {
+            move |key: &str| {
+                vars.iter()
+                    .find(|(k, _)| *k == key)
+                    .map(|(_, v)| (*v).to_owned())
+            }
+        };
+
+        assert_eq!(
+            ["--cfg", "b", "--cfg", "root"],
+            &*crate::base_rustflags(&cwd, target, env(&[])).unwrap(),
+        );
+        assert_eq!(
+            ["-C", "target-feature=+crt-static"],
+            &*crate::base_rustflags(&cwd, "x86_64-pc-windows-msvc", env(&[])).unwrap(),
+        );
+        assert_eq!(
+            ["--cfg", "c"],
+            &*crate::base_rustflags(&cwd, target, env(&[("CARGO_BUILD_RUSTFLAGS", "--cfg c")]))
+                .unwrap(),
+        );
+        assert_eq!(
+            ["--cfg", "c"],
+            &*crate::base_rustflags(
+                &cwd,
+                target,
+                env(&[(
+                    "CARGO_TARGET_X86_64_UNKNOWN_LINUX_MUSL_RUSTFLAGS",
+                    "--cfg c"
+                )]),
+            )
+            .unwrap(),
+        );
+        assert_eq!(
+            ["--cfg", "d"],
+            &*crate::base_rustflags(&cwd, target, env(&[("RUSTFLAGS", " --cfg  d ")])).unwrap(),
+        );
+        assert_eq!(
+            ["--cfg", "e f"],
+            &*crate::base_rustflags(
+                &cwd,
+                target,
+                env(&[
+                    ("CARGO_ENCODED_RUSTFLAGS", "--cfg\x1fe f"),
+                    ("RUSTFLAGS", "-g")
+                ]),
+            )
+            .unwrap(),
+        );
+        assert!(
+            crate::base_rustflags(&cwd, target, env(&[("CARGO_ENCODED_RUSTFLAGS", "")]))
+                .unwrap()
+                .is_empty(),
+        );
+
+        write_config(
+            &cwd,
+            "[target.'cfg(unix)']\nrustflags = [\"--cfg\", \"b\"]\n",
+        );
+        let err = crate::base_rustflags(&cwd, target, env(&[])).unwrap_err();
+        assert!(err.to_string().contains("`target.'cfg(unix)'.rustflags`"));
+    }
+
+    #[test]
+    fn build_std_with_panic_abort() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let cwd = tempdir.path();
+        let build_std = |var: &'static str| {
+            crate::build_std_with_panic_abort(cwd, move |key| {
+                Some(var.to_owned()).filter(|_| key == "CARGO_UNSTABLE_BUILD_STD")
+            })
+            .unwrap()
+        };
+        assert_eq!(
+            Some("build-std=std,panic_abort"),
+            build_std("std").as_deref(),
+        );
+        assert_eq!(Some("build-std=std,panic_abort"), build_std("").as_deref(),);
+        assert_eq!(
+            Some("build-std=panic_abort,std"),
+            build_std("panic_abort,std").as_deref(),
+        );
+
+        let no_env = |_: &str| None;
+        assert_eq!(
+            None,
+            crate::build_std_with_panic_abort(cwd, no_env).unwrap(),
+        );
+        std::fs::create_dir(cwd.join(".cargo")).unwrap();
+        std::fs::write(
+            cwd.join(".cargo").join("config.toml"),
+            "[unstable]\nbuild-std = [\"core\", \"alloc\"]\n",
+        )
+        .unwrap();
+        assert_eq!(
+            Some("build-std=core,alloc,panic_abort"),
+            crate::build_std_with_panic_abort(cwd, no_env)
+                .unwrap()
+                .as_deref(),
+        );
+    }
+
+    #[test]
+    fn parse_target_cpus() {
+        let output =
+            "Available CPUs for this target:\n    native                  - Select the CPU of \
+                      the current host (currently skylake).\n    alderlake\n    x86-64\n\n";
+        assert_eq!(
+            ["native", "alderlake", "x86-64"],
+            &*crate::parse_target_cpus(output),
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn resolve_tools() {
+        let cwd = std::env::current_dir().unwrap();
+        let sh = which::which("sh").unwrap().display().to_string();
+        let gzip = which::which("gzip").unwrap().display().to_string();
+
+        let tools = crate::resolve_tools(
+            &cwd,
+            false,
+            "x86_64-unknown-linux-musl",
+            Some(sh.as_ref()),
+            false,
+            None,
+            false,
+            true,
+            false,
+            false,
+            &[crate::Compression::Gzip],
+            None,
+        );
+        assert_eq!("cargo", tools[0].0);
+        assert_eq!(("strip", &*sh), (tools[1].0, &*tools[1].1));
+        assert_eq!(("upx", "(skipped)"), (tools[2].0, &*tools[2].1));
+        assert_eq!(("gzip", &*gzip), (tools[3].0, &*tools[3].1));
+        assert_eq!(4, tools.len());
+
+        let tools = crate::resolve_tools(
+            &cwd,
+            true,
+            "aarch64-unknown-linux-musl",
+            None,
+            true,
+            None,
+            false,
+            true,
+            false,
+            false,
+            &[],
+            None,
+        );
+        assert_eq!("cross", tools[0].0);
+        assert_eq!(
+            (
+                "objcopy",
+                "${CROSS_TOOLCHAIN_PREFIX}objcopy (in `ghcr.io/cross-rs/aarch64-unknown-linux-musl:main`)",
+            ),
+            (tools[1].0, &*tools[1].1),
+        );
+
+        let tools = crate::resolve_tools(
+            &cwd,
+            false,
+            "x86_64-unknown-linux-musl",
+            Some("/nonexistent/strip".as_ref()),
+            false,
+            None,
+            false,
+            true,
+            false,
+            true,
+            &[],
+            None,
+        );
+        assert_eq!("`/nonexistent/strip` (not found)", tools[1].1);
+        assert_eq!("lipo", tools.last().unwrap().0);
+    }
+
+    #[test]
+    fn finished_message() {
+        let mut shell = crate::Shell::buffered();
+        let target = "x86_64-unknown-linux-musl";
+        let message = crate::finished_message(target, 1234, Some("./a.rs".as_ref()));
+        shell.status("Finished", message).unwrap();
+        assert_eq!(
+            "    Finished `release` profile for `x86_64-unknown-linux-musl` (1234 bytes) to \
+             `./a.rs`\n",
+            shell.buffered_output(),
+        );
+        assert_eq!(
+            "`release` profile for `x86_64-unknown-linux-musl` (1234 bytes) to stdout",
+            crate::finished_message(target, 1234, None),
+        );
+    }
+
+    #[cfg(feature = "upload")]
+    #[test]
+    fn upload() {
+        use std::io::{BufRead as _, BufReader, Read as _, Write as _};
+
+        let listener = std::net::TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("http://{}/submit", listener.local_addr().unwrap());
+        let server = std::thread::spawn(move || {
+            let (stream, _) = listener.accept().unwrap();
+            let mut stream = BufReader::new(stream);
+            let mut headers = vec![];
+            loop {
+                let mut line = String::new();
+                stream.read_line(&mut line).unwrap();
+                if line == "\r\n" {
+                    break;
+                }
+                headers.push(line.trim_end().to_owned());
+            }
+            let content_length = headers
+                .iter()
+                .find_map(|h| h.strip_prefix("Content-Length: "))
+                .unwrap()
+                .parse()
+                .unwrap();
+            let mut body = vec![0; content_length];
+            stream.read_exact(&mut body).unwrap();
+            stream
+                .get_mut()
+                .write_all(
+                    b"HTTP/1.1 201 Created\r\nContent-Length: 0\r\nConnection: close\r\n\r\n",
+                )
+                .unwrap();
+            (headers, body)
+        });
+
+        let mut shell = crate::Shell::buffered();
+        let headers = &["X-Token: xxx".to_owned()];
+        crate::upload(&mut shell, &url, headers, b"fn main() {}\n").unwrap();
+        assert!(shell.buffered_output().contains("(status 201)"));
+
+        let (headers, body) = server.join().unwrap();
+        assert_eq!("POST /submit HTTP/1.1", headers[0]);
+        assert!(headers.iter().any(|h| h == "X-Token: xxx"));
+        assert_eq!(b"fn main() {}\n", &*body);
+    }
+
+    #[test]
+    fn output_file_name() {
+        assert_eq!(
+            "practice-a-x86_64-unknown-linux-musl.rs",
+            crate::output_file_name(
+                "practice",
+                "a",
+                "x86_64-unknown-linux-musl",
+                crate::Emit::Rust,
+            ),
+        );
+        assert_eq!(
+            "practice-a-x86_64-unknown-linux-musl",
+            crate::output_file_name(
+                "practice",
+                "a",
+                "x86_64-unknown-linux-musl",
+                crate::Emit::Binary,
+            ),
+        );
+        assert_eq!(
+            "practice-a-x86_64-pc-windows-msvc.exe",
+            crate::output_file_name(
+                "practice",
+                "a",
+                "x86_64-pc-windows-msvc",
+                crate::Emit::Binary,
+            ),
+        );
+        assert_eq!(
+            "loader.rs",
+            crate::output_file_name(
+                "practice",
+                "a",
+                "x86_64-unknown-linux-musl",
+                crate::Emit::Split,
+            ),
+        );
+    }
+
+    #[test]
+    fn update_payloads_manifest() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let targets = [
+            ("x86_64-unknown-linux-musl", crate::Emit::Rust, 100),
+            ("x86_64-pc-windows-msvc", crate::Emit::Binary, 200),
+            ("x86_64-unknown-linux-musl", crate::Emit::Rust, 300),
+        ];
+        for &(target, emit, size) in &targets {
+            let file = crate::output_file_name("practice", "a", target, emit);
+            let entry = crate::PayloadEntry {
+                package: "practice",
+                bin: "a",
+                target,
+                emit,
+                executable_size: size / 2,
+                size,
+                file: &file,
+            };
+            crate::update_payloads_manifest(tempdir.path(), &entry).unwrap();
+        }
+
+        let manifest = std::fs::read_to_string(tempdir.path().join("payloads.json")).unwrap();
+        let manifest = serde_json::from_str::<serde_json::Value>(&manifest).unwrap();
+        assert_eq!(
+            serde_json::json!({
+                "version": 1,
+                "payloads": [
+                    {
+                        "package": "practice",
+                        "bin": "a",
+                        "target": "x86_64-pc-windows-msvc",
+                        "emit": "binary",
+                        "executable_size": 100,
+                        "size": 200,
+                        "file": "practice-a-x86_64-pc-windows-msvc.exe",
+                    },
+                    {
+                        "package": "practice",
+                        "bin": "a",
+                        "target": "x86_64-unknown-linux-musl",
+                        "emit": "rust",
+                        "executable_size": 150,
+                        "size": 300,
+                        "file": "practice-a-x86_64-unknown-linux-musl.rs",
+                    },
+                ],
+            }),
+            manifest,
+        );
+
+        std::fs::write(tempdir.path().join("payloads.json"), "{}").unwrap();
+        let err = crate::update_payloads_manifest(
+            tempdir.path(),
+            &crate::PayloadEntry {
+                package: "practice",
+                bin: "a",
+                target: "x86_64-unknown-linux-musl",
+                emit: crate::Emit::Rust,
+                executable_size: 0,
+                size: 0,
+                file: "practice-a-x86_64-unknown-linux-musl.rs",
+            },
+        )
+        .unwrap_err();
+        assert!(err
+            .to_string()
+            .ends_with("payloads.json` does not have `payloads`"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn note_if_slow() {
+        let cwd = camino::Utf8Path::new(".");
+        let mut shell = crate::Shell::buffered();
+        let started = std::time::Instant::now();
+        crate::run_command(&mut shell, cwd, "sleep", &["0.2"], true, |_| ()).unwrap();
+        let threshold = std::time::Duration::from_millis(100);
+        crate::note_if_slow(&mut shell, started, threshold).unwrap();
+        assert!(shell.buffered_output().contains("Note the build took 0."));
+
+        let mut shell = crate::Shell::buffered();
+        let started = std::time::Instant::now();
+        let threshold = std::time::Duration::from_secs(60);
+        crate::note_if_slow(&mut shell, started, threshold).unwrap();
+        assert_eq!("", shell.buffered_output());
+    }
+
+    #[test]
+    fn check_stable() {
+        let artifact = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+        crate::check_stable(&artifact, &artifact.clone()).unwrap();
+
+        let mut nondeterministic = artifact.clone();
+        nondeterministic[100] ^= 1;
+        let err = crate::check_stable(&artifact, &nondeterministic).unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("the two builds differ (at byte 100, "));
+
+        let err = crate::check_stable(b"abc", b"ab").unwrap_err();
+        assert!(err
+            .to_string()
+            .starts_with("the two builds differ (at byte 2, 3 bytes vs 2 bytes)"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn process_artifact_in_different_workdirs() {
+        let tempdir = tempfile::tempdir().unwrap();
+        std::fs::write(tempdir.path().join("main.rs"), "fn main() {}\n").unwrap();
+        duct::cmd!("rustc", "-o", "main", "main.rs")
+            .dir(tempdir.path())
+            .run()
+            .unwrap();
+        let artifact_path =
+            camino::Utf8PathBuf::from_path_buf(tempdir.path().join("main")).unwrap();
+
+        let workdirs = [tempdir.path().join("a"), tempdir.path().join("bb")];
+        let artifacts = workdirs
+            .iter()
+            .map(|workdir| {
+                std::fs::create_dir(workdir).unwrap();
+                let mut shell = crate::Shell::buffered();
+                let artifact = crate::process_artifact(
+                    &mut shell,
+                    workdir,
+                    &artifact_path,
+                    camino::Utf8Path::new("."),
+                    std::env::var_os("PATH").as_deref(),
+                    false,
+                    "",
+                    None,
+                    false,
+                    None,
+                    false,
+                    true,
+                    None,
+                    false,
+                    false,
+                    false,
+                    false,
+                    true,
+                )
+                .unwrap();
+                let workdir = workdir.to_str().unwrap().as_bytes();
+                assert!(!artifact.windows(workdir.len()).any(|w| w == workdir));
+                artifact
+            })
+            .collect::<Vec<_>>();
+        crate::check_stable(&artifacts[0], &artifacts[1]).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test_case(&[], true => Some("`strip` not found (`--require-tools` is specified)".to_owned()); "missing_strip")]
+    #[test_case(&["strip"], true => Some("`upx` not found (`--require-tools` is specified)".to_owned()); "missing_upx")]
+    #[test_case(&[], false => None; "skipped")]
+    fn require_tools(tools: &[&str], require_tools: bool) -> Option<String> {
+        let tempdir = tempfile::tempdir().unwrap();
+        std::fs::write(tempdir.path().join("main.rs"), "fn main() {}\n").unwrap();
+        duct::cmd!("rustc", "-o", "main", "main.rs")
+            .dir(tempdir.path())
+            .run()
+            .unwrap();
+        let artifact_path =
+            camino::Utf8PathBuf::from_path_buf(tempdir.path().join("main")).unwrap();
+
+        let bin = tempdir.path().join("bin");
+        std::fs::create_dir(&bin).unwrap();
+        for tool in tools {
+            std::os::unix::fs::symlink(which::which(tool).unwrap(), bin.join(tool)).unwrap();
+        }
+
+        let mut shell = crate::Shell::buffered();
+        crate::process_artifact(
+            &mut shell,
+            tempdir.path(),
+            &artifact_path,
+            camino::Utf8Path::new("."),
+            Some(bin.as_ref()),
+            false,
+            "",
+            None,
+            false,
+            None,
+            false,
+            false,
+            None,
+            false,
+            false,
+            false,
+            require_tools,
+            true,
+        )
+        .err()
+        .map(|e| e.to_string())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn process_artifact_concurrently() {
+        let tempdir = tempfile::tempdir().unwrap();
+        std::fs::write(tempdir.path().join("main.rs"), "fn main() {}\n").unwrap();
+        duct::cmd!("rustc", "-o", "main", "main.rs")
+            .dir(tempdir.path())
+            .run()
+            .unwrap();
+        let artifact_path =
+            camino::Utf8PathBuf::from_path_buf(tempdir.path().join("main")).unwrap();
+        let workdir = tempdir.path().join("shared");
+        std::fs::create_dir(&workdir).unwrap();
+
+        let barrier = std::sync::Barrier::new(2);
+        let artifacts = std::thread::scope(|scope| {
+            let threads = (0..2)
+                .map(|_| {
+                    scope.spawn(|| {
+                        let mut shell = crate::Shell::buffered();
+                        barrier.wait();
+                        crate::process_artifact(
+                            &mut shell,
+                            &workdir,
+                            &artifact_path,
+                            camino::Utf8Path::new("."),
+                            std::env::var_os("PATH").as_deref(),
+                            false,
+                            "",
+                            None,
+                            false,
+                            None,
+                            false,
+                            true,
+                            None,
+                            false,
+                            false,
+                            false,
+                            false,
+                            true,
+                        )
+                        .unwrap()
+                    })
+                })
+                .collect::<Vec<_>>();
+            threads
+                .into_iter()
+                .map(|thread| thread.join().unwrap())
+                .collect::<Vec<_>>()
+        });
+        crate::check_stable(&artifacts[0], &artifacts[1]).unwrap();
+        assert_eq!(0, std::fs::read_dir(&workdir).unwrap().count());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn preflight() {
+        let artifact = b"#!/bin/sh\necho extracted\n".repeat(10);
+        let cwd = camino::Utf8Path::new(".");
+        let mut shell = crate::Shell::buffered();
+        let compression = crate::Compression::Gzip;
+        let compressed =
+            crate::compress(&mut shell, cwd, compression, None, None, &artifact, true).unwrap();
+        let rs = crate::format_with_template(&crate::Template {
+            payload: &base64::encode(compressed),
+            payload_len: artifact.len(),
+            compression: &[compression],
+            ..Default::default()
+        });
+
+        let mut shell = crate::Shell::buffered();
+        let checks = crate::preflight_checks(
+            "x86_64-unknown-linux-gnu",
+            true,
+            Some(64 * 1024),
+            crate::Emit::Rust,
+            &artifact,
+            rs.as_ref(),
+            None,
+            None,
+        );
+        let err = crate::report_preflight(&mut shell, &checks).unwrap_err();
+        assert_eq!("1 of 4 preflight checks failed", err.to_string());
+        let output = shell.buffered_output();
+        let lines = output.lines().map(str::trim_start).collect::<Vec<_>>();
+        assert_eq!(
+            [
+                &*format!(
+                    "Passed size: {} bytes (`--max-size` is 65536 bytes)",
+                    rs.len()
+                ),
+                "Failed musl: `x86_64-unknown-linux-gnu` is not a musl target",
+                "Skipped static linking: not an ELF executable",
+                "Passed decoding: the payload decodes to the executable",
+            ],
+            &*lines,
+        );
+
+        let mut shell = crate::Shell::buffered();
+        let checks = crate::preflight_checks(
+            "x86_64-unknown-linux-musl",
+            true,
+            None,
+            crate::Emit::Rust,
+            &artifact,
+            rs.as_ref(),
+            None,
+            None,
+        );
+        crate::report_preflight(&mut shell, &checks).unwrap();
+
+        let checks = crate::preflight_checks(
+            "x86_64-unknown-linux-musl",
+            false,
+            None,
+            crate::Emit::Rust,
+            b"different",
+            rs.as_ref(),
+            None,
+            None,
+        );
+        assert_eq!(
+            (
+                "decoding",
+                crate::Check::Failed("the payload decodes to a different file".to_owned()),
+            ),
+            checks[3],
+        );
+    }
+
+    #[test]
+    fn write_tempfile() {
+        let path = crate::write_tempfile(b"fn main() {}\n", ".rs").unwrap();
+        assert!(path.is_absolute());
+        assert_eq!(Some("rs".as_ref()), path.extension());
+        assert_eq!("fn main() {}\n", std::fs::read_to_string(&path).unwrap());
+        std::fs::remove_file(path).unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn skip_writing_identical_output() {
+        let tempdir = tempfile::tempdir().unwrap();
+        std::fs::write(tempdir.path().join("main.rs"), "fn main() {}\n").unwrap();
+        duct::cmd!("rustc", "-o", "main", "main.rs")
+            .dir(tempdir.path())
+            .run()
+            .unwrap();
+
+        let (binary, output) = (tempdir.path().join("main"), tempdir.path().join("a.rs"));
+        let run = |extract_name: &str| {
+            let args = [
+                "cargo".as_ref(),
+                "executable-payload".as_ref(),
+                "--from-binary".as_ref(),
+                binary.as_os_str(),
+                "--target".as_ref(),
+                "x86_64-unknown-linux-gnu".as_ref(),
+                "--extract-name".as_ref(),
+                extract_name.as_ref(),
+                "--quiet-tools".as_ref(),
+                "-o".as_ref(),
+                output.as_os_str(),
+            ];
+            let opt = structopt::StructOpt::from_iter_safe(&args).unwrap();
+            let mut shell = crate::Shell::buffered();
+            crate::run(opt, &mut shell).unwrap();
+            shell.buffered_output()
+        };
+        let mtime = || std::fs::metadata(&output).unwrap().modified().unwrap();
+
+        assert!(!run("a").contains("Fresh"));
+        let old = std::time::SystemTime::UNIX_EPOCH + std::time::Duration::from_secs(1_000_000_000);
+        std::fs::File::options()
+            .write(true)
+            .open(&output)
+            .unwrap()
+            .set_modified(old)
+            .unwrap();
+
+        assert!(run("a").contains(&format!("Fresh `{}`", output.display())));
+        assert_eq!(old, mtime());
+
+        assert!(!run("b").contains("Fresh"));
+        assert_ne!(old, mtime());
+    }
+
+    // The default case requires the standard libraries and linkers for all of the targets, and the
+    // custom case requires `x86_64-unknown-linux-musl` and `i686-unknown-linux-musl`.
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    #[test_case(&[], &["x86_64-apple-darwin", "x86_64-pc-windows-gnu", "x86_64-unknown-linux-musl"]; "inconclusive default")]
+    #[test_case(&["x86_64-unknown-linux-gnu,x86_64-unknown-linux-musl,i686-unknown-linux-musl"], &["i686-unknown-linux-musl", "x86_64-unknown-linux-gnu", "x86_64-unknown-linux-musl"]; "custom")]
+    fn os_matrix(values: &[&str], targets: &[&str]) {
+        let tempdir = tempfile::tempdir().unwrap();
+        let package = tempdir.path().join("a");
+        create_package(&package, "a", &[("src/main.rs", "fn main() {}\n")]);
+
+        let (manifest_path, output_dir) = (package.join("Cargo.toml"), tempdir.path().join("out"));
+        let mut args = vec![
+            "cargo".as_ref(),
+            "executable-payload".as_ref(),
+            "--manifest-path".as_ref(),
+            manifest_path.as_os_str(),
+            "--quiet-tools".as_ref(),
+            "--output-dir".as_ref(),
+            output_dir.as_os_str(),
+            "--os-matrix".as_ref(),
+        ];
+        args.extend(values.iter().map(std::ffi::OsStr::new));
+        let opt = structopt::StructOpt::from_iter_safe(&args).unwrap();
+        crate::run(opt, &mut crate::Shell::buffered()).unwrap();
+
+        let mut file_names = std::fs::read_dir(&output_dir)
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        file_names.sort();
+        let mut expected = targets
+            .iter()
+            .map(|t| format!("a-a-{}.rs", t))
+            .collect::<Vec<_>>();
+        expected.push("payloads.json".to_owned());
+        expected.insert(0, crate::LOCK_FILE_NAME.to_owned());
+        assert_eq!(expected, file_names);
+        expected.remove(0);
+
+        let manifest = std::fs::read_to_string(output_dir.join("payloads.json")).unwrap();
+        let manifest = serde_json::from_str::<serde_json::Value>(&manifest).unwrap();
+        let payloads = manifest["payloads"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|p| (p["target"].as_str().unwrap(), p["file"].as_str().unwrap()))
+            .collect::<Vec<_>>();
+        let expected = targets
+            .iter()
+            .zip(&expected)
+            .map(|(t, f)| (*t, &**f))
+            .collect::<Vec<_>>();
+        assert_eq!(expected, payloads);
+    }
+
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    #[test]
+    fn rustc_wrapper() {
+        use std::os::unix::fs::PermissionsExt as _;
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let package = tempdir.path().join("a");
+        create_package(&package, "a", &[("src/main.rs", "fn main() {}\n")]);
+        let (wrapper, log) = (tempdir.path().join("wrapper"), tempdir.path().join("log"));
+        let script = format!(
+            "#!/bin/sh\necho \"$*\" >> '{}'\nexec \"$@\"\n",
+            log.display(),
+        );
+        std::fs::write(&wrapper, script).unwrap();
+        std::fs::set_permissions(&wrapper, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let manifest_path = package.join("Cargo.toml");
+        let output = tempdir.path().join("a.rs");
+        let args = [
+            "cargo".as_ref(),
+            "executable-payload".as_ref(),
+            "--manifest-path".as_ref(),
+            manifest_path.as_os_str(),
+            "--target".as_ref(),
+            "x86_64-unknown-linux-gnu".as_ref(),
+            "--rustc-wrapper".as_ref(),
+            wrapper.as_os_str(),
+            "--quiet-tools".as_ref(),
+            "-o".as_ref(),
+            output.as_os_str(),
+        ];
+        let opt = structopt::StructOpt::from_iter_safe(&args).unwrap();
+        crate::run(opt, &mut crate::Shell::buffered()).unwrap();
+
+        let log = std::fs::read_to_string(log).unwrap();
+        assert!(log.lines().any(|line| line.contains("--crate-name a ")));
+    }
+
+    #[test_case("x86_64-unknown-linux-gnu", "2.17" => Ok("x86_64-linux-gnu.2.17".to_owned()); "x86_64")]
+    #[test_case("aarch64-unknown-linux-gnu", "2.28" => Ok("aarch64-linux-gnu.2.28".to_owned()); "aarch64")]
+    #[test_case("armv7-unknown-linux-gnueabihf", "2.31.1" => Ok("arm-linux-gnueabihf.2.31.1".to_owned()); "armv7")]
+    #[test_case("i686-unknown-linux-gnu", "2.17" => Ok("x86-linux-gnu.2.17".to_owned()); "i686")]
+    #[test_case("riscv64gc-unknown-linux-gnu", "2.27" => Ok("riscv64-linux-gnu.2.27".to_owned()); "riscv64gc")]
+    #[test_case("thumbv7neon-unknown-linux-gnueabihf", "2.28" => Ok("thumb-linux-gnueabihf.2.28".to_owned()); "thumbv7neon")]
+    #[test_case("powerpc64le-unknown-linux-gnu", "2.17" => Ok("powerpc64le-linux-gnu.2.17".to_owned()); "powerpc64le")]
+    #[test_case("sparc-unknown-linux-gnu", "2.17" => Err("`--glibc-version` does not support `sparc`".to_owned()); "unsupported_arch")]
+    #[test_case("x86_64-unknown-linux-musl", "2.17" => Err("`--glibc-version` requires a `*-linux-gnu*` target".to_owned()); "musl")]
+    #[test_case("x86_64-pc-windows-gnu", "2.17" => Err("`--glibc-version` requires a `*-linux-gnu*` target".to_owned()); "windows_gnu")]
+    #[test_case("x86_64-unknown-linux-gnu", "2" => Err("invalid glibc version: \"2\"".to_owned()); "one_component")]
+    #[test_case("x86_64-unknown-linux-gnu", "2.x" => Err("invalid glibc version: \"2.x\"".to_owned()); "not_a_number")]
+    fn zig_glibc_target(target: &str, version: &str) -> Result<String, String> {
+        crate::zig_glibc_target(target, version).map_err(|e| e.to_string())
+    }
+
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    #[test]
+    fn glibc_version() {
+        if which::which("zig").is_err() {
+            // Requires `zig`.
+            return;
+        }
+        let tempdir = tempfile::tempdir().unwrap();
+        let package = tempdir.path().join("a");
+        let main = r#"fn main() { println!("{:?}", std::env::current_dir().is_ok()); }"#;
+        create_package(&package, "a", &[("src/main.rs", main)]);
+
+        let (manifest_path, output) = (package.join("Cargo.toml"), tempdir.path().join("out"));
+        let args = [
+            "cargo".as_ref(),
+            "executable-payload".as_ref(),
+            "--manifest-path".as_ref(),
+            manifest_path.as_os_str(),
+            "--target".as_ref(),
+            "x86_64-unknown-linux-gnu".as_ref(),
+            "--glibc-version".as_ref(),
+            "2.17".as_ref(),
+            "--emit".as_ref(),
+            "binary".as_ref(),
+            "--no-upx".as_ref(),
+            "--quiet-tools".as_ref(),
+            "-o".as_ref(),
+            output.as_os_str(),
+        ];
+        let opt = structopt::StructOpt::from_iter_safe(&args).unwrap();
+        crate::run(opt, &mut crate::Shell::buffered()).unwrap();
+
+        // Every symbol version is at most `GLIBC_2.17`.
+        let binary = std::fs::read(&output).unwrap();
+        let versions = binary
+            .windows(b"GLIBC_2.".len())
+            .enumerate()
+            .filter(|(_, w)| *w == b"GLIBC_2.")
+            .map(|(i, w)| {
+                let minor = binary[i + w.len()..]
+                    .iter()
+                    .take_while(|b| b.is_ascii_digit())
+                    .map(|&b| char::from(b))
+                    .collect::<String>();
+                minor.parse::<u32>().unwrap_or(0)
+            })
+            .collect::<Vec<_>>();
+        assert!(!versions.is_empty());
+        assert!(versions.iter().all(|&minor| minor <= 17), "{:?}", versions);
+
+        let mut perms = std::fs::metadata(&output).unwrap().permissions();
+        std::os::unix::fs::PermissionsExt::set_mode(&mut perms, 0o755);
+        std::fs::set_permissions(&output, perms).unwrap();
+        let output = duct::cmd!(&output).stdout_capture().run().unwrap();
+        assert_eq!(b"true\n", &*output.stdout);
+    }
+
+    #[test]
+    fn zig_cc() {
+        let cwd = std::env::current_dir().unwrap();
+        let err = crate::find_zig(Some("".as_ref()), &cwd).unwrap_err();
+        assert!(err.to_string().starts_with("`zig` not found"));
+
+        let zig_target = crate::zig_glibc_target("x86_64-unknown-linux-gnu", "2.17").unwrap();
+        assert_eq!(
+            "#!/bin/sh\nexec '/opt/zig 0.11/zig' cc -target x86_64-linux-gnu.2.17 \"$@\"\n",
+            crate::zig_cc_script("/opt/zig 0.11/zig".as_ref(), &zig_target),
+        );
+
+        let wrapper = std::path::Path::new("/work/target/executable-payload/zig-cc");
+        assert_eq!(
+            vec![
+                (
+                    "CARGO_TARGET_X86_64_UNKNOWN_LINUX_GNU_LINKER".to_owned(),
+                    wrapper.into(),
+                ),
+                ("CC_x86_64_unknown_linux_gnu".to_owned(), wrapper.into()),
+            ],
+            crate::zig_cc_envs("x86_64-unknown-linux-gnu", wrapper),
+        );
+    }
+
+    #[test_case(&[] => Vec::<String>::new(); "none")]
+    #[test_case(&["--no-upx", "--verify-upx"] => vec!["`--verify-upx` has no effect with `--no-upx`"]; "verify_upx")]
+    #[test_case(&["--no-upx", "--compression-level", "1"] => vec!["`--compression-level` has no effect with `--no-upx` and without `--compression`"]; "compression_level")]
+    #[test_case(&["--no-upx", "--compression", "gzip", "--compression-level", "1"] => Vec::<String>::new(); "compression_level_for_gzip")]
+    #[test_case(&["--xz-exe", "xz", "--compression", "gzip"] => vec!["`--xz-exe` has no effect without `--compression lzma`"]; "xz_exe")]
+    #[test_case(&["--objcopy-exe", "objcopy", "--slim-elf"] => Vec::<String>::new(); "objcopy_exe_for_slim_elf")]
+    #[test_case(&["--emit", "binary", "--banner", "hi", "--self-delete"] => vec!["`--self-delete` has no effect with `--emit binary`", "`--banner` has no effect with `--emit binary`"]; "emit_binary")]
+    #[test_case(&["--emit", "binary", "--extract-path", "/tmp/a", "--inner-attr", "allow(dead_code)"] => vec!["`--extract-path` has no effect with `--emit binary`", "`--inner-attr` has no effect with `--emit binary`"]; "emit_binary_extract_path_inner_attr")]
+    fn unused_flags(args: &[&str]) -> Vec<String> {
+        let args = ["cargo", "executable-payload"].iter().chain(args);
+        let opt = structopt::StructOpt::from_iter_safe(args).unwrap();
+        crate::unused_flags(&opt)
+    }
+
+    #[test]
+    fn report_unused_flags() {
+        let run = |args: &[&str]| {
+            let args = ["cargo", "executable-payload", "--report-unused-flags"]
+                .iter()
+                .chain(&["--from-binary", "/nonexistent", "--no-upx", "--verify-upx"])
+                .chain(args);
+            let opt = structopt::StructOpt::from_iter_safe(args).unwrap();
+            let mut shell = crate::Shell::buffered();
+            let result = crate::run(opt, &mut shell).map_err(|e| e.to_string());
+            (result, shell.buffered_output())
+        };
+
+        let (_, stderr) = run(&[]);
+        assert!(stderr.contains("Unused `--verify-upx` has no effect with `--no-upx`\n"));
+
+        let (result, stderr) = run(&["--strict"]);
+        assert_eq!(
+            Err("1 option has no effect (`--strict` is specified)".to_owned()),
+            result,
+        );
+        assert!(stderr.contains("Unused `--verify-upx` has no effect with `--no-upx`\n"));
+
+        let (result, stderr) = run(&["--strict", "--emit", "binary", "--banner", "hi"]);
+        assert_eq!(
+            Err("2 options have no effect (`--strict` is specified)".to_owned()),
+            result,
+        );
+        assert!(stderr.contains("Unused `--banner` has no effect with `--emit binary`\n"));
+    }
+
+    #[test_case(&[], None => None; "none")]
+    #[test_case(&[], Some("a,b c") => Some("a,b,c".to_owned()); "env")]
+    #[test_case(&[], Some(" ,") => None; "empty_env")]
+    #[test_case(&["a", "b,c"], None => Some("a,b,c".to_owned()); "args")]
+    #[test_case(&["a"], Some("b") => Some("a".to_owned()); "args_override_env")]
+    fn features_arg(features: &[&str], env_features: Option<&str>) -> Option<String> {
+        let features = features.iter().map(|&f| f.to_owned()).collect::<Vec<_>>();
+        crate::features_arg(&features, env_features.map(AsRef::as_ref))
+    }
+
+    #[cfg(all(target_os = "linux", target_arch = "x86_64"))]
+    #[test]
+    fn features() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let package = tempdir.path().join("a");
+        let main = r#"fn main() { println!("{}", cfg!(feature = "greet")); }"#;
+        create_package(&package, "a", &[("src/main.rs", main)]);
+        let manifest_path = package.join("Cargo.toml");
+        let mut manifest = std::fs::read_to_string(&manifest_path).unwrap();
+        manifest += "\n[features]\ngreet = []\n";
+        std::fs::write(&manifest_path, manifest).unwrap();
+
+        let output = tempdir.path().join("a.rs");
+        let args = [
+            "cargo".as_ref(),
+            "executable-payload".as_ref(),
+            "--manifest-path".as_ref(),
+            manifest_path.as_os_str(),
+            "--target".as_ref(),
+            "x86_64-unknown-linux-gnu".as_ref(),
+            "--features".as_ref(),
+            "greet".as_ref(),
+            "--no-upx".as_ref(),
+            "--quiet-tools".as_ref(),
+            "-o".as_ref(),
+            output.as_os_str(),
+        ];
+        let opt = structopt::StructOpt::from_iter_safe(&args).unwrap();
+        crate::run(opt, &mut crate::Shell::buffered()).unwrap();
+
+        let output = run_generated(&std::fs::read_to_string(output).unwrap());
+        assert!(output.status.success());
+        assert_eq!("true\n", std::str::from_utf8(&output.stdout).unwrap());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn emit_binary_to_stdout() {
+        use std::os::unix::fs::PermissionsExt as _;
+
+        let tempdir = tempfile::tempdir().unwrap();
+        std::fs::write(tempdir.path().join("main.rs"), "fn main() {}\n").unwrap();
+        duct::cmd!("rustc", "-o", "main", "main.rs")
+            .dir(tempdir.path())
+            .run()
+            .unwrap();
+        let strip = tempdir.path().join("strip");
+        std::fs::write(
+            &strip,
+            "#!/bin/sh\necho 'stdout of strip'\nexec strip \"$@\"\n",
+        )
+        .unwrap();
+        std::fs::set_permissions(&strip, std::fs::Permissions::from_mode(0o755)).unwrap();
+
+        let (binary, output) = (tempdir.path().join("main"), tempdir.path().join("out"));
+        let run = |output: &std::path::Path| {
+            let args = [
+                "cargo".as_ref(),
+                "executable-payload".as_ref(),
+                "--from-binary".as_ref(),
+                binary.as_os_str(),
+                "--target".as_ref(),
+                "x86_64-unknown-linux-gnu".as_ref(),
+                "--emit".as_ref(),
+                "binary".as_ref(),
+                "--strip-exe".as_ref(),
+                strip.as_os_str(),
+                "-o".as_ref(),
+                output.as_os_str(),
+            ];
+            let opt = structopt::StructOpt::from_iter_safe(&args).unwrap();
+            let mut shell = crate::Shell::buffered();
+            crate::run(opt, &mut shell).unwrap();
+            shell
+        };
+
+        let shell = run("-".as_ref());
+        let stderr = shell.buffered_output();
+        assert!(stderr.contains("stdout of strip\n"));
+        assert!(stderr.contains("Finished"));
+        run(&output);
+        assert_eq!(std::fs::read(&output).unwrap(), shell.buffered_stdout());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn template() {
+        let tempdir = tempfile::tempdir().unwrap();
+        std::fs::write(tempdir.path().join("main.rs"), "fn main() {}\n").unwrap();
+        duct::cmd!("rustc", "-o", "main", "main.rs")
+            .dir(tempdir.path())
+            .run()
+            .unwrap();
+        let template = tempdir.path().join("template.rs");
+        std::fs::write(
+            &template,
+            "// {contest} by {author} ({{author}})\n{payload}// {unknown}\n",
+        )
+        .unwrap();
+
+        let (binary, src) = (tempdir.path().join("main"), tempdir.path().join("main.rs"));
+        let run = |extra_args: &[&str]| {
+            let output = tempdir.path().join("out.rs");
+            let mut args = vec![
+                "cargo".as_ref(),
+                "executable-payload".as_ref(),
+                "--from-binary".as_ref(),
+                binary.as_os_str(),
+                "--src".as_ref(),
+                src.as_os_str(),
+                "--target".as_ref(),
+                "x86_64-unknown-linux-gnu".as_ref(),
+                "--quiet-tools".as_ref(),
+                "-o".as_ref(),
+                output.as_os_str(),
+            ];
+            args.extend(extra_args.iter().map(std::ffi::OsStr::new));
+            let opt = structopt::StructOpt::from_iter_safe(&args).unwrap();
+            let mut shell = crate::Shell::buffered();
+            let result = crate::run(opt, &mut shell);
+            let output = std::fs::read_to_string(&output).ok();
+            std::fs::remove_file(tempdir.path().join("out.rs")).ok();
+            (result, output, shell.buffered_output())
+        };
+
+        let (_, expected, _) = run(&[]);
+        let (result, output, stderr) = run(&[
+            "--template",
+            template.to_str().unwrap(),
+            "--template-var",
+            "contest=abc001",
+            "--template-var",
+            "author=me",
+            "--template-var",
+            "unused=x",
+        ]);
+        result.unwrap();
+        assert_eq!(
+            format!(
+                "// abc001 by me ({{author}})\n{}// {{unknown}}\n",
+                expected.unwrap()
+            ),
+            output.unwrap(),
+        );
+        assert!(stderr.contains("warning: unused `--template-var`: `unused`\n"));
+
+        let (result, ..) = run(&[
+            "--template",
+            template.to_str().unwrap(),
+            "--template-var",
+            "contest=abc001",
+            "--template-var",
+            "author=me",
+            "--template-strict",
+        ]);
+        assert_eq!(
+            "`{unknown}` in the template has no value",
+            result.unwrap_err().to_string(),
+        );
+
+        let (result, ..) = run(&[
+            "--template",
+            template.to_str().unwrap(),
+            "--template-var",
+            "payload=x",
+        ]);
+        assert_eq!(
+            "invalid key for `--template-var`: \"payload\"",
+            result.unwrap_err().to_string(),
+        );
+    }
+
+    #[test_case("{a}-{b}", &[("a", "1"), ("b", "2")], false => Ok(("1-2".to_owned(), vec!["a", "b"])); "vars")]
+    #[test_case("fn main() {{ {a} }}{a}", &[("a", "1")], false => Ok(("fn main() { 1 }1".to_owned(), vec!["a"])); "escape")]
+    #[test_case("fn main() { {a} }", &[("a", "1")], false => Ok(("fn main() { 1 }".to_owned(), vec!["a"])); "block")]
+    #[test_case("{a} {b}", &[("a", "1")], false => Ok(("1 {b}".to_owned(), vec!["a"])); "missing")]
+    #[test_case("{a} {b}", &[("a", "1")], true => Err("`{b}` in the template has no value".to_owned()); "missing_strict")]
+    fn render_template(
+        template: &str,
+        vars: &[(&'static str, &str)],
+        strict: bool,
+    ) -> Result<(String, Vec<&'static str>), String> {
+        crate::render_template(template, vars, strict).map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn write_output() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("out").join("nested").join("a.rs");
+
+        let err = crate::write_output(&path, b"fn main() {}\n", false).unwrap_err();
+        assert_eq!(
+            format!(
+                "`{}` does not exist (use `--mkdir` to create it)",
+                path.parent().unwrap().display(),
+            ),
+            err.to_string(),
+        );
+        assert!(!path.parent().unwrap().exists());
+
+        crate::write_output(&path, b"fn main() {}\n", true).unwrap();
+        assert_eq!("fn main() {}\n", std::fs::read_to_string(&path).unwrap());
+    }
+
+    #[test]
+    fn write_output_concurrently() {
+        use std::sync::{
+            atomic::{AtomicBool, Ordering},
+            Arc,
+        };
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = Arc::new(tempdir.path().join("a.rs"));
+        let contents = Arc::new([vec![b'a'; 1 << 20], vec![b'b'; 1 << 20]]);
+        crate::write_output(&path, &contents[0], false).unwrap();
+
+        let writers = (0..2)
+            .map(|i| {
+                let (path, contents) = (path.clone(), contents.clone());
+                std::thread::spawn(move || {
+                    for _ in 0..20 {
+                        crate::write_output(&path, &contents[i], false).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        let done = Arc::new(AtomicBool::new(false));
+        let reader = {
+            let (path, contents, done) = (path.clone(), contents.clone(), done.clone());
+            std::thread::spawn(move || {
+                while !done.load(Ordering::Relaxed) {
+                    let content = std::fs::read(&*path).unwrap();
+                    assert!(contents.contains(&content), "read a partial file");
+                }
+            })
+        };
+        for writer in writers {
+            writer.join().unwrap();
+        }
+        done.store(true, Ordering::Relaxed);
+        reader.join().unwrap();
+
+        assert!(contents.contains(&std::fs::read(&*path).unwrap()));
+        let entries = std::fs::read_dir(tempdir.path()).unwrap().count();
+        assert_eq!(1, entries, "temporary files are left");
+    }
+
+    #[test]
+    fn lock_output_dir() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let dir = std::sync::Arc::new(tempdir.path().to_owned());
+        let updaters = (0..4)
+            .map(|i| {
+                let dir = dir.clone();
+                std::thread::spawn(move || {
+                    for j in 0..10 {
+                        let file = format!("{}-{}.rs", i, j);
+                        let entry = crate::PayloadEntry {
+                            package: "practice",
+                            bin: "a",
+                            target: "x86_64-unknown-linux-musl",
+                            emit: crate::Emit::Rust,
+                            executable_size: 0,
+                            size: 0,
+                            file: &file,
+                        };
+                        let _lock = crate::lock_output_dir(&dir.join(&file)).unwrap();
+                        crate::update_payloads_manifest(&dir, &entry).unwrap();
+                    }
+                })
+            })
+            .collect::<Vec<_>>();
+        for updater in updaters {
+            updater.join().unwrap();
+        }
+
+        let manifest = std::fs::read_to_string(dir.join("payloads.json")).unwrap();
+        let manifest = serde_json::from_str::<serde_json::Value>(&manifest).unwrap();
+        assert_eq!(40, manifest["payloads"].as_array().unwrap().len());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn write_output_through_symlink() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let (target, link) = (
+            tempdir.path().join("target.rs"),
+            tempdir.path().join("link.rs"),
+        );
+        std::fs::write(&target, "").unwrap();
+        std::os::unix::fs::symlink("target.rs", &link).unwrap();
+
+        crate::write_output(&link, b"fn main() {}\n", false).unwrap();
+        assert!(std::fs::symlink_metadata(&link)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!("fn main() {}\n", std::fs::read_to_string(&target).unwrap());
+
+        crate::write_output("/dev/null".as_ref(), b"fn main() {}\n", false).unwrap();
+        assert!(crate::lock_output_dir("/dev/null".as_ref())
+            .unwrap()
+            .is_none());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn write_output_keeps_permissions() {
+        use std::os::unix::fs::PermissionsExt as _;
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("a");
+        std::fs::write(&path, "").unwrap();
+        std::fs::set_permissions(&path, std::fs::Permissions::from_mode(0o755)).unwrap();
+        crate::write_output(&path, b"\x7fELF", false).unwrap();
+        let mode = std::fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(0o755, mode & 0o777);
+    }
+
+    #[cfg(unix)]
+    #[test_case("/work/a.rs" => Some("/a.rs".to_owned()); "absolute")]
+    #[test_case("/work/out/practice-a-x86_64-unknown-linux-musl.rs" => Some("/out/practice-a-x86_64-unknown-linux-musl.rs".to_owned()); "output_dir")]
+    #[test_case("./out/a.rs" => Some("/out/a.rs".to_owned()); "relative")]
+    #[test_case("a[1]*.rs" => Some("/a\\[1]\\*.rs".to_owned()); "escaped")]
+    #[test_case("/tmp/a.rs" => None; "outside")]
+    #[test_case("../a.rs" => None; "parent")]
+    fn gitignore_entry(path: &str) -> Option<String> {
+        crate::gitignore_entry(path.as_ref(), "/work".as_ref())
+    }
+
+    #[test]
+    fn write_sidecar_hash() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("a.rs");
+        std::fs::write(&path, "abc").unwrap();
+
+        let sidecar = crate::write_sidecar_hash(&path, b"abc").unwrap();
+        assert_eq!(tempdir.path().join("a.rs.sha256"), sidecar);
+        assert_eq!(
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad  a.rs\n",
+            std::fs::read_to_string(&sidecar).unwrap(),
+        );
+        if let Ok(sha256sum) = which::which("sha256sum") {
+            duct::cmd!(sha256sum, "-c", "--quiet", "a.rs.sha256")
+                .dir(tempdir.path())
+                .run()
+                .unwrap();
+        }
+    }
+
+    #[test]
+    fn print_decoded_size() {
+        let artifact = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+        let mut output = vec![];
+        crate::print_decoded_size(&mut output, &artifact).unwrap();
+        let output = String::from_utf8(output).unwrap();
+        assert_eq!(Ok(artifact.len()), output.trim_end().parse());
+    }
+
+    #[test_case("a", "x86_64-pc-windows-msvc" => "a.exe"; "windows")]
+    #[test_case("a", "x86_64-unknown-linux-musl" => "a"; "unix")]
+    #[test_case("a", "wasm32-wasi" => "a.wasm"; "wasm")]
+    #[test_case("a.bin", "x86_64-pc-windows-msvc" => "a.bin"; "explicit_extension")]
+    #[test_case("-", "x86_64-pc-windows-msvc" => "-"; "stdout")]
+    fn with_executable_extension(path: &str, target: &str) -> String {
+        let path = crate::with_executable_extension(path.into(), target);
+        path.to_str().unwrap().to_owned()
+    }
+
+    #[test_case("a" => "a"; "simple")]
+    #[test_case("my-solution_2" => "my-solution_2"; "hyphen_and_underscore")]
+    #[test_case("a b/c" => "a_b_c"; "unsafe_chars")]
+    fn default_extract_name(bin_name: &str) -> String {
+        crate::default_extract_name(bin_name)
+    }
+
+    #[test_case("a" => Ok(()); "simple")]
+    #[test_case("..a" => Ok(()); "dots_and_name")]
+    #[test_case("" => Err(r#"invalid extract name: """#.to_owned()); "empty")]
+    #[test_case("." => Err(r#"invalid extract name: ".""#.to_owned()); "dot")]
+    #[test_case(".." => Err(r#"invalid extract name: "..""#.to_owned()); "dot_dot")]
+    #[test_case("a/b" => Err(r#"invalid extract name: "a/b""#.to_owned()); "slash")]
+    fn validate_extract_name(extract_name: &str) -> Result<(), String> {
+        crate::validate_extract_name(extract_name).map_err(|e| e.to_string())
+    }
+
+    #[test_case("allow(dead_code)" => Ok(()); "list")]
+    #[test_case("allow(clippy::all, unused)" => Ok(()); "paths_in_list")]
+    #[test_case("rustfmt::skip" => Ok(()); "path")]
+    #[test_case("no_std" => Ok(()); "word")]
+    #[test_case(r#"doc = "a""# => Ok(()); "name_value")]
+    #[test_case("" => Err(r#"invalid attribute: """#.to_owned()); "empty")]
+    #[test_case("#![allow(dead_code)]" => Err(r##"invalid attribute: "#![allow(dead_code)]""##.to_owned()); "whole")]
+    #[test_case("allow(dead_code)] fn f() {} #![a" => Err(r##"invalid attribute: "allow(dead_code)] fn f() {} #![a""##.to_owned()); "injection")]
+    #[test_case("allow(dead_code" => Err(r#"invalid attribute: "allow(dead_code""#.to_owned()); "unbalanced")]
+    fn validate_inner_attr(attr: &str) -> Result<(), String> {
+        crate::validate_inner_attr(attr).map_err(|e| e.to_string())
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn inner_attrs() {
+        let artifact = b"#!/bin/sh\necho extracted\n";
+        let inner_attrs = ["deny(warnings)".to_owned(), "forbid(unused)".to_owned()];
+        let rs = crate::format_with_template(&crate::Template {
+            payload: &base64::encode(artifact),
+            payload_len: artifact.len(),
+            inner_attrs: &inner_attrs,
+            ..Default::default()
+        });
+        assert!(
+            rs.starts_with("#![deny(warnings)]\n#![forbid(unused)]\n//! This code is generated by")
+        );
+        let output = run_generated(&rs);
+        assert!(output.status.success());
+        assert_eq!(b"extracted\n", &*output.stdout);
+    }
+
+    #[test]
+    fn extract_path_is_embedded() {
+        let rs = crate::format_with_template(&crate::Template {
+            extract_path: "/tmp/practice-a",
+            ..Default::default()
+        });
+        assert!(rs.contains(r#"static PATH:&str="/tmp/practice-a";"#));
+    }
+
+    #[test]
+    fn provenance() {
+        let tempdir = tempfile::tempdir().unwrap();
+        assert_eq!(None, crate::git_head(tempdir.path()));
+
+        let git = |args: &[&str]| {
+            duct::cmd("git", args)
+                .dir(tempdir.path())
+                .env("GIT_AUTHOR_NAME", "a")
+                .env("GIT_AUTHOR_EMAIL", "a@example.com")
+                .env("GIT_COMMITTER_NAME", "a")
+                .env("GIT_COMMITTER_EMAIL", "a@example.com")
+                .stdout_capture()
+                .read()
+                .unwrap()
+        };
+        git(&["init", "-q"]);
+        git(&["commit", "-q", "--allow-empty", "-m", "initial"]);
+        let commit = git(&["rev-parse", "HEAD"]);
+        assert_eq!(Some(&*commit), crate::git_head(tempdir.path()).as_deref());
+
+        let provenance = crate::Provenance {
+            commit: Some(commit.clone()),
+            built_at: std::time::SystemTime::UNIX_EPOCH
+                + std::time::Duration::from_secs(1_628_553_600),
+        };
+        let rs = crate::format_with_template(&crate::Template {
+            provenance: Some(&provenance),
+            ..Default::default()
+        });
+        assert!(rs.contains(&format!("//! - Commit: `{}`\n", commit)));
+        assert!(rs.contains("//! - Built at: 2021-08-10T00:00:00Z\n"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn payload_len() {
+        let artifact = b"cargo-executable-payload";
+        let payload = base64::encode(artifact);
+
+        let rs = crate::format_with_template(&crate::Template {
+            payload: &payload,
+            payload_len: artifact.len(),
+            ..Default::default()
+        });
+        let output = run_decoder(&rs);
+        assert!(output.status.success());
+        assert_eq!(artifact, &*output.stdout);
+
+        let truncated = &payload[..payload.len() - 5];
+        let rs = crate::format_with_template(&crate::Template {
+            payload: truncated,
+            payload_len: artifact.len(),
+            ..Default::default()
+        });
+        let output = run_decoder(&rs);
+        assert!(!output.status.success());
+        assert!(String::from_utf8_lossy(&output.stderr).contains("payload truncated"));
+    }
+
+    fn create_package(dir: &std::path::Path, name: &str, files: &[(&str, &str)]) {
+        let manifest = format!(
+            "[package]\nname = {:?}\nversion = \"0.0.0\"\nedition = \"2018\"\n",
+            name,
+        );
+        std::fs::create_dir_all(dir).unwrap();
+        std::fs::write(dir.join("Cargo.toml"), manifest).unwrap();
+        for (path, content) in files {
+            let path = dir.join(path);
+            std::fs::create_dir_all(path.parent().unwrap()).unwrap();
+            std::fs::write(path, content).unwrap();
+        }
+    }
+
+    #[test]
+    fn bin_targets_in_multiple_workspaces() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let metadata = ["a", "b"]
+            .iter()
+            .map(|name| {
+                let dir = tempdir.path().join(name);
+                create_package(&dir, name, &[("src/main.rs", "fn main() {}\n")]);
+                crate::cargo_metadata(&dir.join("Cargo.toml"), tempdir.path()).unwrap()
+            })
+            .collect::<Vec<_>>();
+
+        let err = crate::exactly_one_bin_target(&metadata).unwrap_err();
+        assert!(err.to_string().contains("available binaries: a, b"));
+
+        let (bin, package, m) = crate::bin_target_by_name(&metadata, "b").unwrap();
+        assert_eq!("b", bin.name);
+        assert_eq!("b", package.name);
+        assert_eq!(metadata[1].target_directory, m.target_directory);
+
+        let src_path = metadata[0].workspace_root.join("src").join("main.rs");
+        let (bin, ..) = crate::bin_target_by_src_path(&metadata, src_path.as_ref()).unwrap();
+        assert_eq!("a", bin.name);
+    }
+
+    #[test]
+    fn validate_target() {
+        let target_list = &[
+            "x86_64-pc-windows-msvc".to_owned(),
+            "x86_64-unknown-linux-gnu".to_owned(),
+            "x86_64-unknown-linux-musl".to_owned(),
+        ];
+
+        crate::validate_target("x86_64-unknown-linux-musl", target_list).unwrap();
+
+        let err = crate::validate_target("x86_64-unknwon-linux-musl", target_list).unwrap_err();
+        assert_eq!(
+            "`x86_64-unknwon-linux-musl` is not a target known to rustc. did you mean \
+             `x86_64-unknown-linux-musl`?",
+            err.to_string(),
+        );
+
+        let err = crate::validate_target("wasm32-wasi", target_list).unwrap_err();
+        assert!(!err.to_string().contains("did you mean"));
+    }
+
+    #[test]
+    fn minify_code() {
+        let code = indoc! {r##"
+            //! Doc comment.
+            #![warn(rust_2018_idioms)]
+
+            use std::io::{self, Read as _};
+
+            /// Doc comment.
+            fn main() {
+                // Line comment.
+                let mut input = String::new(); /* Block comment. */
+                io::stdin().read_to_string(&mut input).unwrap();
+                let n = input.trim().parse::<i64>().unwrap();
+                let xs = (0..=n).map(|x| x as f64).collect::<Vec<_>>();
+                println!("{}", if n > -1 && xs.len() > 0 { 1. } else { -1.0 });
+                let _: &'static str = r#"{"foo": 'b'}"#;
+                let _ = 'a' as u8 - b'a' + 1.max(0);
+            }
+        "##};
+        let minified = crate::minify_code(code);
+        assert!(minified.len() < code.len());
+        assert!(!minified.contains("comment"));
+        assert!(minified.starts_with("#![warn(rust_2018_idioms)]use std::io::{self,Read as _};"));
+
+        let tokens = |code: &str| {
+            let code = code.replace("        // Line comment.\n", "");
+            let code = code.replace(" /* Block comment. */", "");
+            let code = code.replace("//! Doc comment.\n", "");
+            let code = code.replace("/// Doc comment.\n", "");
+            code.parse::<proc_macro2::TokenStream>()
+                .unwrap()
+                .to_string()
+        };
+        assert_eq!(tokens(code), tokens(&minified));
+
+        assert_eq!("fn main(", crate::minify_code("fn main("));
+    }
+
+    #[test]
+    fn example_only_workspace() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let files = &[
+            ("src/lib.rs", ""),
+            ("examples/ex1.rs", "fn main() {}\n"),
+            ("examples/ex2.rs", "fn main() {}\n"),
+        ];
+        create_package(tempdir.path(), "a", files);
+        let manifest_path = tempdir.path().join("Cargo.toml");
+        let metadata = [crate::cargo_metadata(&manifest_path, tempdir.path()).unwrap()];
+
+        let err = crate::exactly_one_bin_target(&metadata).unwrap_err();
+        assert_eq!(
+            "no bin target in the workspace(s).\n\
+             available examples: ex1, ex2\n\
+             note: currently `cargo-executable-payload` does not support example targets.",
+            err.to_string(),
+        );
+    }
+
+    #[test]
+    fn diagnose_targets() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let files = &[
+            ("src/lib.rs", ""),
+            ("examples/ex.rs", "fn main() {}\n"),
+            ("tests/t.rs", ""),
+        ];
+        create_package(tempdir.path(), "a", files);
+        let manifest_path = tempdir.path().join("Cargo.toml");
+        let metadata = [crate::cargo_metadata(&manifest_path, tempdir.path()).unwrap()];
+
+        let mut lines = crate::diagnose_targets(&metadata)
+            .lines()
+            .map(ToOwned::to_owned)
+            .collect::<Vec<_>>();
+        assert_eq!(
+            format!("workspace `{}`", metadata[0].workspace_root),
+            lines.remove(0),
+        );
+        assert_eq!("  package `a 0.0.0`", lines.remove(0));
+        lines.sort();
+        assert_eq!(
+            [
+                "    example `ex` (crate types: bin, src_path: `examples/ex.rs`)",
+                "    lib `a` (crate types: lib, src_path: `src/lib.rs`)",
+                "    test `t` (crate types: bin, src_path: `tests/t.rs`)",
+            ],
+            *lines,
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn find_strip() {
+        let cwd = camino::Utf8Path::new(".");
+        let path = std::env::var_os("PATH");
+        let path = path.as_deref();
+        let strip = crate::Stripper::Strip;
+        let err =
+            crate::find_tool(strip, Some("/nonexistent/strip".as_ref()), path, cwd).unwrap_err();
+        assert_eq!(
+            "`/nonexistent/strip` (specified with `--strip-exe`) does not exist or is not executable",
+            err.to_string(),
+        );
+
+        let not_executable = tempfile::NamedTempFile::new().unwrap();
+        crate::find_tool(strip, Some(not_executable.path()), path, cwd).unwrap_err();
+
+        let sh = crate::find_tool(strip, Some("sh".as_ref()), path, cwd).unwrap();
+        assert!(sh.unwrap().is_absolute());
+
+        let objcopy = crate::Stripper::Objcopy;
+        let err = crate::find_tool(objcopy, Some("/nonexistent/objcopy".as_ref()), path, cwd);
+        assert!(err.unwrap_err().to_string().contains("`--objcopy-exe`"));
+    }
+
+    #[test]
+    fn cross_tool_command() {
+        let (program, args) = crate::cross_tool_command(
+            "docker".as_ref(),
+            "ghcr.io/cross-rs/aarch64-unknown-linux-musl:main",
+            "/tmp/cargo-executable-payload-XXXXXX".as_ref(),
+            None,
+            "a",
+            crate::Stripper::Strip,
+            None,
+            &["-s"],
+        );
+        assert_eq!("docker", program);
+        assert_eq!(
+            [
+                "run",
+                "--rm",
+                "-v",
+                "/tmp/cargo-executable-payload-XXXXXX:/payload",
+                "ghcr.io/cross-rs/aarch64-unknown-linux-musl:main",
+                "sh",
+                "-c",
+                r#"exec "${CROSS_TOOLCHAIN_PREFIX}strip" -s "$0""#,
+                "/payload/a",
+            ],
+            &*args,
+        );
+
+        let (_, args) = crate::cross_tool_command(
+            "podman".as_ref(),
+            "my-image",
+            "/tmp/cargo-executable-payload-XXXXXX".as_ref(),
+            None,
+            "a",
+            crate::Stripper::Strip,
+            Some("aarch64-linux-musl-strip".as_ref()),
+            &["-s"],
+        );
+        assert_eq!(r#"exec aarch64-linux-musl-strip -s "$0""#, args[7]);
+
+        let (_, args) = crate::cross_tool_command(
+            "docker".as_ref(),
+            "my-image",
+            "/tmp/cargo-executable-payload-XXXXXX".as_ref(),
+            Some("1000:100"),
+            "a",
+            crate::Stripper::Strip,
+            None,
+            &["-s"],
+        );
+        assert_eq!(["run", "--rm", "--user", "1000:100", "-v"], args[..5]);
+
+        let (_, args) = crate::cross_tool_command(
+            "docker".as_ref(),
+            "my-image",
+            "/tmp/cargo-executable-payload-XXXXXX".as_ref(),
+            None,
+            "a",
+            crate::Stripper::Objcopy,
+            None,
+            &["--strip-all"],
+        );
+        assert_eq!(
+            r#"exec "${CROSS_TOOLCHAIN_PREFIX}objcopy" --strip-all "$0""#,
+            args[7],
+        );
+
+        let (_, args) = crate::cross_tool_command(
+            "docker".as_ref(),
+            "my-image",
+            "/tmp/cargo-executable-payload-XXXXXX".as_ref(),
+            None,
+            "a",
+            crate::Stripper::Objcopy,
+            None,
+            &crate::SLIM_ELF_FLAGS,
+        );
+        assert_eq!(
+            r#"exec "${CROSS_TOOLCHAIN_PREFIX}objcopy" --remove-section .comment --remove-section .note.gnu.build-id "$0""#,
+            args[7],
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn cross_container_user() {
+        use std::os::unix::fs::MetadataExt as _;
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let metadata = std::fs::metadata(tempdir.path()).unwrap();
+        let user = format!("{}:{}", metadata.uid(), metadata.gid());
+        let docker = crate::cross_container_user("docker".as_ref(), tempdir.path()).unwrap();
+        let podman = crate::cross_container_user("podman".as_ref(), tempdir.path()).unwrap();
+        match std::env::var("CROSS_ROOTLESS_CONTAINER_ENGINE").as_deref() {
+            Ok("1") | Ok("true") => assert_eq!((None, None), (docker, podman)),
+            Ok("0") | Ok("false") => assert_eq!(
+                (Some(&user), Some(&user)),
+                (docker.as_ref(), podman.as_ref())
+            ),
+            _ => assert_eq!((Some(user), None), (docker, podman)),
+        }
+    }
+
+    #[test_case(None => Ok("ghcr.io/cross-rs/aarch64-unknown-linux-musl:main".to_owned()); "default")]
+    #[test_case(Some("[target.x86_64-unknown-linux-musl]\nimage = \"a\"\n") => Ok("ghcr.io/cross-rs/aarch64-unknown-linux-musl:main".to_owned()); "other_target")]
+    #[test_case(Some("[target.aarch64-unknown-linux-musl]\nimage = \"my/image:1\"\n") => Ok("my/image:1".to_owned()); "name")]
+    #[test_case(Some("[target.aarch64-unknown-linux-musl.image]\nname = \"my/image:1\"\n") => Ok("my/image:1".to_owned()); "table")]
+    #[test_case(Some("[target.aarch64-unknown-linux-musl]\nimage = 1\n") => Err("`target.aarch64-unknown-linux-musl.image` in `Cross.toml` is not an image name".to_owned()); "invalid")]
+    fn cross_image_from_config(config: Option<&str>) -> Result<String, String> {
+        let config = config.map(|toml| ("Cross.toml".as_ref(), toml));
+        crate::cross_image_from_config("aarch64-unknown-linux-musl", config)
+            .map_err(|e| e.to_string())
+    }
+
+    #[test]
+    fn cross_image() {
+        if std::env::var_os("CROSS_CONFIG").is_some() {
+            return;
+        }
+        let tempdir = tempfile::tempdir().unwrap();
+        let manifest_dir = tempdir.path().join("a");
+        std::fs::create_dir(&manifest_dir).unwrap();
+        let cross_toml = "[target.cargo-executable-payload-test]\nimage = \"my/image:1\"\n";
+        std::fs::write(tempdir.path().join("Cross.toml"), cross_toml).unwrap();
+        let image = crate::cross_image(&manifest_dir, "cargo-executable-payload-test").unwrap();
+        assert_eq!("my/image:1", image);
+    }
+
+    #[test]
+    fn stripper_args() {
+        let path = std::path::Path::new("/tmp/a");
+        assert_eq!(["-s", "/tmp/a"], crate::Stripper::Strip.args(path));
+        assert_eq!(
+            ["--strip-all", "/tmp/a"],
+            crate::Stripper::Objcopy.args(path),
+        );
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn gzip_round_trip() {
+        let mut artifact = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+        artifact.truncate(256 * 1024);
+        let cwd = camino::Utf8Path::new(".");
+        let mut shell = crate::Shell::buffered();
+        let compression = crate::Compression::Gzip;
+        let compressed =
+            crate::compress(&mut shell, cwd, compression, None, None, &artifact, true).unwrap();
+        assert!(compressed.len() < artifact.len());
+
+        let payload = base64::encode(compressed);
+        let rs = crate::format_with_template(&crate::Template {
+            payload: &payload,
+            payload_len: artifact.len(),
+            compression: &[compression],
+            ..Default::default()
+        });
+        let output = run_decoder(&rs);
+        assert!(output.status.success());
+        assert!(artifact == output.stdout);
+    }
+
+    #[cfg(unix)]
+    #[test_case(vec![]; "empty")]
+    #[test_case(b"Hello, world!\n".repeat(1000); "repetitive")]
+    #[test_case((0..100_000u32).map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8).collect(); "noisy")]
+    #[test_case({
+        let mut artifact = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+        artifact.truncate(256 * 1024);
+        artifact
+    }; "executable")]
+    fn lzma_round_trip(artifact: Vec<u8>) {
+        let cwd = camino::Utf8Path::new(".");
+        let mut shell = crate::Shell::buffered();
+        let compression = crate::Compression::Lzma;
+        let compressed =
+            crate::compress(&mut shell, cwd, compression, None, None, &artifact, true).unwrap();
+
+        let rs = crate::format_with_template(&crate::Template {
+            payload: &base64::encode(compressed),
+            payload_len: artifact.len(),
+            compression: &[compression],
+            ..Default::default()
+        });
+        assert!(!rs.contains("fn inflate("));
+        let output = run_decoder(&rs);
+        assert!(output.status.success());
+        assert!(artifact == output.stdout);
+    }
+
+    #[cfg(unix)]
+    #[test_case([crate::Compression::Lzma, crate::Compression::Gzip]; "lzma_then_gzip")]
+    #[test_case([crate::Compression::Gzip, crate::Compression::Lzma]; "gzip_then_lzma")]
+    fn lzma_and_gzip_layers(compression: [crate::Compression; 2]) {
+        let artifact = b"Hello, world!\n".repeat(1000);
+        let cwd = camino::Utf8Path::new(".");
+        let mut shell = crate::Shell::buffered();
+        let mut payload = artifact.clone();
+        for &c in &compression {
+            payload = crate::compress(&mut shell, cwd, c, None, None, &payload, true).unwrap();
+        }
+
+        let rs = crate::format_with_template(&crate::Template {
+            payload: &base64::encode(payload),
+            payload_len: artifact.len(),
+            compression: &compression,
+            ..Default::default()
+        });
+        assert_eq!(
+            compression,
+            *crate::parse_generated(&rs).unwrap().compression
+        );
+        assert!(artifact == crate::decode_generated(rs.as_ref(), None).unwrap());
+        let output = run_decoder(&rs);
+        assert!(output.status.success());
+        assert!(artifact == output.stdout);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn two_compression_layers() {
+        let artifact = b"Hello, world!\n".repeat(1000);
+        let cwd = camino::Utf8Path::new(".");
+        let mut shell = crate::Shell::buffered();
+        let compression = [crate::Compression::Gzip, crate::Compression::Gzip];
+        let once =
+            crate::compress(&mut shell, cwd, compression[0], None, None, &artifact, true).unwrap();
+        let twice =
+            crate::compress(&mut shell, cwd, compression[1], None, None, &once, true).unwrap();
+
+        let rs = crate::format_with_template(&crate::Template {
+            payload: &base64::encode(twice),
+            payload_len: artifact.len(),
+            compression: &compression,
+            ..Default::default()
+        });
+        assert_eq!(2, rs.matches("let acc=inflate(&acc);").count());
+        assert_eq!(1, rs.matches("fn inflate(").count());
+        let output = run_decoder(&rs);
+        assert!(output.status.success());
+        assert!(artifact == output.stdout);
+    }
+
+    #[cfg(unix)]
+    #[test_case(b"abc"; "no_padding")]
+    #[test_case(b"abcd"; "two_padding")]
+    #[test_case(b"abcde"; "one_padding")]
+    fn base64_capacity(artifact: &[u8]) {
+        let rs = crate::format_with_template(&crate::Template {
+            payload: &base64::encode(artifact),
+            payload_len: artifact.len(),
+            ..Default::default()
+        });
+        assert!(rs.contains("let mut acc=Vec::with_capacity(PAYLOAD.len()/4*3);"));
+        let output = run_decoder(&rs);
+        assert!(output.status.success());
+        assert_eq!(artifact, &*output.stdout);
+    }
+
+    #[test_case(crate::Compressor::Upx, None => Ok("--best".to_owned()); "upx_default")]
+    #[test_case(crate::Compressor::Upx, Some(1) => Ok("-1".to_owned()); "upx_1")]
+    #[test_case(crate::Compressor::Upx, Some(10) => Err("`upx` does not support compression level 10 (expected 1 to 9)".to_owned()); "upx_10")]
+    #[test_case(crate::Compressor::Gzip, None => Ok("-9".to_owned()); "gzip_default")]
+    #[test_case(crate::Compressor::Gzip, Some(6) => Ok("-6".to_owned()); "gzip_6")]
+    #[test_case(crate::Compressor::Gzip, Some(0) => Err("`gzip` does not support compression level 0 (expected 1 to 9)".to_owned()); "gzip_0")]
+    #[test_case(crate::Compressor::Xz, Some(0) => Ok("-0".to_owned()); "xz_0")]
+    #[test_case(crate::Compressor::Xz, Some(10) => Err("`xz` does not support compression level 10 (expected 0 to 9)".to_owned()); "xz_10")]
+    fn compression_level_arg(
+        compressor: crate::Compressor,
+        level: Option<u32>,
+    ) -> Result<String, String> {
+        compressor.level_arg(level).map_err(|e| e.to_string())
+    }
+
+    #[test_case(None => 2; "default")]
+    #[test_case(Some(9) => 2; "best")]
+    #[test_case(Some(1) => 4; "fastest")]
+    fn gzip_compression_level(level: Option<u32>) -> u8 {
+        let cwd = camino::Utf8Path::new(".");
+        let gzip = crate::Compression::Gzip;
+        let compressed = crate::compress(
+            &mut crate::Shell::buffered(),
+            cwd,
+            gzip,
+            level,
+            None,
+            &[0; 1000],
+            true,
+        )
+        .unwrap();
+        // XFL of the header, which is 2 for the maximum compression and 4 for the fastest.
+        compressed[8]
+    }
+
+    #[cfg(unix)]
+    #[test_case(false; "plain")]
+    #[test_case(true; "checksum")]
+    fn split(checksum: bool) {
+        let artifact = (0..1000u32)
+            .map(|i| (i * 7 % 256) as u8)
+            .collect::<Vec<_>>();
+        let payload = base64::encode(&artifact);
+        let checksum = if checksum {
+            crate::ChecksumAlgo::Sha256.checksum(&artifact)
+        } else {
+            crate::Checksum::None
+        };
+        let rs = crate::format_with_template(&crate::Template {
+            payload: &payload,
+            payload_len: artifact.len(),
+            payload_file: Some(crate::PAYLOAD_FILE_NAME),
+            checksum,
+            ..Default::default()
+        });
+        assert!(rs.contains(r#"static PAYLOAD:&str=include_str!("payload.b64");"#));
+        assert!(!rs.contains(&payload));
+
+        let checks = crate::preflight_checks(
+            "x86_64-unknown-linux-musl",
+            false,
+            None,
+            crate::Emit::Split,
+            &artifact,
+            rs.as_ref(),
+            Some(&payload),
+            None,
+        );
+        assert!(matches!(checks[3], ("decoding", crate::Check::Passed(_))));
+
+        let tempdir = tempfile::tempdir().unwrap();
+        std::fs::write(tempdir.path().join("payload.b64"), &payload).unwrap();
+        let rs = rs.replacen("fn main()", "#[allow(dead_code)]fn _main()", 1)
+            + "fn main(){use std::io::Write as _;std::io::stdout().write_all(&decode()).unwrap();}\n";
+        std::fs::write(tempdir.path().join("loader.rs"), rs).unwrap();
+        duct::cmd!("rustc", "--edition", "2018", "-o", "loader", "loader.rs")
+            .dir(tempdir.path())
+            .stdout_null()
+            .run()
+            .unwrap();
+        let output = duct::cmd!(tempdir.path().join("loader"))
+            .stdout_capture()
+            .run()
+            .unwrap();
+        assert_eq!(artifact, output.stdout);
+    }
+
+    #[test]
+    fn checksums() {
+        assert_eq!(0, crate::crc32(b""));
+        assert_eq!(0xcbf4_3926, crate::crc32(b"123456789"));
+        let hex = |hash: [u8; 32]| {
+            hash.iter()
+                .map(|b| format!("{:02x}", b))
+                .collect::<String>()
+        };
+        assert_eq!(
+            "e3b0c44298fc1c149afbf4c8996fb92427ae41e4649b934ca495991b7852b855",
+            hex(crate::sha256(b"")),
+        );
+        assert_eq!(
+            "ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad",
+            hex(crate::sha256(b"abc")),
+        );
+        assert_eq!(
+            "248d6a61d20638b8e5c026930c3e6039a33ce45964ff2167f6ecedd419db06c1",
+            hex(crate::sha256(
+                b"abcdbcdecdefdefgefghfghighijhijkijkljklmklmnlmnomnopnopq"
+            )),
+        );
+    }
+
+    #[cfg(unix)]
+    #[test_case(crate::ChecksumAlgo::None, &[][..]; "none")]
+    #[test_case(crate::ChecksumAlgo::Crc32, &[][..]; "crc32")]
+    #[test_case(crate::ChecksumAlgo::Sha256, &[][..]; "sha256")]
+    #[test_case(crate::ChecksumAlgo::Crc32, &[crate::Compression::Gzip][..]; "crc32_with_gzip")]
+    #[test_case(crate::ChecksumAlgo::Sha256, &[crate::Compression::Gzip][..]; "sha256_with_gzip")]
+    fn checksum_algo(algo: crate::ChecksumAlgo, compression: &[crate::Compression]) {
+        let artifact = (0..1000u32)
+            .map(|i| (i * 7 % 256) as u8)
+            .collect::<Vec<_>>();
+        let mut payload = artifact.clone();
+        for &c in compression {
+            let cwd = camino::Utf8Path::new(".");
+            payload = crate::compress(
+                &mut crate::Shell::buffered(),
+                cwd,
+                c,
+                None,
+                None,
+                &payload,
+                true,
+            )
+            .unwrap();
+        }
+        let payload = base64::encode(payload);
+        let rs = crate::format_with_template(&crate::Template {
+            payload: &payload,
+            payload_len: artifact.len(),
+            checksum: algo.checksum(&artifact),
+            compression,
+            ..Default::default()
+        });
+        assert_eq!(
+            algo != crate::ChecksumAlgo::None,
+            rs.contains(r#""payload corrupted");"#),
+        );
+
+        let output = run_decoder(&rs);
+        assert!(output.status.success());
+        assert_eq!(artifact, output.stdout);
+
+        if compression.is_empty() {
+            // Same length, different content.
+            let corrupted = format!(
+                "\"{}{}",
+                if payload.starts_with('A') { 'B' } else { 'A' },
+                &payload[1..]
+            );
+            let rs = rs.replacen(&format!("\"{}", payload), &corrupted, 1);
+            let output = run_decoder(&rs);
+            assert_eq!(algo == crate::ChecksumAlgo::None, output.status.success());
+            if algo != crate::ChecksumAlgo::None {
+                assert!(String::from_utf8_lossy(&output.stderr).contains("payload corrupted"));
+            }
+        }
+    }
+
+    #[cfg(unix)]
+    #[test_case(0, &[][..]; "empty")]
+    #[test_case(1, &[][..]; "padding2")]
+    #[test_case(2, &[][..]; "padding1")]
+    #[test_case(3, &[][..]; "no_padding")]
+    #[test_case(300_000, &[][..]; "large")]
+    #[test_case(1000, &[crate::Compression::Gzip][..]; "gzip")]
+    fn const_decode(len: u32, compression: &[crate::Compression]) {
+        let artifact = (0..len).map(|i| (i * 7 % 256) as u8).collect::<Vec<_>>();
+        let mut payload = artifact.clone();
+        for &c in compression {
+            let cwd = camino::Utf8Path::new(".");
+            payload = crate::compress(
+                &mut crate::Shell::buffered(),
+                cwd,
+                c,
+                None,
+                None,
+                &payload,
+                true,
+            )
+            .unwrap();
+        }
+        let payload = base64::encode(&payload);
+        let rs = crate::format_with_template(&crate::Template {
+            payload: &payload,
+            payload_len: artifact.len(),
+            compression,
+            const_decode: true,
+            ..Default::default()
+        });
+        assert!(rs.contains("static DECODED:"));
+
+        let output = run_decoder(&rs);
+        assert!(output.status.success());
+        assert_eq!(artifact, output.stdout);
+    }
+
+    #[cfg(all(unix, target_arch = "x86_64"))]
+    #[test]
+    fn multiarch() {
+        let (v1, v3) = (&b"x86-64"[..], &b"x86-64-v3"[..]);
+        let rs = crate::format_with_template(&crate::Template {
+            payload: &base64::encode(v1),
+            payload_len: v1.len(),
+            payload_v3: Some((&base64::encode(v3), v3.len())),
+            ..Default::default()
+        });
+        assert!(rs.contains("static PAYLOADS:[(&str,usize);2]="));
+        assert!(!rs.contains("static PAYLOAD:"));
+        assert!(rs.contains(r#"is_x86_feature_detected!("avx2")"#));
+
+        let is_x86_64_v3 = is_x86_feature_detected!("avx")
+            && is_x86_feature_detected!("avx2")
+            && is_x86_feature_detected!("bmi1")
+            && is_x86_feature_detected!("bmi2")
+            && is_x86_feature_detected!("f16c")
+            && is_x86_feature_detected!("fma")
+            && is_x86_feature_detected!("lzcnt")
+            && is_x86_feature_detected!("popcnt")
+            && is_x86_feature_detected!("sse4.2")
+            && is_x86_feature_detected!("xsave");
+        let output = run_decoder(&rs);
+        assert!(output.status.success());
+        assert_eq!(if is_x86_64_v3 { v3 } else { v1 }, &*output.stdout);
+    }
+
+    #[test]
+    fn z85() {
+        let hello_world = [0x86, 0x4f, 0xd2, 0x6f, 0xb5, 0x59, 0xf7, 0x5b];
+        assert_eq!(
+            ("HelloWorld".to_owned(), 0),
+            crate::z85_encode(&hello_world)
+        );
+        assert_eq!(Some(hello_world.to_vec()), crate::z85_decode("HelloWorld"));
+        assert_eq!(None, crate::z85_decode("Hell"));
+        assert_eq!(None, crate::z85_decode("#####"));
+
+        for len in 0..=9 {
+            let data = (0..len).map(|i| 255 - i as u8).collect::<Vec<_>>();
+            let (encoded, padding) = crate::z85_encode(&data);
+            assert_eq!((len + padding) / 4 * 5, encoded.len());
+            let mut decoded = crate::z85_decode(&encoded).unwrap();
+            decoded.truncate(decoded.len() - padding);
+            assert_eq!(data, decoded);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test_case(&[]; "empty")]
+    #[test_case(b"a"; "one")]
+    #[test_case(b"abcd"; "four")]
+    #[test_case(b"\xff\xff\xff\xff\xff\xff\xff"; "seven")]
+    fn z85_round_trip(artifact: &[u8]) {
+        let (payload, padding) = crate::z85_encode(artifact);
+        let rs = crate::format_with_template(&crate::Template {
+            payload: &payload,
+            payload_len: artifact.len(),
+            encoding: crate::Encoding::Z85,
+            padding,
+            ..Default::default()
+        });
+        assert!(rs.contains("let mut acc=Vec::with_capacity(PAYLOAD.len()/5*4);"));
+        let output = run_decoder(&rs);
+        assert!(output.status.success());
+        assert_eq!(artifact, &*output.stdout);
+
+        let generated = crate::parse_generated(&rs).unwrap();
+        assert_eq!(artifact, &*generated.payload);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn z85_with_gzip() {
+        let artifact = b"Hello, world!\n".repeat(100);
+        let cwd = camino::Utf8Path::new(".");
+        let mut shell = crate::Shell::buffered();
+        let compression = crate::Compression::Gzip;
+        let compressed =
+            crate::compress(&mut shell, cwd, compression, None, None, &artifact, true).unwrap();
+        let (payload, padding) = crate::z85_encode(&compressed);
+        let rs = crate::format_with_template(&crate::Template {
+            payload: &payload,
+            payload_len: artifact.len(),
+            encoding: crate::Encoding::Z85,
+            padding,
+            compression: &[compression],
+            ..Default::default()
+        });
+        let output = run_decoder(&rs);
+        assert!(output.status.success());
+        assert!(artifact == output.stdout);
+    }
+
+    #[test]
+    fn compare_generated() {
+        let generate = |artifact: &[u8], banner| {
+            crate::format_with_template(&crate::Template {
+                original_source_code: "fn main() {}\n",
+                payload: &base64::encode(artifact),
+                payload_len: artifact.len(),
+                banner,
+                ..Default::default()
+            })
+        };
+        let old = generate(b"old", None);
+
+        assert_eq!(
+            crate::GeneratedDiff::Unchanged,
+            crate::compare_generated(&old, &generate(b"old", None), "a.rs"),
+        );
+        assert_eq!(
+            crate::GeneratedDiff::PayloadOnly,
+            crate::compare_generated(&old, &generate(b"new!", None), "a.rs"),
+        );
+        let diff = match crate::compare_generated(&old, &generate(b"new!", Some("hi")), "a.rs") {
+            crate::GeneratedDiff::Changed(diff) => diff,
+            diff => panic!("{:?}", diff),
+        };
+        assert!(diff.starts_with("--- a.rs\n+++ (new)\n@@ "));
+        assert!(diff.contains("\n-#[allow(clippy::all)]fn main()->std::io::Result<()>{use std::"));
+        assert!(diff.contains("\n+#[allow(clippy::all)]fn main()->std::io::Result<()>{eprintln!"));
+        assert!(diff.contains(r#"static PAYLOAD:&str="..";static LEN:usize=..;"#));
+        assert!(!diff.contains(&base64::encode(b"new!")));
+    }
+
+    #[test]
+    fn unified_diff() {
+        let old = (1..=12).map(|i| format!("{}\n", i)).collect::<String>();
+        let new = (1..=12)
+            .filter(|&i| i != 11)
+            .map(|i| match i {
+                2 => "two\n".to_owned(),
+                i => format!("{}\n", i),
+            })
+            .collect::<String>();
+        assert_eq!(
+            indoc! {"
+                --- old
+                +++ new
+                @@ -1,5 +1,5 @@
+                 1
+                -2
+                +two
+                 3
+                 4
+                 5
+                @@ -8,5 +8,4 @@
+                 8
+                 9
+                 10
+                -11
+                 12
+            "},
+            crate::unified_diff(&old, &new, "old", "new"),
+        );
+        assert_eq!(
+            "--- old\n+++ new\n@@ -0,0 +1 @@\n+a\n",
+            crate::unified_diff("", "a\n", "old", "new"),
+        );
+        assert_eq!("", crate::unified_diff("a\n", "a\n", "old", "new"));
+    }
+
+    #[test]
+    fn parse_generated() {
+        let rs = crate::format_with_template(&crate::Template {
+            payload: &base64::encode(b"payload"),
+            payload_len: 7,
+            extract_path: "/tmp/a \"b\"\n\u{1f980}",
+            ..Default::default()
+        });
+        assert_eq!(
+            crate::Generated {
+                format_version: Some(crate::FORMAT_VERSION),
+                extract_path: "/tmp/a \"b\"\n\u{1f980}".to_owned(),
+                compression: vec![],
+                payload: b"payload".to_vec(),
+                payload_len: Some(7),
+            },
+            crate::parse_generated(&rs).unwrap(),
+        );
+
+        let compression = [crate::Compression::Gzip, crate::Compression::Gzip];
+        let rs = crate::format_with_template(&crate::Template {
+            payload: &base64::encode(b"compressed"),
+            payload_len: 100,
+            compression: &compression,
+            extract_path: "/tmp/a",
+            spawn: true,
+            banner: Some("banner"),
+            ..Default::default()
+        });
+        let generated = crate::parse_generated(&rs).unwrap();
+        assert_eq!(compression, &*generated.compression);
+        assert_eq!(b"compressed", &*generated.payload);
+        assert_eq!(Some(100), generated.payload_len);
+
+        // Format version 1.
+        let rs = indoc! {r#"
+            //! This code is generated by [cargo-executable-payload](https://github.com/qryxip/cargo-executable-payload).
+
+            original_source_code! {
+                fn main() {}
+            }
+
+            fn main()->std::io::Result<()>{}static PATH:&str="/tmp/a.out";static PAYLOAD:&str="cGF5bG9hZA==";
+        "#};
+        assert_eq!(
+            crate::Generated {
+                format_version: None,
+                extract_path: "/tmp/a.out".to_owned(),
+                compression: vec![],
+                payload: b"payload".to_vec(),
+                payload_len: None,
+            },
+            crate::parse_generated(rs).unwrap(),
+        );
+
+        let err = crate::parse_generated("fn main() {}").unwrap_err();
+        assert_eq!("`PATH` not found", err.to_string());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn runtime_decoders() {
+        let mut artifact = std::fs::read(std::env::current_exe().unwrap()).unwrap();
+        artifact.truncate(256 * 1024);
+        let cwd = camino::Utf8Path::new(".");
+        let mut shell = crate::Shell::buffered();
+        let compression = [crate::Compression::Gzip];
+        let compressed =
+            crate::compress(&mut shell, cwd, compression[0], None, None, &artifact, true).unwrap();
+        let payload = base64::encode(compressed);
+
+        let render = |runtime_decoder| {
+            crate::format_with_template(&crate::Template {
+                payload: &payload,
+                payload_len: artifact.len(),
+                compression: &compression,
+                runtime_decoder,
+                ..Default::default()
+            })
+        };
+        let minimal = render(crate::RuntimeDecoder::Minimal);
+        let fast = render(crate::RuntimeDecoder::Fast);
+        assert!(minimal.len() < fast.len());
+        assert_eq!(
+            fast.len() + crate::INFLATE.len() + crate::DECODE_BASE64.len(),
+            minimal.len() + crate::INFLATE_FAST.len() + crate::DECODE_BASE64_FAST.len(),
+        );
+
+        let output = run_decoder(&fast);
+        assert!(output.status.success());
+        assert!(artifact == output.stdout);
+
+        let truncated = base64::encode(&base64::decode(&payload).unwrap()[..1000]);
+        let output = run_decoder(&fast.replace(&payload, &truncated));
+        assert!(!output.status.success());
+        assert!(String::from_utf8_lossy(&output.stderr).contains("payload truncated"));
+    }
+
+    #[cfg(unix)]
+    #[test_case(0; "empty")]
+    #[test_case(1; "two_padding")]
+    #[test_case(2; "one_padding")]
+    #[test_case(3; "no_padding")]
+    #[test_case(4 * 1024 * 1024; "large")]
+    fn fast_base64_decoder(len: usize) {
+        let artifact = (0..len as u32)
+            .map(|i| (i.wrapping_mul(2_654_435_761) >> 24) as u8)
+            .collect::<Vec<_>>();
+        let payload = base64::encode(&artifact);
+
+        for &runtime_decoder in &[crate::RuntimeDecoder::Minimal, crate::RuntimeDecoder::Fast] {
+            let rs = crate::format_with_template(&crate::Template {
+                payload: &payload,
+                payload_len: artifact.len(),
+                runtime_decoder,
+                ..Default::default()
+            });
+            let rs = rs.replacen("fn main()", "#[allow(dead_code)]fn _main()", 1)
+                + "fn main(){use std::io::Write as _;std::io::stdout().write_all(&decode()).unwrap();}\n";
+            let output = run_generated(&rs);
+            assert!(output.status.success());
+            assert!(artifact == output.stdout);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn compressed_source_comment() {
+        let source_code = "fn main() {\n    println!(\"Hello, world!\");\n}\n".repeat(100);
+        let cwd = camino::Utf8Path::new(".");
+        let mut shell = crate::Shell::buffered();
+        let compression = [crate::Compression::Gzip];
+        let compressed = crate::compress(
+            &mut shell,
+            cwd,
+            compression[0],
+            None,
+            None,
+            source_code.as_ref(),
+            true,
+        )
+        .unwrap();
+        let comment =
+            crate::compressed_source_comment(&compression, source_code.len(), &compressed);
+        assert!(comment.starts_with(&format!(
+            "compression = [\"gzip\"],\nlen = {},\nbase64 = \"",
+            source_code.len(),
+        )));
+
+        let rs = crate::format_with_template(&crate::Template {
+            original_source_code: &comment,
+            ..Default::default()
+        });
+        let base64 = rs
+            .split("base64 = \"")
+            .nth(1)
+            .and_then(|rest| rest.split('"').next())
+            .unwrap();
+
+        let rs = crate::format_with_template(&crate::Template {
+            payload: base64,
+            payload_len: source_code.len(),
+            compression: &compression,
+            ..Default::default()
+        });
+        let output = run_decoder(&rs);
+        assert!(output.status.success());
+        assert_eq!(source_code.as_bytes(), &*output.stdout);
+    }
+
+    #[test]
+    fn quiet_on_success() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("a.rs");
+        std::fs::write(&path, "fn main() {}\n").unwrap();
+        let path = path.to_str().unwrap();
+
+        let run = |args: &[&str]| {
+            let quiet = ["--quiet-on-success", "--version-check", path];
+            let args = ["cargo", "executable-payload"]
+                .iter()
+                .chain(&quiet)
+                .chain(args);
+            let opt = structopt::StructOpt::from_iter_safe(args).unwrap();
+            let mut shell = crate::Shell::buffered();
+            let result = crate::run(opt, &mut shell);
+            (result, shell.buffered_output())
+        };
+
+        let (result, output) = run(&["--check-tools"]);
+        result.unwrap();
+        assert_eq!("", output);
+
+        let (result, output) = run(&[
+            "--from-binary",
+            "/nonexistent",
+            "--target",
+            "x86_64-unknown-linux-gnu",
+        ]);
+        result.unwrap_err();
+        assert!(output.starts_with("warning: "));
+        assert!(output.contains("was generated in an old format"));
+    }
+
+    #[test]
+    fn format_version() {
+        let rs = crate::format_with_template(&Default::default());
+        assert_eq!(Some(crate::FORMAT_VERSION), crate::format_version_of(&rs));
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let path = tempdir.path().join("a.rs");
+
+        std::fs::write(&path, &rs).unwrap();
+        let mut shell = crate::Shell::buffered();
+        crate::check_format_version(&mut shell, &path).unwrap();
+        assert_eq!("", shell.buffered_output());
+
+        let old = rs.replace(
+            &format!("format version: {}", crate::FORMAT_VERSION),
+            "format version: 1",
+        );
+        std::fs::write(&path, old).unwrap();
+        let mut shell = crate::Shell::buffered();
+        crate::check_format_version(&mut shell, &path).unwrap();
+        assert!(shell.buffered_output().contains("format version 1"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn rust_fragment() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let extract_path = tempdir.path().join("a");
+        let artifact = b"#!/bin/sh\necho extracted\n";
+        let fragment = crate::format_with_template(&crate::Template {
+            original_source_code: "fn main() {}\n",
+            payload: &base64::encode(artifact),
+            payload_len: artifact.len(),
+            extract_path: extract_path.to_str().unwrap(),
+            fragment: true,
+            ..Default::default()
+        });
+        assert!(!fragment.contains("fn main"));
+        assert!(!fragment.contains("//!"));
+        assert!(!fragment.contains("original_source_code"));
+        assert!(fragment.contains("pub fn run_payload()->std::io::Result<()>{"));
+
+        let rs = indoc! {r#"
+            //! An existing program.
+
+            use std::io::Write as _;
+
+            fn main() {
+                println!("host");
+                std::io::stdout().flush().unwrap();
+                run_payload().unwrap();
+            }
+
+        "#}
+        .to_owned()
+            + &fragment;
+        let output = run_generated(&rs);
+        assert!(output.status.success());
+        assert_eq!(b"host\nextracted\n", &*output.stdout);
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn unique_extract_path() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let extract_path = tempdir.path().join("a");
+        let artifact = b"#!/bin/sh\necho extracted\n";
+        let rs = crate::format_with_template(&crate::Template {
+            payload: &base64::encode(artifact),
+            payload_len: artifact.len(),
+            extract_path: extract_path.to_str().unwrap(),
+            ..Default::default()
+        });
+        assert!(rs.contains("let e=Command::new(&path).exec();"));
+
+        for _ in 0..2 {
+            let output = run_generated(&rs);
+            assert!(output.status.success());
+            assert_eq!(b"extracted\n", &*output.stdout);
+        }
+        assert!(!extract_path.exists());
+        let extracted = std::fs::read_dir(tempdir.path())
+            .unwrap()
+            .map(|entry| entry.unwrap().path())
+            .collect::<Vec<_>>();
+        assert_eq!(2, extracted.len());
+        for path in extracted {
+            let file_name = path.file_name().unwrap().to_str().unwrap();
+            let suffix = file_name.strip_prefix("a.").unwrap();
+            assert!(suffix.split('.').all(|n| n.parse::<u128>().is_ok()));
+            assert_eq!(artifact, &*std::fs::read(path).unwrap());
+        }
+    }
+
+    #[test]
+    fn create_with_mode() {
+        let rs = crate::format_with_template(&Default::default());
+        assert!(rs.contains(
+            "let mut file=OpenOptions::new().write(true).create_new(true).mode(0o755)\
+             .open(&path)?;",
+        ));
+        // `mode` is masked by the umask.
+        assert!(rs.contains("file.set_permissions(Permissions::from_mode(0o755))?;"));
+        assert!(!rs.contains("File::create"));
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn write_retries() {
+        let rs = crate::format_with_template(&Default::default());
+        assert!(!rs.contains("retries"));
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let extract_path = tempdir.path().join("a");
+        let artifact = b"#!/bin/sh\necho extracted\n";
+        let rs = crate::format_with_template(&crate::Template {
+            payload: &base64::encode(artifact),
+            payload_len: artifact.len(),
+            extract_path: extract_path.to_str().unwrap(),
+            write_retries: 3,
+            ..Default::default()
+        });
+        assert!(rs.contains("let mut retries=3;"));
+        assert!(rs.contains("e.raw_os_error()==Some(ENOSPC)"));
+        assert!(rs.contains(r#"))]const ENOSPC:i32=28;"#));
+        assert!(rs.contains("let _=std::fs::remove_file(&path);"));
+        assert!(rs.contains("file.sync_all()?;"));
+
+        let output = run_generated(&rs);
+        assert!(output.status.success());
+        assert_eq!(b"extracted\n", &*output.stdout);
+    }
+
+    #[cfg(unix)]
+    #[test_case(0; "no_retries")]
+    #[test_case(1; "retries")]
+    fn exec_failure_removes_executable(exec_retries: u32) {
+        let tempdir = tempfile::tempdir().unwrap();
+        let extract_path = tempdir.path().join("a");
+        let artifact = b"#!/nonexistent\n";
+        let rs = crate::format_with_template(&crate::Template {
+            payload: &base64::encode(artifact),
+            payload_len: artifact.len(),
+            extract_path: extract_path.to_str().unwrap(),
+            exec_retries,
+            ..Default::default()
+        });
+        let output = run_generated(&rs);
+        assert!(!output.status.success());
+        assert_eq!(0, std::fs::read_dir(tempdir.path()).unwrap().count());
+    }
+
+    #[cfg(unix)]
+    #[test_case(false, b"#!/bin/sh\necho extracted\n" => (true, b"extracted\n".to_vec()); "exec")]
+    #[test_case(true, b"#!/bin/sh\necho extracted\n" => (true, b"extracted\n".to_vec()); "spawn")]
+    #[test_case(false, b"#!/nonexistent\n" => (false, vec![]); "exec_failure")]
+    fn self_delete(spawn: bool, artifact: &[u8]) -> (bool, Vec<u8>) {
+        let rs = crate::format_with_template(&crate::Template {
+            spawn,
+            ..Default::default()
+        });
+        assert!(!rs.contains("Guard"));
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let extract_path = tempdir.path().join("a");
+        let rs = crate::format_with_template(&crate::Template {
+            payload: &base64::encode(artifact),
+            payload_len: artifact.len(),
+            extract_path: extract_path.to_str().unwrap(),
+            spawn,
+            self_delete: true,
+            ..Default::default()
+        });
+        assert!(rs.contains("impl Drop for Guard<'_>"));
+        assert!(!rs.contains("/bin/sh"));
+
+        // A successful `exec` leaves the executable.
+        let output = run_generated(&rs);
+        let left = usize::from(output.status.success() && !spawn);
+        assert_eq!(left, std::fs::read_dir(tempdir.path()).unwrap().count());
+        (output.status.success(), output.stdout)
+    }
+
+    #[cfg(unix)]
+    #[test_case(false; "exec")]
+    #[test_case(true; "spawn")]
+    fn no_new_privs(spawn: bool) {
+        let rs = crate::format_with_template(&crate::Template {
+            spawn,
+            ..Default::default()
+        });
+        assert!(!rs.contains("prctl"));
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let extract_path = tempdir.path().join("a");
+        let artifact = b"#!/bin/sh\ngrep NoNewPrivs /proc/self/status\n";
+        let rs = crate::format_with_template(&crate::Template {
+            payload: &base64::encode(artifact),
+            payload_len: artifact.len(),
+            extract_path: extract_path.to_str().unwrap(),
+            spawn,
+            no_new_privs: true,
+            ..Default::default()
+        });
+        assert!(rs.contains(r#"#[cfg(target_os="linux")]{extern "C"{fn prctl("#));
+        assert!(rs.contains("prctl(38,1usize,0usize,0usize,0usize)"));
+
+        if cfg!(target_os = "linux") {
+            let output = run_generated(&rs);
+            assert!(output.status.success());
+            assert_eq!(&b"NoNewPrivs:\t1\n"[..], &*output.stdout);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test_case(false; "exec")]
+    #[test_case(true; "spawn")]
+    fn exec_retries(spawn: bool) {
+        let rs = crate::format_with_template(&crate::Template {
+            spawn,
+            ..Default::default()
+        });
+        assert!(!rs.contains("exec_retries"));
+
+        let tempdir = tempfile::tempdir().unwrap();
+        let extract_path = tempdir.path().join("a");
+        let artifact = b"#!/bin/sh\necho extracted\n";
+        let rs = crate::format_with_template(&crate::Template {
+            payload: &base64::encode(artifact),
+            payload_len: artifact.len(),
+            extract_path: extract_path.to_str().unwrap(),
+            exec_retries: 5,
+            spawn,
+            env_passthrough: &["FOO".to_owned()],
+            ..Default::default()
+        });
+        assert!(rs.contains("let mut exec_retries=5;let mut wait=10;"));
+        assert!(rs.contains("e.raw_os_error()==Some(ETXTBSY)"));
+        assert!(rs.contains(r#"))]const ETXTBSY:i32=26;"#));
+
+        let output = run_generated(&rs);
+        assert!(output.status.success());
+        assert_eq!(b"extracted\n", &*output.stdout);
+    }
+
+    #[test]
+    fn no_fsync() {
+        let rs = crate::format_with_template(&Default::default());
+        assert!(rs.contains("file.sync_all()?;"));
+
+        let rs = crate::format_with_template(&crate::Template {
+            no_fsync: true,
+            ..Default::default()
+        });
+        assert!(!rs.contains("sync_all"));
+    }
+
+    #[test]
+    fn spawn() {
+        let rs = crate::format_with_template(&Default::default());
+        assert!(rs.contains("let e=Command::new(&path).exec();"));
+        assert!(!rs.contains("Stdio"));
+
+        let rs = crate::format_with_template(&crate::Template {
+            spawn: true,
+            ..Default::default()
+        });
+        assert!(!rs.contains("exec()"));
+        for stream in &["stdin", "stdout", "stderr"] {
+            assert!(rs.contains(&format!(".{}(Stdio::inherit())", stream)));
+        }
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn banner() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let extract_path = tempdir.path().join("a");
+        let artifact = b"#!/bin/sh\necho extracted\n";
+        let banner = "Built with \"cargo-executable-payload\"\n{} \\ \u{1f980}";
+        let rs = crate::format_with_template(&crate::Template {
+            payload: &base64::encode(artifact),
+            payload_len: artifact.len(),
+            extract_path: extract_path.to_str().unwrap(),
+            banner: Some(banner),
+            ..Default::default()
+        });
+
+        let output = run_generated(&rs);
+        assert!(output.status.success());
+        assert_eq!(b"extracted\n", &*output.stdout);
+        assert_eq!(format!("{}\n", banner).as_bytes(), &*output.stderr);
+
+        let rs = crate::format_with_template(&Default::default());
+        assert!(!rs.contains("eprintln!"));
+    }
+
+    #[cfg(unix)]
+    #[test_case(crate::RuntimeDecoder::Minimal, false; "minimal")]
+    #[test_case(crate::RuntimeDecoder::Fast, true; "fast_spawn")]
+    fn as_bin_target(runtime_decoder: crate::RuntimeDecoder, spawn: bool) {
+        let artifact = b"Hello, world!\n".repeat(100);
+        let cwd = camino::Utf8Path::new(".");
+        let mut shell = crate::Shell::buffered();
+        let compression = crate::Compression::Gzip;
+        let compressed =
+            crate::compress(&mut shell, cwd, compression, None, None, &artifact, true).unwrap();
+        let rs = crate::format_with_template(&crate::Template {
+            payload: &base64::encode(compressed),
+            payload_len: artifact.len(),
+            compression: &[compression],
+            runtime_decoder,
+            spawn,
+            banner: Some("banner"),
+            ..Default::default()
+        });
+        assert!(!rs.lines().any(|line| line.starts_with("#![")));
+
+        let tempdir = tempfile::tempdir().unwrap();
+        create_package(
+            tempdir.path(),
+            "host",
+            &[
+                ("src/main.rs", "fn main() {}\n"),
+                ("src/bin/payload.rs", &rs),
+            ],
+        );
+        let cargo = std::env::var_os("CARGO").unwrap_or_else(|| "cargo".into());
+        duct::cmd!(cargo, "build", "--offline", "--bin", "payload")
+            .dir(tempdir.path())
+            .env("RUSTFLAGS", "-D warnings")
+            .stderr_null()
+            .run()
+            .unwrap();
+    }
+
+    #[cfg(unix)]
+    #[test_case(false; "exec")]
+    #[test_case(true; "spawn")]
+    fn env_passthrough(spawn: bool) {
+        let tempdir = tempfile::tempdir().unwrap();
+        let extract_path = tempdir.path().join("a");
+        let artifact = b"#!/bin/sh\necho \"$FOO,${BAR-unset}\"\n";
+        let rs = crate::format_with_template(&crate::Template {
+            payload: &base64::encode(artifact),
+            payload_len: artifact.len(),
+            extract_path: extract_path.to_str().unwrap(),
+            spawn,
+            env_passthrough: &["FOO".to_owned(), "BAR".to_owned()],
+            ..Default::default()
+        });
+        assert!(rs.contains(r#"for k in&["FOO","BAR"]{if let Some(v)=std::env::var_os(k)"#));
+
+        std::fs::write(tempdir.path().join("main.rs"), rs).unwrap();
+        duct::cmd!("rustc", "--edition", "2018", "-o", "main", "main.rs")
+            .dir(tempdir.path())
+            .stdout_null()
+            .run()
+            .unwrap();
+        let output = duct::cmd!(tempdir.path().join("main"))
+            .env("FOO", "foo")
+            .env_remove("BAR")
+            .read()
+            .unwrap();
+        assert_eq!("foo,unset", output);
+
+        let rs = crate::format_with_template(&Default::default());
+        assert!(!rs.contains("var_os"));
+    }
+
+    #[cfg(unix)]
+    #[test_case(false, false; "exec")]
+    #[test_case(true, false; "spawn")]
+    #[test_case(false, true; "exec_without_preserve_arg0")]
+    fn preserve_arg0(spawn: bool, no_preserve_arg0: bool) {
+        let tempdir = tempfile::tempdir().unwrap();
+        let extract_path = tempdir.path().join("a");
+        std::fs::write(
+            tempdir.path().join("arg0.rs"),
+            "fn main() { print!(\"{}\", std::env::args().next().unwrap()); }\n",
+        )
+        .unwrap();
+        duct::cmd!("rustc", "-o", "arg0", "arg0.rs")
+            .dir(tempdir.path())
+            .run()
+            .unwrap();
+        let artifact = std::fs::read(tempdir.path().join("arg0")).unwrap();
+        let rs = crate::format_with_template(&crate::Template {
+            payload: &base64::encode(&artifact),
+            payload_len: artifact.len(),
+            extract_path: extract_path.to_str().unwrap(),
+            spawn,
+            preserve_arg0: !no_preserve_arg0,
+            ..Default::default()
+        });
+        assert_eq!(
+            !no_preserve_arg0,
+            rs.contains("CommandExt::arg0(&mut cmd,arg0)")
+        );
+
+        std::fs::write(tempdir.path().join("main.rs"), rs).unwrap();
+        duct::cmd!("rustc", "--edition", "2018", "-o", "main", "main.rs")
+            .dir(tempdir.path())
+            .stdout_null()
+            .run()
+            .unwrap();
+        let main = tempdir.path().join("main");
+        let output = duct::cmd!(&main).read().unwrap();
+        if no_preserve_arg0 {
+            assert!(output.starts_with(&format!("{}.", extract_path.display())));
+        } else {
+            assert_eq!(main.to_str().unwrap(), output);
+        }
+    }
+
+    #[cfg(unix)]
+    #[test_case(0, false, false; "plain")]
+    #[test_case(2, false, false; "exec_retries")]
+    #[test_case(0, true, false; "preserve_arg0")]
+    #[test_case(0, false, true; "self_delete")]
+    fn posix_spawn(exec_retries: u32, preserve_arg0: bool, self_delete: bool) {
+        let tempdir = tempfile::tempdir().unwrap();
+        let extract_path = tempdir.path().join("a");
+        let artifact = b"#!/bin/sh\nread line\necho \"got $line, $FOO\"\nexit 3\n";
+        let rs = crate::format_with_template(&crate::Template {
+            payload: &base64::encode(artifact),
+            payload_len: artifact.len(),
+            extract_path: extract_path.to_str().unwrap(),
+            exec_retries,
+            posix_spawn: true,
+            self_delete,
+            preserve_arg0,
+            ..Default::default()
+        });
+        assert!(rs.contains("fn posix_spawn(path:&std::path::Path)->std::io::Result<i32>"));
+        assert!(!rs.contains("Command"));
+
+        std::fs::write(tempdir.path().join("main.rs"), rs).unwrap();
+        duct::cmd!("rustc", "--edition", "2018", "-o", "main", "main.rs")
+            .dir(tempdir.path())
+            .stdout_null()
+            .run()
+            .unwrap();
+        let output = duct::cmd!(tempdir.path().join("main"))
+            .env("FOO", "foo")
+            .stdin_bytes("hello\n")
+            .stdout_capture()
+            .unchecked()
+            .run()
+            .unwrap();
+        assert_eq!(Some(3), output.status.code());
+        assert_eq!(b"got hello, foo\n", &*output.stdout);
+        // The decoded executable is removed either way.
+        let mut file_names = std::fs::read_dir(tempdir.path())
+            .unwrap()
+            .map(|e| e.unwrap().file_name().into_string().unwrap())
+            .collect::<Vec<_>>();
+        file_names.sort();
+        assert_eq!(["main", "main.rs"], *file_names);
+    }
+
+    #[cfg(unix)]
+    #[test_case("2021", false, false; "edition2021")]
+    #[test_case("2024", true, false; "edition2024")]
+    #[test_case("2021", false, true; "edition2021_posix_spawn")]
+    #[test_case("2024", true, true; "edition2024_posix_spawn")]
+    fn unsafe_extern(edition: &str, unsafe_extern: bool, posix_spawn: bool) {
+        let tempdir = tempfile::tempdir().unwrap();
+        let extract_path = tempdir.path().join("a");
+        let artifact = b"#!/bin/sh
+echo ok
+";
+        let rs = crate::format_with_template(&crate::Template {
+            payload: &base64::encode(artifact),
+            payload_len: artifact.len(),
+            extract_path: extract_path.to_str().unwrap(),
+            no_new_privs: true,
+            posix_spawn,
+            unsafe_extern,
+            ..Default::default()
+        });
+        assert_eq!(unsafe_extern, rs.contains(r#"unsafe extern "C""#));
+
+        std::fs::write(tempdir.path().join("main.rs"), rs).unwrap();
+        duct::cmd!("rustc", "--edition", edition, "-o", "main", "main.rs")
+            .dir(tempdir.path())
+            .stdout_null()
+            .run()
+            .unwrap();
+        let output = duct::cmd!(tempdir.path().join("main")).read().unwrap();
+        assert_eq!("ok", output);
+    }
+
+    #[test_case("x86_64-unknown-linux-musl" => Some((&["x86_64"][..], "linux")); "x86_64_linux")]
+    #[test_case("i686-pc-windows-msvc" => Some((&["x86"][..], "windows")); "x86_windows")]
+    #[test_case("armv7-unknown-linux-gnueabihf" => Some((&["arm"][..], "linux")); "arm_linux")]
+    #[test_case("aarch64-linux-android" => Some((&["aarch64"][..], "android")); "aarch64_android")]
+    #[test_case("universal2-apple-darwin" => Some((&["x86_64", "aarch64"][..], "macos")); "universal")]
+    #[test_case("mips-unknown-linux-gnu" => None; "unknown_arch")]
+    #[test_case("x86_64-unknown-none" => None; "unknown_os")]
+    fn runtime_arch_os(target: &str) -> Option<(&'static [&'static str], &'static str)> {
+        crate::runtime_arch_os(target)
+    }
+
+    #[cfg(unix)]
+    #[test_case(&[std::env::consts::ARCH, "riscv64"], std::env::consts::OS => true; "matched")]
+    #[test_case(&["riscv64"], std::env::consts::OS => false; "arch_mismatch")]
+    #[test_case(&[std::env::consts::ARCH], "ios" => false; "os_mismatch")]
+    fn assert_arch(arches: &[&str], os: &str) -> bool {
+        let tempdir = tempfile::tempdir().unwrap();
+        let extract_path = tempdir.path().join("a");
+        let artifact = b"#!/bin/sh\necho ok\n";
+        let rs = crate::format_with_template(&crate::Template {
+            payload: &base64::encode(artifact),
+            payload_len: artifact.len(),
+            extract_path: extract_path.to_str().unwrap(),
+            assert_arch: Some((arches, os)),
+            ..Default::default()
+        });
+        assert!(rs.contains(&format!("if!{:?}.contains(&ARCH)||OS!={:?}{{", arches, os,)));
+
+        let output = run_generated(&rs);
+        if output.status.success() {
+            assert_eq!(b"ok\n", &*output.stdout);
+        } else {
+            assert_eq!(Some(1), output.status.code());
+            assert_eq!(
+                format!(
+                    "this program is for {}-{}, but the current platform is {}-{}\n",
+                    arches.join("|"),
+                    os,
+                    std::env::consts::ARCH,
+                    std::env::consts::OS,
+                ),
+                String::from_utf8_lossy(&output.stderr),
+            );
+            // Nothing is written.
+            assert_eq!(0, std::fs::read_dir(tempdir.path()).unwrap().count());
+        }
+        output.status.success()
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn spawn_pipes_stdin() {
+        let tempdir = tempfile::tempdir().unwrap();
+        let extract_path = tempdir.path().join("a");
+        let artifact = b"#!/bin/sh\nread line\necho \"got $line\"\nexit 3\n";
+        let rs = crate::format_with_template(&crate::Template {
+            payload: &base64::encode(artifact),
+            payload_len: artifact.len(),
+            extract_path: extract_path.to_str().unwrap(),
+            spawn: true,
+            ..Default::default()
+        });
+
+        let output = run_generated_with_stdin(&rs, b"hello\n");
+        assert_eq!(Some(3), output.status.code());
+        assert_eq!(b"got hello\n", &*output.stdout);
     }
 }
@@ -2,8 +2,11 @@ use cargo_executable_payload::{Opt, Shell};
 use structopt::{clap, StructOpt as _};
 
 fn main() {
-    let opt = Opt::from_args();
     let mut shell = Shell::new();
+    let args = cargo_executable_payload::expand_response_files(std::env::args_os())
+        .and_then(cargo_executable_payload::apply_profile_file)
+        .unwrap_or_else(|err| exit_with_error(err, &mut shell));
+    let opt = Opt::from_iter(args);
     if let Err(err) = cargo_executable_payload::run(opt, &mut shell) {
         exit_with_error(err, &mut shell);
     }
@@ -0,0 +1,38 @@
+#![cfg(all(target_os = "linux", target_arch = "x86_64"))]
+
+use std::{os::unix::fs::PermissionsExt as _, process::Command};
+
+#[test]
+fn features_env() {
+    let tempdir = tempfile::tempdir().unwrap();
+    let package = tempdir.path().join("a");
+    std::fs::create_dir_all(package.join("src")).unwrap();
+    std::fs::write(
+        package.join("Cargo.toml"),
+        "[package]\nname = \"a\"\nversion = \"0.0.0\"\nedition = \"2018\"\n\n[features]\ngreet = []\n",
+    )
+    .unwrap();
+    std::fs::write(
+        package.join("src").join("main.rs"),
+        r#"fn main() { println!("{}", cfg!(feature = "greet")); }"#,
+    )
+    .unwrap();
+
+    let output = tempdir.path().join("out");
+    let status = Command::new(env!("CARGO_BIN_EXE_cargo-executable-payload"))
+        .arg("executable-payload")
+        .arg("--manifest-path")
+        .arg(package.join("Cargo.toml"))
+        .args(["--target", "x86_64-unknown-linux-gnu"])
+        .args(["--emit", "binary", "--no-upx", "--quiet-tools", "-o"])
+        .arg(&output)
+        .env(cargo_executable_payload::FEATURES_ENV, "greet")
+        .status()
+        .unwrap();
+    assert!(status.success());
+
+    std::fs::set_permissions(&output, std::fs::Permissions::from_mode(0o755)).unwrap();
+    let output = Command::new(&output).output().unwrap();
+    assert!(output.status.success());
+    assert_eq!("true\n", std::str::from_utf8(&output.stdout).unwrap());
+}